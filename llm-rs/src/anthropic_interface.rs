@@ -0,0 +1,269 @@
+//! Talk to the Anthropic Messages API.
+//! Documented [here](https://docs.anthropic.com/claude/reference/messages_post)
+//!
+//! Replies are translated into a `ChatRequestInfo` so callers can
+//! treat Anthropic and OpenAI chats the same way
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::json::AnthropicError;
+use crate::json::AnthropicResponse;
+use crate::json::AnthropicUsage;
+use crate::json::ChatRequestInfo;
+use crate::json::Message;
+use crate::json::Usage;
+use crate::openai_interface::ApiInterface;
+use crate::transport::Transport;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Base URI for requests
+const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1";
+
+/// The version of the API this code is written against.  Sent in
+/// the "anthropic-version" header
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Build the JSON payload for the Messages API.  OpenAI style
+/// "system" messages are pulled out into the separate `system`
+/// field.  Anthropic insists that user and assistant take turns, so
+/// consecutive messages with the same role are joined
+pub fn payload(
+    model: &str,
+    messages: &[Message],
+    max_tokens: u32,
+    temperature: f32,
+    stream: bool,
+) -> Value {
+    let mut system: Vec<&str> = vec![];
+    let mut turns: Vec<Message> = vec![];
+    for message in messages.iter() {
+        if message.role == "system" {
+            if !message.content.is_empty() {
+                system.push(message.content.as_str());
+            }
+            continue;
+        }
+        match turns.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content = format!("{}\n\n{}", last.content, message.content);
+            }
+            _ => turns.push(message.clone()),
+        }
+    }
+
+    let mut data = json!({
+        "model": model,
+        "messages": turns,
+        "max_tokens": max_tokens,
+        "temperature": temperature,
+    });
+    if !system.is_empty() {
+        data["system"] = json!(system.join("\n"));
+    }
+    if stream {
+        data["stream"] = json!(true);
+    }
+    data
+}
+
+fn request_headers(api_key: &str) -> [(String, String); 2] {
+    [
+        ("x-api-key".to_string(), api_key.to_string()),
+        (
+            "anthropic-version".to_string(),
+            ANTHROPIC_VERSION.to_string(),
+        ),
+    ]
+}
+
+/// Anthropic returns errors in the body of the response.  If `value`
+/// is one of them convert it to an `ApiError`
+fn check_error(value: &Value, headers: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    if value["type"] == "error" {
+        let error: AnthropicError = serde_json::from_value(value["error"].clone())?;
+        return Err(Box::new(ApiError::new(
+            ApiErrorType::Error(format!("{}: {}", error.error_type, error.message)),
+            headers.clone(),
+        )));
+    }
+    Ok(())
+}
+
+/// Send the data to Anthropic and return the response with headers.
+/// This maintains no state
+pub fn send_chat(
+    transport: &dyn Transport,
+    api_key: &str,
+    data: &Value,
+) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+    let uri = format!("{ANTHROPIC_API_URL}/messages");
+    let (headers, response_string) = ApiInterface::post_json(
+        transport,
+        &request_headers(api_key),
+        data,
+        uri.as_str(),
+        None,
+    )?;
+    let value: Value = serde_json::from_str(response_string.as_str())?;
+    check_error(&value, &headers)?;
+    let response: AnthropicResponse = serde_json::from_value(value)?;
+    let content: String = response
+        .content
+        .iter()
+        .filter(|c| c.content_type == "text")
+        .map(|c| c.text.as_str())
+        .collect();
+    let json = ChatRequestInfo::new(
+        response.id,
+        response.model,
+        Usage::from(response.usage),
+        content,
+        response.stop_reason.unwrap_or_default(),
+    );
+    Ok((headers, json))
+}
+
+/// As `send_chat` but the reply is streamed.  Each piece of text is
+/// passed to `on_text` as it arrives.  The whole reply is returned
+/// at the end.  `data` must have been built with `stream` set
+pub fn send_chat_stream(
+    transport: &dyn Transport,
+    api_key: &str,
+    data: &Value,
+    on_text: &mut dyn FnMut(&str),
+) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+    let uri = format!("{ANTHROPIC_API_URL}/messages");
+
+    // The stream is server sent events.  Only the "data:" lines are
+    // needed.  Lines can be split over chunks, so buffer them
+    let mut line_buffer: Vec<u8> = vec![];
+    let mut events: Vec<Value> = vec![];
+    let mut on_chunk = |chunk: &[u8]| {
+        line_buffer.extend_from_slice(chunk);
+        while let Some(pos) = line_buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = line_buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(event) = line.trim().strip_prefix("data:") {
+                if let Ok(event) = serde_json::from_str::<Value>(event.trim()) {
+                    if event["type"] == "content_block_delta" {
+                        if let Some(text) = event["delta"]["text"].as_str() {
+                            on_text(text);
+                        }
+                    }
+                    events.push(event);
+                }
+            }
+        }
+    };
+    let (headers, response_string) = ApiInterface::post_json(
+        transport,
+        &request_headers(api_key),
+        data,
+        uri.as_str(),
+        Some(&mut on_chunk),
+    )?;
+
+    if events.is_empty() {
+        // Not a stream.  An error sent before streaming started
+        let value: Value = serde_json::from_str(response_string.as_str())?;
+        check_error(&value, &headers)?;
+        return Err(Box::new(ApiError::new(
+            ApiErrorType::BadJson(response_string),
+            headers,
+        )));
+    }
+
+    // Assemble the reply from the events
+    let mut id = String::new();
+    let mut model = String::new();
+    let mut usage = AnthropicUsage::default();
+    let mut content = String::new();
+    let mut stop_reason = String::new();
+    for event in events.iter() {
+        check_error(event, &headers)?;
+        match event["type"].as_str() {
+            Some("message_start") => {
+                let message = &event["message"];
+                id = message["id"].as_str().unwrap_or_default().to_string();
+                model = message["model"].as_str().unwrap_or_default().to_string();
+                usage = serde_json::from_value(message["usage"].clone()).unwrap_or_default();
+            }
+            Some("content_block_delta") => {
+                content += event["delta"]["text"].as_str().unwrap_or_default();
+            }
+            Some("message_delta") => {
+                if let Some(reason) = event["delta"]["stop_reason"].as_str() {
+                    stop_reason = reason.to_string();
+                }
+                if let Some(tokens) = event["usage"]["output_tokens"].as_u64() {
+                    usage.output_tokens = tokens as u32;
+                }
+            }
+            _ => (),
+        }
+    }
+    let json = ChatRequestInfo::new(id, model, Usage::from(usage), content, stop_reason);
+    Ok((headers, json))
+}
+
+/// Convert the usage into a price.  In cents.
+pub fn cost(usage: &Usage, model: &str) -> f64 {
+    // Cents per thousand tokens: (prompt, completion)
+    let (prompt, completion) = if model.contains("opus") {
+        (1.5, 7.5)
+    } else if model.contains("sonnet") {
+        (0.3, 1.5)
+    } else if model.contains("haiku") {
+        (0.025, 0.125)
+    } else {
+        // Older models.  Claude 2 pricing
+        (0.8, 2.4)
+    };
+    usage.prompt_tokens as f64 / 1000.0 * prompt
+        + usage.completion_tokens as f64 / 1000.0 * completion
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn payload_separates_system_prompt() {
+        let messages = vec![
+            message("system", "You are terse"),
+            message("user", "Hello"),
+            message("assistant", "Hi"),
+            message("user", "Bye"),
+        ];
+        let data = payload("claude-3-haiku-20240307", &messages, 100, 0.5, false);
+        assert_eq!(data["system"], "You are terse");
+        assert_eq!(data["messages"].as_array().unwrap().len(), 3);
+        assert_eq!(data["messages"][0]["role"], "user");
+        assert!(data.get("stream").is_none());
+    }
+
+    #[test]
+    fn payload_joins_consecutive_roles() {
+        // `ApiInterface::chat` sends an empty system prompt when the
+        // conversation starts
+        let messages = vec![
+            message("system", ""),
+            message("user", "One"),
+            message("user", "Two"),
+        ];
+        let data = payload("claude-3-haiku-20240307", &messages, 100, 0.5, true);
+        assert!(data.get("system").is_none());
+        assert_eq!(data["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(data["messages"][0]["content"], "One\n\nTwo");
+        assert_eq!(data["stream"], true);
+    }
+}
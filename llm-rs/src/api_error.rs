@@ -1,3 +1,4 @@
+use crate::budget::Period;
 use reqwest::StatusCode;
 use std::collections::HashMap;
 use std::{error::Error, fmt};
@@ -10,6 +11,16 @@ pub enum ApiErrorType {
     // When a bad status is returned from a network connection.
     // Includes the failing code and the textual error string
     Status(StatusCode, String),
+    // The request was stopped with a `CancellationToken`
+    Cancelled,
+    // The request was not sent as what it `estimate`d to cost would
+    // take spending, `spent` so far, past a budget's `limit`.  In cents
+    BudgetExceeded {
+        period: Period,
+        limit: f64,
+        spent: f64,
+        estimate: f64,
+    },
 }
 
 #[derive(Debug)]
@@ -42,6 +53,28 @@ impl fmt::Display for ApiError {
                 write!(f, "{header_report}\nHTTP Status({status}) Reason: {reason}")
             }
 
+            ApiErrorType::Cancelled => write!(f, "Cancelled"),
+            ApiErrorType::BudgetExceeded {
+                period: Period::Request,
+                limit,
+                estimate,
+                ..
+            } => write!(
+                f,
+                "Over budget: The request could cost {estimate:.4}, \
+                 more than the {limit:.4} per request"
+            ),
+            ApiErrorType::BudgetExceeded {
+                period,
+                limit,
+                spent,
+                estimate,
+            } => write!(
+                f,
+                "Over budget: The request could cost {estimate:.4}.  \
+                 {spent:.4} of the {limit:.4} per {period} is spent"
+            ),
+
             // Generic.  TODO: Get rid of this
             ApiErrorType::Error(ref msg) => write!(f, "{header_report}\nError: {msg}"),
         }
@@ -0,0 +1,152 @@
+//! Send OpenAI requests through Azure OpenAI.
+//! Documented [here](https://learn.microsoft.com/azure/ai-services/openai/reference)
+//!
+//! Azure addresses models by "deployment", not model name.  Each
+//! request has an `api-version` query parameter, and the key is sent
+//! in an `api-key` header.  The configuration is a JSON file:
+//!
+//! ```json
+//! {
+//!   "endpoint": "https://my-resource.openai.azure.com",
+//!   "api_version": "2024-02-01",
+//!   "deployments": {
+//!     "gpt-4": "my-gpt-4",
+//!     "gpt-3.5-turbo": "my-gpt-35"
+//!   }
+//! }
+//! ```
+//!
+//! If there is no "api_key" in the file the environment variable
+//! `AZURE_OPENAI_API_KEY` is used
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::json::ChatRequestInfo;
+use crate::openai_interface::ApiInterface;
+use crate::transport::Transport;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AzureConfig {
+    /// The resource's endpoint: "https://<resource>.openai.azure.com"
+    pub endpoint: String,
+
+    /// Sent as the `api-version` query parameter
+    pub api_version: String,
+
+    /// The secret key for the resource
+    #[serde(default)]
+    pub api_key: String,
+
+    /// Model name => deployment name
+    pub deployments: HashMap<String, String>,
+}
+
+impl AzureConfig {
+    /// Read the configuration from the JSON file at `path`
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let mut config: AzureConfig = serde_json::from_str(contents.as_str())?;
+        config.endpoint = config.endpoint.trim_end_matches('/').to_string();
+        if config.api_key.is_empty() {
+            config.api_key = std::env::var("AZURE_OPENAI_API_KEY").map_err(|_| {
+                ApiError::new(
+                    ApiErrorType::Error(format!(
+                        "No api_key in {} and AZURE_OPENAI_API_KEY not set",
+                        path.display()
+                    )),
+                    HashMap::new(),
+                )
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// The deployment that serves `model`
+    pub fn deployment(&self, model: &str) -> Result<&str, Box<dyn Error>> {
+        match self.deployments.get(model) {
+            Some(deployment) => Ok(deployment.as_str()),
+            None => Err(Box::new(ApiError::new(
+                ApiErrorType::Error(format!("No Azure deployment for model: {model}")),
+                HashMap::new(),
+            ))),
+        }
+    }
+
+    /// The URI for `operation` ("chat/completions",
+    /// "images/generations"...) on the deployment that serves `model`
+    pub fn deployment_uri(&self, model: &str, operation: &str) -> Result<String, Box<dyn Error>> {
+        Ok(format!(
+            "{}/openai/deployments/{}/{operation}?api-version={}",
+            self.endpoint,
+            self.deployment(model)?,
+            self.api_version
+        ))
+    }
+
+    /// The URI for `operation` ("files", "models"...) that is not
+    /// specific to a deployment
+    pub fn uri(&self, operation: &str) -> String {
+        format!(
+            "{}/openai/{operation}?api-version={}",
+            self.endpoint, self.api_version
+        )
+    }
+
+    /// The authentication header: (name, value)
+    pub fn auth_header(&self) -> (String, String) {
+        ("api-key".to_string(), self.api_key.clone())
+    }
+}
+
+/// Send a chat to the deployment that serves `model`.  The same as
+/// `ApiInterface::send_chat` but for Azure.  This maintains no state
+pub fn send_chat(
+    transport: &dyn Transport,
+    config: &AzureConfig,
+    model: &str,
+    data: &serde_json::Value,
+) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+    let uri = config.deployment_uri(model, "chat/completions")?;
+    let (headers, response_string) =
+        ApiInterface::post_json(transport, &[config.auth_header()], data, uri.as_str(), None)?;
+    let json: ChatRequestInfo = serde_json::from_str(response_string.as_str())?;
+    Ok((headers, json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AzureConfig {
+        AzureConfig {
+            endpoint: "https://example.openai.azure.com".to_string(),
+            api_version: "2024-02-01".to_string(),
+            api_key: "key".to_string(),
+            deployments: HashMap::from([("gpt-4".to_string(), "my-gpt-4".to_string())]),
+        }
+    }
+
+    #[test]
+    fn deployment_uri_uses_deployment_and_version() {
+        assert_eq!(
+            config()
+                .deployment_uri("gpt-4", "chat/completions")
+                .unwrap(),
+            "https://example.openai.azure.com/openai/deployments/my-gpt-4/chat/completions?api-version=2024-02-01"
+        );
+        assert_eq!(
+            config().uri("files/abc/content"),
+            "https://example.openai.azure.com/openai/files/abc/content?api-version=2024-02-01"
+        );
+    }
+
+    #[test]
+    fn unknown_model_has_no_deployment() {
+        assert!(config().deployment("gpt-3.5-turbo").is_err());
+    }
+}
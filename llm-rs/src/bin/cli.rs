@@ -1,20 +1,56 @@
 mod code {
     pub mod cli_error;
+    pub mod config;
+    pub mod markdown;
+    pub mod session;
+    pub mod shell;
     pub mod my_helper;
-    pub mod shared_state;
 }
-use code::shared_state::SharedState;
+use code::config;
+use code::markdown;
+use code::shell;
+use code::session::Session;
+use code::session::SessionStore;
+use code::config::Profile;
 use chrono::Local;
 use code::my_helper::MyHelper;
+use directories::BaseDirs;
 use directories::ProjectDirs;
 use image::ImageFormat;
+use llm_rs::json::OllamaPullStatus;
+use llm_rs::ledger::Ledger;
+use llm_rs::ledger::Report;
 use llm_rs::model_mode::ModelMode;
+use llm_rs::model_registry::ModelRegistry;
+use llm_rs::azure::AzureConfig;
+use llm_rs::budget::Budget;
+use llm_rs::budget::Period;
+use llm_rs::cache::CacheConfig;
+use llm_rs::cancel::CancellationToken;
+use llm_rs::compare::Comparison;
+use llm_rs::chatgpt_import;
+use llm_rs::code_block;
+use llm_rs::code_block::CodeBlock;
+use llm_rs::export;
+use llm_rs::eval;
+use llm_rs::eval::Suite;
+use llm_rs::export::ExportFormat;
+use llm_rs::api_error::ApiError;
+use llm_rs::api_error::ApiErrorType;
+use llm_rs::api_result::ApiResult;
+use llm_rs::fallback;
+use llm_rs::fallback::MODEL_HEADER;
+use llm_rs::template;
+use llm_rs::template::TemplateStore;
+use llm_rs::ollama_interface::OllamaInterface;
+use llm_rs::patch::Patch;
+use llm_rs::transport::HttpRequest;
+use llm_rs::transport::Transport;
 use openai_interface::ApiInterface;
 use context::Context;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use regex::Regex;
-use reqwest::blocking::get;
 use rustyline::completion::FilenameCompleter;
 use rustyline::highlight::MatchingBracketHighlighter;
 use rustyline::hint::HistoryHinter;
@@ -26,18 +62,28 @@ use std::env::current_dir;
 use std::error::Error;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::IsTerminal;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 use std::time::Instant;
 use std::{env, fs};
 extern crate llm_rs;
 
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use clap::CommandFactory;
+use clap::FromArgMatches;
 use clap::Parser;
+use clap::Subcommand;
 use llm_rs::openai_interface;
 use llm_rs::context;
+use llm_rs::conversation_tree::ConversationTree;
 
 const DEFAULT_MODEL: &str = "gpt-4";
 const DEFAULT_TOKENS: u32 = 2_000_u32;
@@ -66,6 +112,97 @@ struct Arguments {
     #[arg(long)]
     api_key: Option<String>,
 
+    /// The secret key for Anthropic ("claude-*") models.  [Default:
+    /// environment variable `ANTHROPIC_API_KEY`]
+    #[arg(long)]
+    anthropic_api_key: Option<String>,
+
+    /// Where the Ollama server is, for "ollama/*" models.  [Default:
+    /// environment variable `OLLAMA_HOST` or http://localhost:11434]
+    #[arg(long)]
+    ollama_url: Option<String>,
+
+    /// A JSON file that maps models to Azure OpenAI deployments.  If
+    /// set OpenAI models are used through Azure.  [Default:
+    /// environment variable `AZURE_OPENAI_CONFIG`]
+    #[arg(long)]
+    azure_config: Option<String>,
+
+    /// Send requests through this proxy.  [Default: environment
+    /// variable `HTTPS_PROXY`]
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// A PEM file of root certificates to trust as well as the
+    /// system's.  Can be repeated
+    #[arg(long)]
+    ca_cert: Vec<PathBuf>,
+
+    /// Seconds to wait for a connection
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for each read of a response [default: 1200]
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// A header, "Name: value", to send with every request.  Can be
+    /// repeated
+    #[arg(long)]
+    header: Vec<String>,
+
+    /// Append a JSON record of every request to this file.  Records
+    /// are also logged, target "llm_rs::telemetry", when `RUST_LOG`
+    /// allows
+    #[arg(long)]
+    telemetry: Option<PathBuf>,
+
+    /// Cache replies to chats and completions on disk.  The same
+    /// prompt, model and settings get the same reply, without cost
+    #[arg(long)]
+    cache: bool,
+
+    /// Hours a cached reply is used for [default: 168]
+    #[arg(long)]
+    cache_ttl: Option<u64>,
+
+    /// Megabytes the cache can use [default: 100]
+    #[arg(long)]
+    cache_size: Option<u64>,
+
+    /// A JSON file of model capabilities, added to those built in
+    #[arg(long)]
+    models: Option<PathBuf>,
+
+    /// The directory of prompt templates, "<name>.tpl".  [Default:
+    /// "templates" in the configuration directory]
+    #[arg(long)]
+    templates: Option<PathBuf>,
+
+    /// Chat models to try, in order, when the model is rate limited,
+    /// overloaded or times out.  Comma separated
+    #[arg(long, value_delimiter = ',')]
+    fallback: Vec<String>,
+
+    /// Refuse chats that could cost more than this many cents
+    #[arg(long)]
+    budget_request: Option<f64>,
+
+    /// Refuse chats that could take the conversation's cost past
+    /// this many cents
+    #[arg(long)]
+    budget_conversation: Option<f64>,
+
+    /// Refuse chats that could take today's spending past this many
+    /// cents
+    #[arg(long)]
+    budget_day: Option<f64>,
+
+    /// Refuse chats that could take this month's spending past this
+    /// many cents
+    #[arg(long)]
+    budget_month: Option<f64>,
+
     /// The initial mode (API endpoint)
     #[arg(long, short='d', default_value=DEFAULT_MODE)]
     mode: String,
@@ -77,8 +214,180 @@ struct Arguments {
     /// The system prompt sent to the chat model
     #[arg(long, short='p', default_value=None)]
     system_prompt: Option<String>,
+
+    /// Read a prompt from standard input, print the reply and exit.
+    /// The same as `ask` with no prompt
+    #[arg(long)]
+    stdin: bool,
+
+    /// With `--stdin`, add a file's contents to the prompt.  Can be
+    /// repeated
+    #[arg(long, short = 'f', requires = "stdin")]
+    file: Vec<PathBuf>,
+
+    /// With `--stdin`, continue the conversation in a context file
+    /// saved with `! sx`
+    #[arg(long, requires = "stdin")]
+    context: Option<PathBuf>,
+
+    /// With `--stdin`, print JSON with the reply, the model that
+    /// answered, the token usage and the cost
+    #[arg(long, requires = "stdin")]
+    json: bool,
+
+    /// The profile, in "config.toml" in the configuration directory,
+    /// to take defaults from.  Flags override it.  [Default: the
+    /// file's `profile`]
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Resume the named session, with its conversation, model, mode
+    /// and system prompt, or start it.  It is saved after every turn
+    #[arg(long)]
+    session: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Run without the interactive prompt
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a suite of prompts with assertions against models, and
+    /// report which pass and what they cost.  Exits with 1 if any
+    /// fail
+    Eval {
+        /// The suite, a JSON file of prompts and assertions
+        suite: PathBuf,
+
+        /// A model to run the suite with.  Can be repeated.
+        /// [Default: the suite's models, or `--model`]
+        #[arg(long = "with")]
+        models: Vec<String>,
+
+        /// Record the requests and responses in this file
+        #[arg(long, conflicts_with = "replay")]
+        record: Option<PathBuf>,
+
+        /// Answer requests from a file made with `--record`, without
+        /// the network.  Runs are reproducible
+        #[arg(long)]
+        replay: Option<PathBuf>,
+
+        /// Write the report as JSON to this file too
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+
+    /// Send one chat prompt, print the reply and exit.  For
+    /// pipelines, hooks and scripts.  Exit status: 0 success, 1
+    /// failure, 2 no prompt, 3 over budget, 4 not authorised, 5 rate
+    /// limited, overloaded or timed out, 130 interrupted
+    Ask {
+        /// The prompt.  If there is none it is read from standard
+        /// input.  A "-" is replaced by standard input
+        prompt: Vec<String>,
+
+        /// Add a file's contents to the prompt.  Can be repeated
+        #[arg(long, short = 'f')]
+        file: Vec<PathBuf>,
+
+        /// Continue the conversation in a context file saved with
+        /// `! sx`
+        #[arg(long)]
+        context: Option<PathBuf>,
+
+        /// Print JSON with the reply, the model that answered, the
+        /// token usage and the cost
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Summarise spending, from the ledger, by day, model and session
+    Report {
+        /// Write the summary as CSV to this file too
+        #[arg(long)]
+        csv: Option<PathBuf>,
+    },
 }
 
+/// The meta commands.  The first word of each line is the command
+const META_HELP: &str = "\
+    p  Display settings\n\
+    mm Display all available information about all models\n\
+    md Display the models for the current mode, with their capabilities, including local Ollama models\n\
+    ms <model> Change the current model.  \"claude-*\" models use Anthropic, \"ollama/*\" local models\n\
+    pull <model> Download a model into Ollama\n\
+    pf [<profile>] List the profiles in \"config.toml\", or change to one\n\
+    ml List modes\n\
+    m  <mode> Change mode (API endpoint\n\
+    dx Display context (for chat)\n\
+    cx Clear context\n\
+    br [<branch>] List the branches of the conversation, or change to one\n\
+    fork <branch> [<turns>] Start a branch with the first <turns> turns of this one, or all of them, and change to it\n\
+    bdiff <branch> [<branch>] Show the turns two branches, or a branch and this one, do not share\n\
+    undo Take the last prompt and reply off the context, and their cost off the conversation\n\
+    retry [<model>] [<temperature>] Send the last prompt again, for a new reply.  The model and temperature are for this prompt only\n\
+    edit Edit the last prompt and send it again, for a new reply\n\
+    ppx <path> Pretty print conversation to path\n\
+    ex <md|html|jsonl> <path> Export the conversation as Markdown, HTML, or fine-tuning data (appended)\n\
+    v  Set verbosity\n\
+    k  Set max tokens for completions\n\
+    t  Set temperature for completions\n\
+    sp Set system prompt (after `! cc`\n\
+    st Toggle streaming chat replies (Anthropic models)\n\
+    fmt Toggle displaying chat replies as formatted Markdown, with code highlighted\n\
+    cmp [<model>...|off] Send chat prompts to all the models, with the same context, to compare replies\n\
+    adopt <n> Continue the conversation with compared reply <n>\n\
+    cb List the code blocks in the last reply\n\
+    cw <n> <path> Write code block <n> to a file\n\
+    ca <n> <path> Append code block <n> to a file\n\
+    sh [{name}] <command> Run a command, if you agree.  Its output is {name}, or {sh}, in prompts\n\
+    shm Toggle offering to run the shell commands in chat replies\n\
+    patch <n> Show the changes in diff <n> and apply them to the working directory, if you agree\n\
+    b [<request|conversation|day|month> <cents|none>] Display the budgets and spending, or set a budget\n\
+    fb [<model>...|none] Display or set the chat models to try when the model is rate limited, overloaded or times out\n\
+    tpl [<name> key=value...] List templates, or send the rendered template as the prompt\n\
+    cache [clear|off|on] Display the response cache, empty it, or stop and start using it\n\
+    ci Clear image\n\
+    mask <path> Set the mask to use in image edit mode.  A 1024x1024 PNG with transparent mask\n\
+    a <path> Audio file for transcription\n\
+    ci Clear the image stored for editing\n\
+    f List the files stored on the server\n\
+    fu <path> Upload a file of fine tuning data\n\
+    fd <file id> Delete a file\n\
+    fi <file id> Get information about file\n\
+    fc <file id> [destination_file] Get contents of file\n\
+    ft <file ID> Start a fine tune model using a JASONL training file \n\
+    ftl List fine tunes\n\
+    ftr <id> Retrieve the info for a fine tune\n\
+    fl <name> <path>  Associate the contents of the `path` with `name` for use in prompts like: {{name}}\n\
+    sx <path>  Save the context to a file at the specified path\n\
+    rx <path>  Restore the context from a file at the specified path\n\
+    ss [<name>] List the sessions, or change to one, resuming it or starting it.  The session is saved after every turn\n\
+    sn <name> <new name> Rename a session\n\
+    sd <name> Delete a session\n\
+    ix <conversations.json> <dir> Import a ChatGPT data export.  Each conversation is a context file in <dir> for `! rx`\n\
+    $ [<path>] Display spending by day, model and session, or write it to a CSV file\n\
+    ?  This text\n";
+
+/// The modes (API endpoints)
+const MODES: [&str; 5] = [
+    "completions",
+    "chat",
+    "image",
+    "image_edit",
+    "audio_transcription",
+];
+
+/// Exit statuses for `ask`
+const EXIT_FAILED: i32 = 1;
+const EXIT_NO_PROMPT: i32 = 2;
+const EXIT_OVER_BUDGET: i32 = 3;
+const EXIT_UNAUTHORISED: i32 = 4;
+const EXIT_TRY_AGAIN: i32 = 5;
+const EXIT_INTERRUPTED: i32 = 130;
+
 /// A structure to hold data for the interface.
 struct CliInterface {
     /// If this is > 0 output status messages.  Information about
@@ -118,6 +427,54 @@ struct CliInterface {
 
     /// Local data.  Generally this is reading local files of data
     local_data: HashMap<String, String>,
+
+    /// Display chat replies as they arrive.  Only Anthropic models
+    /// stream
+    stream: bool,
+
+    /// Display chat replies as formatted Markdown, with code
+    /// highlighted.  Not when they stream
+    render: bool,
+
+    /// Offer to run the shell commands the model proposes
+    shell_mode: bool,
+
+    /// The IDs of the files on the server, from `! f`, for completion
+    file_ids: Vec<String>,
+
+    /// Prompt templates for `! tpl`
+    templates: TemplateStore,
+
+    /// The models and their capabilities
+    registry: ModelRegistry,
+
+    /// If not empty chat prompts go to all these models, to compare
+    /// the replies
+    compare_models: Vec<String>,
+
+    /// The last compared prompt and the replies.  One can be adopted
+    comparison: Option<(String, Vec<Comparison>)>,
+
+    /// The profiles, for `! pf`
+    config: config::Config,
+
+    /// The profile in use
+    profile: Option<String>,
+
+    /// The saved sessions
+    sessions: SessionStore,
+
+    /// The session in use.  Saved after every turn
+    session: Option<String>,
+
+    /// The turn taken off by `! retry` or `! edit`, the length of the
+    /// context without it, and the model and temperature to go back
+    /// to once it is sent again
+    resend: Option<(context::Turn, usize, String, f32)>,
+
+    /// The branches of the conversation.  The context is the branch
+    /// in use, and is recorded in the tree before it is used
+    tree: ConversationTree,
 }
 
 impl CliInterface {
@@ -148,15 +505,14 @@ impl CliInterface {
     }
 
     /// Called for an image that OpenAI generates.
-    fn process_image_url(&mut self, url: &str) -> Result<(), Box<dyn Error>> {
+    fn process_image_url(&mut self, url: &str, transport: &dyn Transport) -> Result<(), Box<dyn Error>> {
         println!("process_image_url({url})");
         let start = Instant::now();
 
         // Must convert the image
         // convert otter.png -type TrueColor -define png:color-type=6 otter_rgba.png
 
-        let mut img_data: Vec<u8> = Vec::new();
-        get(url).unwrap().read_to_end(&mut img_data).unwrap();
+        let img_data: Vec<u8> = transport.send(&HttpRequest::get(url), None)?.body;
         println!("Down loaded URL: {} bytes", img_data.len());
 
         let incomming_image_file_path = Self::make_file("png")?;
@@ -164,6 +520,7 @@ impl CliInterface {
         let mut incomming_image_file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&incomming_image_file_path)?;
         println!("Created {:?}", incomming_image_file_path);
         incomming_image_file.write_all(&img_data)?;
@@ -194,6 +551,13 @@ impl CliInterface {
             .build();
         let h = MyHelper {
             completer: FilenameCompleter::new(),
+            commands: META_HELP
+                .lines()
+                .filter_map(|l| l.split_whitespace().next())
+                .map(|c| c.to_string())
+                .collect(),
+            arguments: HashMap::new(),
+            variables: vec![],
             highlighter: MatchingBracketHighlighter::new(),
             hinter: HistoryHinter {},
             colored_prompt: "".to_owned(),
@@ -216,6 +580,40 @@ impl CliInterface {
         Ok(read_line)
     }
 
+    /// Tell the completer what the arguments of meta commands, and
+    /// the variables, can be now
+    fn update_completions(&self, helper: &mut MyHelper) {
+        let mut models: Vec<String> = self
+            .registry
+            .models_for(&self.model_mode)
+            .iter()
+            .map(|m| m.id.clone())
+            .collect();
+        models.sort();
+        let sessions: Vec<String> = match self.sessions.list() {
+            Ok(sessions) => sessions.into_iter().map(|(name, _)| name).collect(),
+            Err(_) => vec![],
+        };
+        let branches: Vec<String> = self.tree.branches().into_iter().map(|(name, _)| name).collect();
+        helper.arguments = HashMap::from([
+            ("ms".to_string(), models),
+            ("m".to_string(), MODES.iter().map(|m| m.to_string()).collect()),
+            ("fd".to_string(), self.file_ids.clone()),
+            ("fi".to_string(), self.file_ids.clone()),
+            ("fc".to_string(), self.file_ids.clone()),
+            ("ft".to_string(), self.file_ids.clone()),
+            ("pf".to_string(), self.config.profiles.keys().cloned().collect()),
+            ("ss".to_string(), sessions.clone()),
+            ("sn".to_string(), sessions.clone()),
+            ("sd".to_string(), sessions),
+            ("tpl".to_string(), self.templates.list().unwrap_or_default()),
+            ("br".to_string(), branches.clone()),
+            ("bdiff".to_string(), branches),
+        ]);
+        helper.variables = self.local_data.keys().cloned().collect();
+        helper.variables.sort();
+    }
+
     fn expand_variables(&self, input: String) -> Result<String, Box<dyn Error>> {
         let re = Regex::new(r"\{(\w+)\}").unwrap();
         let result = re
@@ -229,6 +627,324 @@ impl CliInterface {
         Ok(result)
     }
 
+    /// "! tpl <name> key=value..." is replaced by the rendered
+    /// template, which is sent as the prompt.  Anything else is
+    /// returned unchanged
+    fn expand_template(&self, prompt: String) -> Result<String, Box<dyn Error>> {
+        let rest = match prompt.trim().strip_prefix('!').map(|r| r.trim_start()) {
+            Some(rest) => rest,
+            None => return Ok(prompt),
+        };
+        let rest = match rest.strip_prefix("tpl ") {
+            Some(rest) => rest.trim(),
+            None => return Ok(prompt),
+        };
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        self.templates.render(name, &template::parse_args(args)?)
+    }
+
+    /// "! retry [<model>] [<temperature>]" and "! edit" take the last
+    /// turn off the context and are replaced by its prompt, edited for
+    /// "! edit".  Anything else is returned unchanged
+    fn expand_resend(
+        &mut self,
+        prompt: String,
+        api_interface: &mut ApiInterface,
+        read_line: &mut Editor<MyHelper, FileHistory>,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut words = match prompt.trim().strip_prefix('!') {
+            Some(rest) => rest.split_whitespace(),
+            None => return Ok(prompt),
+        };
+        let command = words.next().unwrap_or("");
+        if command != "retry" && command != "edit" {
+            return Ok(prompt);
+        }
+        if self.model_mode != ModelMode::Chat || !self.compare_models.is_empty() {
+            return Err(format!("`! {command}` is for chats, not comparing models").into());
+        }
+        let mut model = self.model.clone();
+        let mut temperature = api_interface.temperature;
+        for arg in words {
+            match arg.parse::<f32>() {
+                Ok(t) => temperature = t,
+                Err(_) if command == "retry" => {
+                    if self.registry.supports(arg, &self.model_mode) == Some(false) {
+                        return Err(format!("{arg} cannot be used in {} mode", self.model_mode).into());
+                    }
+                    model = arg.to_string();
+                }
+                Err(_) => return Err(format!("`! edit` takes no arguments: {arg}").into()),
+            }
+        }
+        let turn = api_interface
+            .context
+            .undo()
+            .ok_or_else(|| format!("Nothing to {command}"))?;
+        let new_prompt = if command == "edit" {
+            match read_line.readline_with_initial("edit:> ", (turn.prompt.as_str(), "")) {
+                Ok(p) if !p.trim().is_empty() => p,
+                _ => {
+                    api_interface.context.restore(turn);
+                    return Err("Not edited".into());
+                }
+            }
+        } else {
+            turn.prompt.clone()
+        };
+        self.resend = Some((
+            turn,
+            api_interface.context.len(),
+            std::mem::replace(&mut self.model, model),
+            std::mem::replace(&mut api_interface.temperature, temperature),
+        ));
+        Ok(new_prompt)
+    }
+
+    /// After `! retry` or `! edit` go back to the model and
+    /// temperature.  If there is no new reply put the old one back
+    fn finish_resend(&mut self, api_interface: &mut ApiInterface) {
+        if let Some((turn, len, model, temperature)) = self.resend.take() {
+            self.model = model;
+            api_interface.temperature = temperature;
+            if api_interface.context.len() == len {
+                api_interface.context.restore(turn);
+            }
+        }
+    }
+
+    /// The total spent, from the ledger.  If it cannot be read that
+    /// is a warning, not a reason to stop
+    fn spent(api_interface: &ApiInterface) -> f64 {
+        api_interface.ledger().spent().unwrap_or_else(|err| {
+            eprintln!("{err}: Cannot read the ledger");
+            0.0
+        })
+    }
+
+    /// The budgets and what has been spent
+    fn budget_report(api_interface: &ApiInterface) -> String {
+        let now = Local::now();
+        match api_interface.spending() {
+            Ok(spending) => format!(
+                "{}\nSpent today: {:.2} This month: {:.2}",
+                api_interface.budget,
+                spending.today(now),
+                spending.this_month(now)
+            ),
+            Err(err) => format!("{}\n{err}: Cannot read spending", api_interface.budget),
+        }
+    }
+
+    /// The profiles, with the one in use marked
+    fn profile_list(&self) -> String {
+        if self.config.profiles.is_empty() {
+            return format!(
+                "No profiles.  Add them to {}",
+                config::Config::default_path().display()
+            );
+        }
+        self.config
+            .profiles
+            .iter()
+            .fold(String::new(), |a, (name, profile)| {
+                let mark = if self.profile.as_deref() == Some(name.as_str()) {
+                    "*"
+                } else {
+                    " "
+                };
+                format!("{a}{mark} {name}: {profile}\n")
+            })
+    }
+
+    /// Change to the settings in the profile `name`.  Those it does
+    /// not set are left alone
+    fn use_profile(
+        &mut self,
+        name: &str,
+        api_interface: &mut ApiInterface,
+    ) -> Result<String, Box<dyn Error>> {
+        let profile = self.config.get(name)?.clone();
+        // Check everything before changing anything
+        let mode = match profile.mode.as_deref() {
+            Some(mode) => Some(
+                ModelMode::from_str(mode).map_err(|_| format!("{mode} is an invalid mode"))?,
+            ),
+            None => None,
+        };
+        let provider = profile.provider.clone().unwrap_or_default();
+        let azure = match provider.azure_config.as_deref() {
+            Some(path) => Some(AzureConfig::from_file(Path::new(path))?),
+            None => None,
+        };
+        if let Some(model) = profile.model {
+            self.model = model;
+        }
+        if let Some(mode) = mode {
+            self.model_mode = mode;
+        }
+        if let Some(temperature) = profile.temperature {
+            api_interface.temperature = temperature;
+        }
+        if let Some(max_tokens) = profile.max_tokens {
+            api_interface.tokens = max_tokens;
+        }
+        if let Some(system_prompt) = profile.system_prompt {
+            api_interface.context.purpose = system_prompt;
+        }
+        if let Some(record_file) = profile.record_file {
+            self.record_file = record_file;
+        }
+        if let Some(key) = provider.api_key_env.and_then(|name| env::var(name).ok()) {
+            api_interface.set_api_key(key);
+        }
+        if let Some(key) = provider
+            .anthropic_api_key_env
+            .and_then(|name| env::var(name).ok())
+        {
+            api_interface.set_anthropic_api_key(key);
+        }
+        if let Some(url) = provider.ollama_url.as_deref() {
+            api_interface.set_ollama_url(url);
+        }
+        if let Some(azure) = azure {
+            api_interface.set_azure(azure);
+        }
+        self.profile = Some(name.to_string());
+        Ok(format!("Profile: {name}\nModel: {} Mode: {}", self.model, self.model_mode))
+    }
+
+    /// Save the session in use, if there is one
+    fn save_session(&mut self, api_interface: &ApiInterface) -> Result<(), Box<dyn Error>> {
+        if let Some(name) = self.session.as_deref() {
+            let context = api_interface.get_context()?;
+            self.tree.update(&context);
+            self.sessions.save(
+                name,
+                &Session {
+                    model: self.model.clone(),
+                    mode: self.model_mode.to_string(),
+                    context,
+                    tree: Some(self.tree.clone()),
+                    updated: Local::now(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Change to the session `name`, resuming it if it was saved.  A
+    /// new session keeps the conversation if there was no session,
+    /// otherwise it starts a new conversation with the same system
+    /// prompt
+    fn use_session(
+        &mut self,
+        name: &str,
+        api_interface: &mut ApiInterface,
+    ) -> Result<String, Box<dyn Error>> {
+        self.save_session(api_interface)?;
+        if self.sessions.exists(name) {
+            let session = self.sessions.load(name)?;
+            self.model_mode = ModelMode::from_str(session.mode.as_str())
+                .map_err(|_| format!("{} is an invalid mode", session.mode))?;
+            self.model = session.model;
+            self.tree = match session.tree {
+                Some(tree) => tree,
+                None => ConversationTree::new(&session.context),
+            };
+            api_interface.set_context(session.context);
+            self.session = Some(name.to_string());
+            Ok(format!(
+                "Resumed session: {name}\nModel: {} Mode: {} Turns: {} Cost: {:.2}",
+                self.model,
+                self.model_mode,
+                api_interface.context.len(),
+                api_interface.context.cost
+            ))
+        } else {
+            if self.session.is_some() {
+                api_interface.clear_context();
+                self.tree = ConversationTree::new(&api_interface.context);
+            }
+            self.session = Some(name.to_string());
+            self.save_session(api_interface)?;
+            Ok(format!("New session: {name}"))
+        }
+    }
+
+    /// The saved sessions, most recent first, with the one in use
+    /// marked
+    fn session_list(&self) -> Result<String, Box<dyn Error>> {
+        let sessions = self.sessions.list()?;
+        if sessions.is_empty() {
+            return Ok("No sessions.  Start one with `! ss <name>`".to_string());
+        }
+        Ok(sessions.iter().fold(String::new(), |a, (name, session)| {
+            let mark = if self.session.as_deref() == Some(name.as_str()) {
+                "*"
+            } else {
+                " "
+            };
+            format!(
+                "{a}{mark} {name}: {} {} Turns: {} Cost: {:.2} Updated: {}\n",
+                session.model,
+                session.mode,
+                session.context.len(),
+                session.context.cost,
+                session.updated.format("%Y-%m-%d %H:%M"),
+            )
+        }))
+    }
+
+    /// Code block `n`, counting from 1, in the last reply
+    fn code_block(api_interface: &ApiInterface, n: Option<&str>) -> Result<CodeBlock, String> {
+        let blocks = match api_interface.context.last_reply() {
+            Some(reply) => code_block::code_blocks(reply),
+            None => return Err("No reply".to_string()),
+        };
+        if blocks.is_empty() {
+            return Err("No code in the last reply".to_string());
+        }
+        match n.and_then(|n| n.parse::<usize>().ok()) {
+            Some(n) if n >= 1 && n <= blocks.len() => Ok(blocks[n - 1].clone()),
+            _ => Err(format!("Enter a code block, 1 to {}", blocks.len())),
+        }
+    }
+
+    /// Show what the diff in code block `n` changes, and apply it to
+    /// the working directory if the user agrees
+    fn apply_patch(
+        &self,
+        api_interface: &ApiInterface,
+        n: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        let block = Self::code_block(api_interface, n)?;
+        if !block.is_diff() {
+            return Ok("That is not a diff".to_string());
+        }
+        let patch = Patch::parse(block.code.as_str())?;
+        let diff = format!("```diff\n{}```", block.code);
+        println!("{}", markdown::display(diff.as_str(), self.render));
+        let dir = current_dir()?;
+        if !shell::confirm(format!("{patch}\nApply to {}?", dir.display()).as_str())? {
+            return Ok("Not applied".to_string());
+        }
+        patch.apply(dir.as_path())?;
+        Ok("Applied".to_string())
+    }
+
+    /// Run `command`, if the user agrees, and keep its output as
+    /// `{name}` for prompts
+    fn run_command(&mut self, name: &str, command: &str) -> Result<String, Box<dyn Error>> {
+        if !shell::confirm(format!("$ {command}\nRun it?").as_str())? {
+            return Ok("Not run".to_string());
+        }
+        let (status, output) = shell::run(command)?;
+        let response = format!("{output}\nExit status: {status}.  The output is {{{name}}}");
+        _ = self.local_data.insert(name.to_string(), output);
+        Ok(response)
+    }
+
     /// Process prompts that are to effect or inspect the programme itself
     /// `prommpt` is what the user entered after the initial "!"
     fn process_meta(
@@ -252,6 +968,7 @@ impl CliInterface {
 		    // files in a random order
 		    let mut sorted_vec = vl.body;
 		    sorted_vec.sort();
+		    self.file_ids = sorted_vec.iter().map(|f| f.1.clone()).collect();
                     response_text = format!(
                         ".....File ID...................Name{}",
                         sorted_vec
@@ -309,9 +1026,200 @@ impl CliInterface {
 			response_text = "Enter a file ID".to_string();
 		    }
 		}
+		"cache" => {
+		    // Inspect or manage the response cache
+		    response_text = match api_interface.cache() {
+			None => "No cache.  Start with `--cache`".to_string(),
+			Some(cache) => match meta.next() {
+			    None => format!(
+				"Cache: {} {}{}",
+				cache.config().dir.display(),
+				cache.stats()?,
+				if cache.bypass() { " (off)" } else { "" }
+			    ),
+			    Some("clear") => format!("Cleared {} entries", cache.clear()?),
+			    Some("off") => {
+				cache.set_bypass(true);
+				"Cache off".to_string()
+			    }
+			    Some("on") => {
+				cache.set_bypass(false);
+				"Cache on".to_string()
+			    }
+			    Some(arg) => format!("Unknown cache command: {arg}"),
+			},
+		    };
+		}
+		"tpl" => {
+		    // List the templates.  "! tpl <name>..." is expanded
+		    // before it gets here
+		    let names = self.templates.list()?;
+		    let mut list = format!("Templates in {}", self.templates.dir().display());
+		    for name in names.iter() {
+			list += match self.templates.load(name) {
+			    Ok(t) => format!(
+				"\n\t{name} {}",
+				t.params.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", ")
+			    ),
+			    Err(err) => format!("\n\t{name} {err}"),
+			}.as_str();
+		    }
+		    response_text = list;
+		}
+		"fb" => {
+		    // The models to fall back to when the model is busy
+		    let models: Vec<String> = meta.map(|m| m.to_string()).collect();
+		    if models.as_slice() == ["none"] {
+			api_interface.fallback.clear();
+		    } else if !models.is_empty() {
+			api_interface.fallback = models;
+		    }
+		    response_text = if api_interface.fallback.is_empty() {
+			"No fallback models".to_string()
+		    } else {
+			format!("Fallback: {} {}", self.model, api_interface.fallback.join(" "))
+		    };
+		}
+		"b" => {
+		    // Display or set a budget, in cents
+		    let args: Vec<&str> = meta.collect();
+		    response_text = match args.as_slice() {
+			[] => Self::budget_report(api_interface),
+			[period, cents] => match period.parse::<Period>() {
+			    Ok(period) => {
+				let limit = if *cents == "none" {
+				    Ok(None)
+				} else {
+				    cents.parse::<f64>().map(Some)
+				};
+				match limit {
+				    Ok(limit) => {
+					api_interface.budget.set(period, limit);
+					Self::budget_report(api_interface)
+				    }
+				    Err(_) => format!("Not a number of cents: {cents}"),
+				}
+			    }
+			    Err(err) => format!("{err}"),
+			},
+			_ => "Usage: ! b [<request|conversation|day|month> <cents|none>]".to_string(),
+		    };
+		}
+		"cmp" => {
+		    // Compare chat models.  Prompts go to all of them
+		    let models: Vec<String> = meta.map(|m| m.to_string()).collect();
+		    if models.as_slice() == ["off"] {
+			self.compare_models.clear();
+			self.comparison = None;
+		    } else if !models.is_empty() {
+			self.compare_models = models;
+		    }
+		    response_text = if self.compare_models.is_empty() {
+			"Not comparing models".to_string()
+		    } else {
+			format!("Comparing: {}.  `! adopt <n>` to continue with a reply", self.compare_models.join(" "))
+		    };
+		}
+		"adopt" => {
+		    // Continue the conversation with one of the compared replies
+		    let n: Option<usize> = meta.next().and_then(|n| n.parse().ok());
+		    response_text = match (self.comparison.as_ref(), n) {
+			(None, _) => "Nothing to adopt.  Compare models with `! cmp`".to_string(),
+			(Some((prompt, comparisons)), Some(n)) if n >= 1 && n <= comparisons.len() => {
+			    let comparison = &comparisons[n - 1];
+			    if api_interface.adopt(prompt, comparison) {
+				let response = format!("Adopted the reply from {}", comparison.model);
+				self.comparison = None;
+				response
+			    } else {
+				format!("{} did not reply", comparison.model)
+			    }
+			}
+			(Some((_, comparisons)), _) => format!("Enter a reply number, 1 to {}", comparisons.len()),
+		    };
+		}
+		"cb" => {
+		    // List the code blocks in the last reply
+		    response_text = match api_interface.context.last_reply() {
+			Some(reply) => match code_block::code_blocks(reply) {
+			    blocks if blocks.is_empty() => "No code in the last reply".to_string(),
+			    blocks => blocks.iter().enumerate().fold(String::new(), |a, (i, block)| {
+				format!("{a}[{}] {block}\n", i + 1)
+			    }),
+			},
+			None => "No reply".to_string(),
+		    };
+		}
+		"cw" | "ca" => {
+		    // Write, or append, a code block to a file
+		    let n = meta.next();
+		    let path: String = meta.collect::<Vec<&str>>().join(" ");
+		    response_text = match Self::code_block(api_interface, n) {
+			Ok(_) if path.is_empty() => "Enter the file to write the code to".to_string(),
+			Ok(block) => {
+			    let mut options = OpenOptions::new();
+			    if cmd == "cw" {
+				options.write(true).create(true).truncate(true);
+			    } else {
+				options.append(true).create(true);
+			    }
+			    match options.open(path.as_str()).and_then(|mut f| f.write_all(block.code.as_bytes())) {
+				Ok(()) if cmd == "cw" => format!("Wrote {} lines to {path}", block.code.lines().count()),
+				Ok(()) => format!("Appended {} lines to {path}", block.code.lines().count()),
+				Err(err) => format!("{err}: Cannot write to {path}"),
+			    }
+			}
+			Err(err) => err,
+		    };
+		}
+		"sh" => {
+		    // Run a command.  "! sh {name} <command>" to keep the
+		    // output as `{name}`, otherwise it is `{sh}`
+		    let mut words: Vec<&str> = meta.collect();
+		    let name = match words.first().and_then(|w| w.strip_prefix('{')).and_then(|w| w.strip_suffix('}')) {
+			Some(name) if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+			    let name = name.to_string();
+			    words.remove(0);
+			    name
+			}
+			_ => "sh".to_string(),
+		    };
+		    let command = words.join(" ");
+		    response_text = if command.is_empty() {
+			"! sh [{name}] <command>: Run a command.  Its output is `{name}`, or `{sh}`, in prompts".to_string()
+		    } else {
+			match self.run_command(name.as_str(), command.as_str()) {
+			    Ok(s) => s,
+			    Err(err) => format!("{err}: Cannot run {command}"),
+			}
+		    };
+		}
+		"shm" => {
+		    // Toggle offering to run commands the model proposes
+		    self.shell_mode = !self.shell_mode;
+		    response_text = format!("Run proposed commands: {}", self.shell_mode);
+		}
+		"patch" => {
+		    // Apply a diff in the last reply to the working directory
+		    response_text = match self.apply_patch(api_interface, meta.next()) {
+			Ok(s) => s,
+			Err(err) => format!("{err}"),
+		    };
+		}
+		"st" => {
+		    // Toggle streaming of chat replies
+		    self.stream = !self.stream;
+		    response_text = format!("Streaming: {}", self.stream);
+		}
+		"fmt" => {
+		    // Toggle displaying replies as Markdown
+		    self.render = !self.render;
+		    response_text = format!("Format replies: {}", self.render);
+		}
 		"fd" => {
 		    // Delete a file
                     let file_id: String = meta.collect::<Vec<&str>>().join(" ");
+		    self.file_ids.retain(|id| *id != file_id);
 		    response_text = match api_interface.files_delete(file_id) {
 			Ok(_) => "Deleted".to_string(),
 			Err(err) => format!("{err} Failed to delete"),
@@ -320,20 +1228,32 @@ impl CliInterface {
                 "p" => {
                     response_text = format!(
                         "OpenAI Interface: {api_interface}\n\
+			 Profile: {}\n\
+			 Session: {}\n\
+			 Branch: {}\n\
 			 Record File:{}\n\
 			 Model: {}\n\
 			 Model Mode: {}\n\
 			 Image: {:#?}\n\
 			 mask: {:#?}\n\
 			 audio file:{:#?}\n\
+			 Stream: {}\n\
+			 Format: {}\n\
+			 Run proposed commands: {}\n\
 			 Completions{}",
                         // Display the parameters
+                        self.profile.as_deref().unwrap_or("none"),
+                        self.session.as_deref().unwrap_or("none"),
+                        self.tree.current(),
                         self.record_file,
 			self.model,
 			self.model_mode,
 			self.image,
 			self.mask,
 			self.audio_file,
+			self.stream,
+			self.render,
+			self.shell_mode,
 			self.local_data.keys().fold("".to_string(), |a, b| format!("{a}\n\t{b}")),
                     );
                 }
@@ -342,16 +1262,22 @@ impl CliInterface {
 		    response_text = match api_interface.model_list() {
 			Ok(s) => s.body,
 			Err(err) => format!("{err}: Failed to get model list"),
-		    }
+		    } + match api_interface.local_model_info() {
+			Ok(tags) => tags.models.iter().fold("Local Models".to_string(), |a, m| {
+			    format!("{a}\n  - ollama/{} Size: {} Modified: {}", m.name, m.size, m.modified_at)
+			}),
+			Err(err) => format!("{err}: Failed to get local models"),
+		    }.as_str()
 		}
                 "md" => {
-                    // Display known models
-                    let mut model_list: Vec<&str> = self.model_mode.models_available();
-                    model_list.sort();
+                    // Display the models for the mode, after asking
+                    // the providers what they offer
+		    api_interface.update_registry(&mut self.registry);
                     response_text = format!(
                         "Models for mode: {}: {}",
                         self.model_mode,
-                        model_list
+                        self.registry
+                            .models_for(&self.model_mode)
                             .iter()
                             .fold(String::new(), |a, b| format!("{a}\n\t{b}"))
                     );
@@ -359,21 +1285,55 @@ impl CliInterface {
                 "ms" => {
                     // Set a model
                     if let Some(model_name) = meta.next() {
-                        response_text = format!("New model: {model_name}");
-                        self.model = model_name.to_string();
+			response_text = match self.registry.supports(model_name, &self.model_mode) {
+			    Some(false) => format!("{model_name} cannot be used in {} mode", self.model_mode),
+			    supported => {
+				self.model = model_name.to_string();
+				match supported {
+				    Some(_) => format!("New model: {model_name}"),
+				    None => format!("New model: {model_name} (Capabilities unknown)"),
+				}
+			    }
+			};
                     } else {
                         response_text = "No model".to_string();
                     }
                 }
+                "pf" => {
+                    // List the profiles, or change to one
+                    response_text = match meta.next() {
+                        Some(name) => match self.use_profile(name, api_interface) {
+                            Ok(s) => s,
+                            Err(err) => format!("{err}"),
+                        },
+                        None => self.profile_list(),
+                    };
+                }
+		"pull" => {
+		    // Download a model into Ollama
+		    match meta.next() {
+			Some(model) => {
+			    let mut on_progress = |status: &OllamaPullStatus| {
+				match (status.completed, status.total) {
+				    (Some(completed), Some(total)) if total > 0 => print!(
+					"\r{}: {:>3}% ({completed}/{total})   ",
+					status.status,
+					completed * 100 / total
+				    ),
+				    _ => print!("\n{}", status.status),
+				};
+				_ = std::io::stdout().flush();
+			    };
+			    response_text = match api_interface.pull_model(model, &mut on_progress) {
+				Ok(()) => format!("\nPulled: {}", OllamaInterface::local_name(model)),
+				Err(err) => format!("\n{err}: Failed to pull {model}"),
+			    };
+			}
+			None => response_text = "Enter a model to pull into Ollama".to_string(),
+		    }
+		}
                 "ml" => {
-                    response_text = "Modes\n\t\
-				     completions\n\t\
-				     chat\n\t\
-				     image\n\t\
-				     image_edit\n\t\
-				     audio_transcription\n\t\
-				     "
-			.to_string()
+                    response_text = MODES.iter().fold("Modes".to_string(), |a, m| format!("{a}\n\t{m}"));
                 }
                 "m" => {
                     // Set the mode (effectively the API endpoint at OpenAI
@@ -482,6 +1442,111 @@ impl CliInterface {
                     response_text = "Clear context".to_string();
                     api_interface.clear_context();
                 }
+		"br" => {
+		    // List the branches, or change to one
+		    self.tree.update(&api_interface.context);
+		    response_text = match meta.next() {
+			Some(name) => match self.tree.switch(name) {
+			    Ok(context) => {
+				api_interface.set_context(context);
+				format!("Branch: {name} Turns: {}", api_interface.context.len() / 2)
+			    }
+			    Err(err) => format!("{err}"),
+			},
+			None => self.tree.branches().iter().fold(String::new(), |a, (name, turns)| {
+			    let mark = if name == self.tree.current() { "*" } else { " " };
+			    format!("{a}{mark} {name}: {turns} turns\n")
+			}),
+		    };
+		}
+		"fork" => {
+		    // Start a branch from a turn of this one
+		    self.tree.update(&api_interface.context);
+		    let name = meta.next().unwrap_or("");
+		    let turns = match meta.next().map(|t| t.parse::<usize>()) {
+			Some(Ok(turns)) => Ok(Some(turns)),
+			Some(Err(err)) => Err(format!("{err}: Turns must be a number")),
+			None => Ok(None),
+		    };
+		    response_text = match turns.map(|turns| self.tree.fork(name, turns)) {
+			Ok(Ok(context)) => {
+			    api_interface.set_context(context);
+			    format!("Branch: {name} Turns: {}", api_interface.context.len() / 2)
+			}
+			Ok(Err(err)) => format!("{err}"),
+			Err(err) => err,
+		    };
+		}
+		"bdiff" => {
+		    // Compare two branches
+		    self.tree.update(&api_interface.context);
+		    let from = meta.next().unwrap_or("");
+		    let to = meta.next().unwrap_or(self.tree.current()).to_string();
+		    response_text = match self.tree.diff(from, to.as_str()) {
+			Ok(diff) => diff.to_string(),
+			Err(err) => format!("{err}"),
+		    };
+		}
+		"undo" => {
+		    // The money was spent, so spending per day and
+		    // month is unchanged
+		    response_text = match api_interface.context.undo() {
+			Some(turn) => format!(
+			    "Undone: {}\nCost: {:.2}/{:.2}:{}",
+			    turn.prompt.lines().next().unwrap_or(""),
+			    turn.cost,
+			    api_interface.context.cost,
+			    api_interface.context.len()
+			),
+			None => "Nothing to undo".to_string(),
+		    };
+		}
+		"ex" => {
+		    // Export the conversation.  Fine-tuning data is
+		    // appended, so conversations can be collected
+		    let format = meta.next().map(|f| f.parse::<ExportFormat>());
+		    let file_path: String = meta.collect::<Vec<&str>>().join(" ");
+		    response_text = match format {
+			Some(Ok(_)) if file_path.is_empty() => "Enter a path to export to".to_string(),
+			Some(Ok(format)) => {
+			    let context = api_interface.get_context()?;
+			    let text = export::export(&context, format);
+			    let mut options = OpenOptions::new();
+			    if format == ExportFormat::FineTune {
+				options.append(true).create(true);
+			    } else {
+				options.write(true).create(true).truncate(true);
+			    }
+			    match options.open(file_path.as_str()) {
+				Ok(mut f) => {
+				    f.write_all(text.as_bytes())?;
+				    format!("Exported conversation as {format} to {file_path}")
+				}
+				Err(err) => format!("{err}: Failed to open file at: {file_path}"),
+			    }
+			}
+			Some(Err(err)) => format!("{err}"),
+			None => "Enter a format (md, html or jsonl) and a path".to_string(),
+		    };
+		}
+		"ix" => {
+		    // Import a ChatGPT export as context files for `! rx`
+		    match (meta.next(), meta.next()) {
+			(Some(export), Some(dir)) => {
+			    response_text = match chatgpt_import::import_to_dir(Path::new(export), Path::new(dir)) {
+				Ok(files) => format!(
+				    "Imported {} conversations into {dir}{}",
+				    files.len(),
+				    files.iter().fold(String::new(), |a, b| format!("{a}\n\t{}", b.display()))
+				),
+				Err(err) => format!("{err}: Failed to import {export}"),
+			    };
+			}
+			_ => {
+			    response_text = "Enter the path of a ChatGPT \"conversations.json\" and a directory for the contexts".to_string();
+			}
+		    }
+		}
 		"ppx" => {
 		    // Print out the conversation to the passed path
 		    // in a human readable form
@@ -493,7 +1558,7 @@ impl CliInterface {
 			    // Save the context into the specified file
 			    let context: Context = api_interface.get_context()?;
 			    // `context` has query/response pairs.  So has an even length
-			    assert!(context.len() % 2 == 0);
+			    assert!(context.len().is_multiple_of(2));
 			    let context = CliInterface::pretty_print_conversation(context)?;
 			    f.write_all(context.as_bytes())?;
 			    format!("Wrote context to {file_path}")
@@ -692,11 +1757,53 @@ impl CliInterface {
                     };
 
                 }
+                "ss" => {
+                    // List the sessions, or change to one
+                    response_text = match meta.next() {
+                        Some(name) => match self.use_session(name, api_interface) {
+                            Ok(s) => s,
+                            Err(err) => format!("{err}"),
+                        },
+                        None => self.session_list()?,
+                    };
+                }
+                "sn" => {
+                    // Rename a session
+                    response_text = match (meta.next(), meta.next()) {
+                        (Some(from), Some(to)) => match self.sessions.rename(from, to) {
+                            Ok(()) => {
+                                if self.session.as_deref() == Some(from) {
+                                    self.session = Some(to.to_string());
+                                }
+                                format!("Renamed session {from} to {to}")
+                            }
+                            Err(err) => format!("{err}"),
+                        },
+                        _ => "Enter the session and its new name".to_string(),
+                    };
+                }
+                "sd" => {
+                    // Delete a session.  If it is in use it is no
+                    // longer saved
+                    response_text = match meta.next() {
+                        Some(name) => match self.sessions.delete(name) {
+                            Ok(()) => {
+                                if self.session.as_deref() == Some(name) {
+                                    self.session = None;
+                                }
+                                format!("Deleted session {name}")
+                            }
+                            Err(err) => format!("{err}"),
+                        },
+                        None => "Enter the session to delete".to_string(),
+                    };
+                }
 		"sx" => {
 		    let file_path: String = meta.collect::<Vec<&str>>().join(" ");
-		    // Save the context into the specified file
-		    let context = api_interface.get_context()?;
-		    let serialized_context = serde_json::to_string(&context)?;
+		    // Save the conversation, all its branches, into the
+		    // specified file
+		    self.tree.update(&api_interface.get_context()?);
+		    let serialized_context = serde_json::to_string(&self.tree)?;
 		    response_text = format!("Saved context to {}", file_path);
 		    let mut file = File::create(file_path)?;
 		    file.write_all(serialized_context.as_bytes())?;
@@ -711,13 +1818,11 @@ impl CliInterface {
 			    current_dir()?.display()
 			);
 		    } else if PathBuf::from(file_path.as_str()).exists() {
-			// Read the contents of the file.
-			let file_contents = fs::read_to_string(Path::new(&file_path))?;
-			// Deserialize the Vec<String> from the file contents.
-			let context: Context = serde_json::from_str(&file_contents)?;
+			let (context, tree) = read_conversation(Path::new(&file_path))?;
 
 			// Set the context in the API interface.
 			api_interface.set_context(context);
+			self.tree = tree;
 
 			response_text = "Context loaded from file.".to_string();
 		    } else {
@@ -727,39 +1832,19 @@ impl CliInterface {
 			);
 		    }
 		}
-                "?" => {
-                    response_text = "\
-		    p  Display settings\n\
-		    mm Display all available information about all models\n\
-		    md Display all available models for the current mode\n\
-		    ms <model> Change the current model\n\
-		    ml List modes\
-		    m  <mode> Change mode (API endpoint\n\
-		    dx Display context (for chat)\n\
-		    cx Clear context\n\
-		    ppx <path> Pretty print conversation to path\n\
-		    v  Set verbosity\n\
-		    k  Set max tokens for completions\n\
-		    t  Set temperature for completions\n\
-		    sp Set system prompt (after `! cc`\n\
-		    ci Clear image\
-		    mask <path> Set the mask to use in image edit mode.  A 1024x1024 PNG with transparent mask\n\
-		    a <path> Audio file for transcription\n\
-		    ci Clear the image stored for editing\n\
-		    f List the files stored on the server\n\
-		    fu <path> Upload a file of fine tuning data\n\
-		    fd <file id> Delete a file\n\
-		    fi <file id> Get information about file\n\
-		    fc <file id> [destination_file] Get contents of file\n\
-		    ft <file ID> Start a fine tune model using a JASONL training file \n\
-		    ftl List fine tunes\n\
-		    ftr <id> Retrieve the info for a fine tune\n\
-		    fl <name> <path>  Associate the contents of the `path` with `name` for use in prompts like: {{name}}\n\
-		    sx <path>  Save the context to a file at the specified path\n\
-		    rx <path>  Restore the context from a file at the specified path\n\
- 		    ?  This text\n"
-                        .to_string()
-                }
+		"$" => {
+		    // Summarise spending.  To a CSV file if there is a path
+		    let file_path: String = meta.collect::<Vec<&str>>().join(" ");
+		    response_text = match api_interface.ledger().charges().map(|charges| Report::new(&charges)) {
+			Ok(report) if file_path.is_empty() => report.to_string(),
+			Ok(report) => match fs::write(file_path.as_str(), report.to_csv()) {
+			    Ok(_) => format!("Wrote spending to {file_path}"),
+			    Err(err) => format!("{err}: Cannot write {file_path}"),
+			},
+			Err(err) => format!("{err}: Cannot read the ledger"),
+		    };
+		}
+                "?" => response_text = META_HELP.to_string(),
                 _ => response_text = format!("Unknown command: {cmd}\n"),
             };
         } else {
@@ -786,7 +1871,6 @@ impl CliInterface {
                     .insert(k.clone(), response_headers.get(k).unwrap().clone());
                 result += &format!("{k}: {}\n", response_headers[k]);
             }
-        } else {
         }
         Ok(result)
     }
@@ -797,28 +1881,394 @@ impl CliInterface {
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Get the command line options
-    let cmd_line_opts = Arguments::parse();
-
+/// The `ApiInterface` the command line asks for.  Requests are
+/// recorded to `record`, or answered from `replay`, if given
+fn make_api_interface(
+    cmd_line_opts: &Arguments,
+    record: Option<&Path>,
+    replay: Option<&Path>,
+) -> Result<ApiInterface, Box<dyn Error>> {
     // API key.  Stored in openai_interface
     let _key_binding: String;
     let api_key = match cmd_line_opts.api_key.as_deref() {
         Some(key) => key,
         None => {
-            _key_binding = env::var("OPENAI_API_KEY").unwrap();
+            // Not needed if only using Anthropic models
+            _key_binding = env::var("OPENAI_API_KEY").unwrap_or_default();
             _key_binding.as_str()
         }
     };
 
-    // The model.  Stored in openai_interface
-    let model = cmd_line_opts.model.as_str();
+    let mut builder = ApiInterface::builder(
+        api_key.to_string(),
+        cmd_line_opts.max_tokens,
+        cmd_line_opts.temperature,
+    );
+    if let Some(proxy) = cmd_line_opts.proxy.as_deref() {
+        builder = builder.proxy(proxy);
+    }
+    for path in cmd_line_opts.ca_cert.iter() {
+        builder = builder.root_certificate(path.clone());
+    }
+    if let Some(seconds) = cmd_line_opts.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(seconds) = cmd_line_opts.timeout {
+        builder = builder.timeout(Duration::from_secs(seconds));
+    }
+    for header in cmd_line_opts.header.iter() {
+        match header.split_once(':') {
+            Some((name, value)) => builder = builder.header(name.trim(), value.trim()),
+            None => return Err(format!("Bad header: {header}").into()),
+        }
+    }
+    if let Some(path) = cmd_line_opts.telemetry.as_ref() {
+        builder = builder.telemetry_file(path.clone());
+    }
+    if cmd_line_opts.cache {
+        let project_dir = ProjectDirs::from("worik", "org", "llm-rs").unwrap();
+        let mut config = CacheConfig::new(project_dir.cache_dir().to_path_buf());
+        if let Some(hours) = cmd_line_opts.cache_ttl {
+            config.ttl = Duration::from_secs(hours * 60 * 60);
+        }
+        if let Some(megabytes) = cmd_line_opts.cache_size {
+            config.max_bytes = megabytes * 1024 * 1024;
+        }
+        builder = builder.cache(config);
+    }
+    if let Some(path) = record {
+        builder = builder.record_file(path.to_path_buf());
+    }
+    if let Some(path) = replay {
+        builder = builder.replay_file(path.to_path_buf());
+    }
+    // Charges are kept so budgets per day and month apply across runs
+    builder = builder.ledger(ledger());
+    let mut api_interface = builder.build()?;
+    api_interface.fallback = cmd_line_opts.fallback.clone();
+    api_interface.budget = Budget {
+        per_request: cmd_line_opts.budget_request,
+        per_conversation: cmd_line_opts.budget_conversation,
+        per_day: cmd_line_opts.budget_day,
+        per_month: cmd_line_opts.budget_month,
+    };
+    if let Some(sp) = cmd_line_opts.system_prompt.clone() {
+        api_interface.context.purpose = sp;
+    }
+    if let Some(url) = cmd_line_opts.ollama_url.as_deref() {
+        api_interface.set_ollama_url(url);
+    }
+    if let Some(key) = cmd_line_opts
+        .anthropic_api_key
+        .clone()
+        .or_else(|| env::var("ANTHROPIC_API_KEY").ok())
+    {
+        api_interface.set_anthropic_api_key(key);
+    }
+    if let Some(path) = cmd_line_opts
+        .azure_config
+        .clone()
+        .or_else(|| env::var("AZURE_OPENAI_CONFIG").ok())
+    {
+        api_interface.set_azure(AzureConfig::from_file(Path::new(path.as_str()))?);
+    }
+    Ok(api_interface)
+}
+
+/// Take the settings that were not given on the command line from
+/// `profile`
+fn apply_profile(cmd_line_opts: &mut Arguments, matches: &ArgMatches, profile: &Profile) {
+    let on_command_line =
+        |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if !on_command_line("model") {
+        if let Some(model) = profile.model.clone() {
+            cmd_line_opts.model = model;
+        }
+    }
+    if !on_command_line("mode") {
+        if let Some(mode) = profile.mode.clone() {
+            cmd_line_opts.mode = mode;
+        }
+    }
+    if !on_command_line("temperature") {
+        if let Some(temperature) = profile.temperature {
+            cmd_line_opts.temperature = temperature;
+        }
+    }
+    if !on_command_line("max_tokens") {
+        if let Some(max_tokens) = profile.max_tokens {
+            cmd_line_opts.max_tokens = max_tokens;
+        }
+    }
+    if !on_command_line("record_file") {
+        if let Some(record_file) = profile.record_file.clone() {
+            cmd_line_opts.record_file = record_file;
+        }
+    }
+    if cmd_line_opts.system_prompt.is_none() {
+        cmd_line_opts.system_prompt = profile.system_prompt.clone();
+    }
+    if let Some(provider) = profile.provider.as_ref() {
+        if cmd_line_opts.api_key.is_none() {
+            cmd_line_opts.api_key = provider
+                .api_key_env
+                .as_ref()
+                .and_then(|name| env::var(name).ok());
+        }
+        if cmd_line_opts.anthropic_api_key.is_none() {
+            cmd_line_opts.anthropic_api_key = provider
+                .anthropic_api_key_env
+                .as_ref()
+                .and_then(|name| env::var(name).ok());
+        }
+        if cmd_line_opts.ollama_url.is_none() {
+            cmd_line_opts.ollama_url = provider.ollama_url.clone();
+        }
+        if cmd_line_opts.azure_config.is_none() {
+            cmd_line_opts.azure_config = provider.azure_config.clone();
+        }
+    }
+}
+
+/// The ledger, "ledger.jsonl" in the data directory beside
+/// "shared_state.json".  A new one starts with the total that was
+/// kept in "shared_state.json" before there was a ledger.  With no
+/// data directory the ledger is only kept in memory
+fn ledger() -> Ledger {
+    let data_dir = match ProjectDirs::from("org", "worik", "root") {
+        Some(dirs) => Some(dirs.data_local_dir().to_owned()),
+        None => BaseDirs::new().map(|dirs| dirs.data_local_dir().to_owned()),
+    };
+    match data_dir {
+        Some(dir) => Ledger::with_file(dir.join("ledger.jsonl"))
+            .carry_over(dir.join("shared_state.json")),
+        None => Ledger::default(),
+    }
+}
+
+/// The exit status for `ask` failing with `err`
+fn exit_status(err: &(dyn Error + 'static)) -> i32 {
+    if fallback::is_retryable(err) {
+        return EXIT_TRY_AGAIN;
+    }
+    match err.downcast_ref::<ApiError>().map(|e| &e.error_type) {
+        Some(ApiErrorType::BudgetExceeded { .. }) => EXIT_OVER_BUDGET,
+        Some(ApiErrorType::Cancelled) => EXIT_INTERRUPTED,
+        Some(ApiErrorType::Status(status, _)) if matches!(status.as_u16(), 401 | 403) => {
+            EXIT_UNAUTHORISED
+        }
+        _ => EXIT_FAILED,
+    }
+}
+
+/// What `ask` prints when it gets `result`.  Only the reply, or, as
+/// JSON, the reply with the model that answered, the tokens and the
+/// cost
+fn ask_output(result: &ApiResult<String>, json: bool) -> String {
+    if !json {
+        return result.body.clone();
+    }
+    let header = |name: &str| -> Option<&str> { result.headers.get(name).map(|h| h.as_str()) };
+    let tokens = |name: &str| -> u32 {
+        header(name)
+            .and_then(|t| t.parse().ok())
+            .unwrap_or_default()
+    };
+    let cost: f64 = header("Cost")
+        .and_then(|c| c.parse().ok())
+        .unwrap_or_default();
+    serde_json::json!({
+        "reply": result.body,
+        "model": header(MODEL_HEADER),
+        "usage": {
+            "prompt_tokens": tokens("Tokens prompt"),
+            "completion_tokens": tokens("Tokens completion"),
+            "total_tokens": tokens("Tokens total"),
+        },
+        "cost": cost,
+    })
+    .to_string()
+}
+
+/// Read a conversation saved by `! sx`.  Its branch in use, and all
+/// its branches.  A file of one context, saved before conversations
+/// branched or imported, is a conversation with one branch
+fn read_conversation(path: &Path) -> Result<(Context, ConversationTree), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    if let Ok(tree) = serde_json::from_str::<ConversationTree>(contents.as_str()) {
+        return Ok((tree.context(tree.current())?, tree));
+    }
+    let context: Context = serde_json::from_str(contents.as_str())?;
+    let tree = ConversationTree::new(&context);
+    Ok((context, tree))
+}
+
+/// Send one prompt, made from `words`, standard input and `files`,
+/// and print the reply.  Returns the exit status
+fn ask(
+    cmd_line_opts: &Arguments,
+    words: &[String],
+    files: &[PathBuf],
+    context: Option<&Path>,
+    json: bool,
+) -> i32 {
+    let fail = |status: i32, message: String| -> i32 {
+        if json {
+            println!("{}", serde_json::json!({"error": message, "status": status}));
+        }
+        eprintln!("{message}");
+        status
+    };
+
+    // The prompt
+    let mut input: Option<String> = None;
+    let mut read_input = || -> Result<String, Box<dyn Error>> {
+        if input.is_none() {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            input = Some(text);
+        }
+        Ok(input.clone().unwrap_or_default())
+    };
+    let mut prompt: Vec<String> = vec![];
+    for word in words.iter() {
+        if word == "-" {
+            match read_input() {
+                Ok(text) => prompt.push(text),
+                Err(err) => return fail(EXIT_FAILED, format!("{err}: Cannot read the prompt")),
+            }
+        } else {
+            prompt.push(word.clone());
+        }
+    }
+    let mut prompt = if words.is_empty() {
+        match read_input() {
+            Ok(text) => text,
+            Err(err) => return fail(EXIT_FAILED, format!("{err}: Cannot read the prompt")),
+        }
+    } else {
+        prompt.join(" ")
+    };
+    for path in files.iter() {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                prompt += format!("\n\n{}:\n```\n{}\n```", path.display(), contents.trim_end())
+                    .as_str()
+            }
+            Err(err) => return fail(EXIT_FAILED, format!("{err}: Cannot read {}", path.display())),
+        }
+    }
+    let prompt = prompt.trim();
+    if prompt.is_empty() {
+        return fail(EXIT_NO_PROMPT, "No prompt".to_string());
+    }
+
+    let mut api_interface = match make_api_interface(cmd_line_opts, None, None) {
+        Ok(api_interface) => api_interface,
+        Err(err) => return fail(EXIT_FAILED, format!("{err}")),
+    };
+    if let Some(path) = context {
+        match read_conversation(path) {
+            Ok((context, _)) => {
+                let purpose = api_interface.context.purpose.clone();
+                api_interface.set_context(context);
+                if cmd_line_opts.system_prompt.is_some() {
+                    api_interface.context.purpose = purpose;
+                }
+            }
+            Err(err) => {
+                return fail(EXIT_FAILED, format!("{err}: Cannot load {}", path.display()))
+            }
+        }
+    }
 
-    // Maximum tokens.  Stored in openai_interface.  TODO: Store in CliInterface?
-    let tokens: u32 = cmd_line_opts.max_tokens;
+    // Ctrl-C cancels the request
+    let token = CancellationToken::new();
+    api_interface.cancel_with(Some(token.clone()));
+    _ = ctrlc::set_handler(move || token.cancel());
 
-    // .  Stored in openai_interface
-    let temperature: f32 = cmd_line_opts.temperature;
+    match api_interface.chat(prompt, cmd_line_opts.model.as_str()) {
+        Ok(result) => {
+            println!("{}", ask_output(&result, json));
+            0
+        }
+        Err(err) => fail(exit_status(err.as_ref()), format!("{err}").trim().to_string()),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // Request telemetry is logged.  Set `RUST_LOG` to see it
+    env_logger::init();
+
+    // Get the command line options, with defaults from the profile
+    let matches = Arguments::command().get_matches();
+    let mut cmd_line_opts = Arguments::from_arg_matches(&matches)?;
+    let config = config::Config::from_file(config::Config::default_path().as_path())?;
+    let profile = cmd_line_opts.profile.clone().or(config.profile.clone());
+    if let Some(name) = profile.as_deref() {
+        apply_profile(&mut cmd_line_opts, &matches, config.get(name)?);
+    }
+
+    if let Some(Command::Eval {
+        suite,
+        models,
+        record,
+        replay,
+        report,
+    }) = cmd_line_opts.command.as_ref()
+    {
+        let suite = Suite::from_file(suite)?;
+        let mut models = models.clone();
+        if models.is_empty() && suite.models.is_empty() {
+            models.push(cmd_line_opts.model.clone());
+        }
+        let mut api_interface =
+            make_api_interface(&cmd_line_opts, record.as_deref(), replay.as_deref())?;
+        let result = eval::run(&mut api_interface, &suite, &models);
+        println!("{result}");
+        if let Some(path) = report {
+            fs::write(path, serde_json::to_string_pretty(&result)?)?;
+        }
+        if result.failed() > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Report { csv }) = cmd_line_opts.command.as_ref() {
+        let report = Report::new(&ledger().charges()?);
+        println!("{report}");
+        if let Some(path) = csv {
+            fs::write(path, report.to_csv())?;
+        }
+        return Ok(());
+    }
+    if let Some(Command::Ask {
+        prompt,
+        file,
+        context,
+        json,
+    }) = cmd_line_opts.command.as_ref()
+    {
+        std::process::exit(ask(
+            &cmd_line_opts,
+            prompt,
+            file,
+            context.as_deref(),
+            *json,
+        ));
+    }
+    if cmd_line_opts.stdin {
+        std::process::exit(ask(
+            &cmd_line_opts,
+            &[],
+            &cmd_line_opts.file,
+            cmd_line_opts.context.as_deref(),
+            cmd_line_opts.json,
+        ));
+    }
+
+    // The model.  Stored in openai_interface
+    let model = cmd_line_opts.model.as_str();
 
     // The mode.  Stored in openai_interface.  TODO Should be stored
     // in CliInterface
@@ -840,27 +2290,65 @@ fn main() -> Result<(), Box<dyn Error>> {
         header_cache: HashMap::new(),
         cost: 0.0,
         local_data: HashMap::new(),
+        stream: false,
+        // Escapes would be garbage in a file or a pipe
+        render: std::io::stdout().is_terminal(),
+        shell_mode: false,
+        file_ids: vec![],
+        resend: None,
+        tree: ConversationTree::new(&Context::new("")),
+        templates: TemplateStore::new(match cmd_line_opts.templates.clone() {
+            Some(dir) => dir,
+            None => ProjectDirs::from("worik", "org", "llm-rs")
+                .unwrap()
+                .config_dir()
+                .join("templates"),
+        }),
+        registry: ModelRegistry::builtin(),
+        compare_models: vec![],
+        comparison: None,
+        config,
+        profile,
+        sessions: SessionStore::new(),
+        session: None,
     };
+    if let Some(path) = cmd_line_opts.models.as_ref() {
+        cli_interface.registry.load_file(path)?;
+    }
     // The file name of the conversation record
-    cli_interface.record_file = cmd_line_opts.record_file;
+    cli_interface.record_file = cmd_line_opts.record_file.clone();
     // Keep  record of the conversations
     let mut options = OpenOptions::new();
     let mut conversation_record_file: File = options
-        .write(true)
         .append(true)
         .create(true)
         .open(cli_interface.record_file.as_str())
         .unwrap();
+    let mut record_file_name = cli_interface.record_file.clone();
     let mut read_line: Editor<MyHelper, FileHistory> = cli_interface.set_up_read_line()?;
     let mut prompt: String;
-    let mut api_interface = ApiInterface::new(api_key.to_string(), tokens, temperature);
-    if let Some(sp) = cmd_line_opts.system_prompt {
-        api_interface.context.purpose = sp;
+    let mut api_interface = make_api_interface(&cmd_line_opts, None, None)?;
+    if let Some(name) = cmd_line_opts.session.as_deref() {
+        println!("{}", cli_interface.use_session(name, &mut api_interface)?);
+    }
+
+    // Ctrl-C cancels the requests for the line being processed.
+    // Otherwise it quits.  While a line is being read rustyline
+    // handles it
+    let in_flight: Arc<Mutex<Option<CancellationToken>>> = Arc::new(Mutex::new(None));
+    {
+        let in_flight = in_flight.clone();
+        ctrlc::set_handler(move || match in_flight.lock().unwrap().as_ref() {
+            Some(token) => token.cancel(),
+            None => std::process::exit(130),
+        })?;
     }
     loop {
         // Read the input text
-        let p = format!("{}/{mode}:> ", cli_interface.model);
-        read_line.helper_mut().expect("No helper").colored_prompt = format!("\x1b[1;32m{p}\x1b[0m");
+        let p = format!("{}/{}:> ", cli_interface.model, cli_interface.model_mode);
+        let helper = read_line.helper_mut().expect("No helper");
+        helper.colored_prompt = format!("\x1b[1;32m{p}\x1b[0m");
+        cli_interface.update_completions(helper);
         let readline = read_line.readline(&p);
         let input = match readline {
             Ok(line) => line,
@@ -872,9 +2360,25 @@ fn main() -> Result<(), Box<dyn Error>> {
         };
         read_line.add_history_entry(input.as_str())?;
 
+        // A cancelled token stays cancelled, so a new one for each line
+        let token = CancellationToken::new();
+        api_interface.cancel_with(Some(token.clone()));
+        *in_flight.lock().unwrap() = Some(token);
+
         // Expand and varoables i the prompt
 
         prompt = cli_interface.expand_variables(input.clone())?;
+
+        // Render a template to use as the prompt, or send the last
+        // prompt again
+        let mut expand_error: Option<String> = None;
+        match cli_interface
+            .expand_template(prompt.clone())
+            .and_then(|p| cli_interface.expand_resend(p, &mut api_interface, &mut read_line))
+        {
+            Ok(p) => prompt = p,
+            Err(err) => expand_error = Some(format!("{err}")),
+        };
         _ = conversation_record_file
             .write(
                 format!(
@@ -893,14 +2397,28 @@ fn main() -> Result<(), Box<dyn Error>> {
         // * It can be the completion message (error or success) for
         //   some change to the state of this programme
         let response_text: String;
+
+        // If the reply has already been displayed as it streamed in,
+        // this is what is left to display
+        let mut display_text: Option<String> = None;
+
+        // The reply formatted for the terminal
+        let mut rendered: Option<String> = None;
+
+        // Shell commands in the reply, to offer to run
+        let mut proposed: Vec<String> = vec![];
         let prompt = prompt.as_str().trim();
-        if prompt.is_empty() {
+        if let Some(err) = expand_error {
+            response_text = err;
+        } else if prompt.is_empty() {
             response_text = "No prompt\n".to_string();
         } else if prompt.starts_with('!') {
             let cprompt = prompt;
             response_text = cli_interface.process_meta(cprompt, &mut api_interface)?;
         } else {
-            // Send the prompt to the LLM
+            // Send the prompt to the LLM.  What it costs is charged
+            // to the session in use
+            api_interface.session = cli_interface.session.clone();
             let start_time = Local::now();
             let response = match cli_interface.model_mode {
                 ModelMode::AudioTranscription => {
@@ -919,7 +2437,36 @@ fn main() -> Result<(), Box<dyn Error>> {
                         Err(err) => format!("{err}"),
                     }
                 }
-                ModelMode::Chat => match api_interface.chat(prompt, cli_interface.model.as_str()) {
+                ModelMode::Chat if !cli_interface.compare_models.is_empty() => {
+		    // Compare the replies.  The context is unchanged
+		    // until one is adopted
+		    match api_interface.compare(prompt, &cli_interface.compare_models) {
+			Ok(comparisons) => {
+			    let cost: f64 = comparisons.iter().map(|c| c.cost).sum();
+			    let spent = CliInterface::spent(&api_interface);
+			    cli_interface.cost = cost;
+			    let mut result = format!(
+				"{:.2}/{:.2}/{:.2}:{}",
+				cost, api_interface.context.cost, spent, api_interface.context.len()
+			    );
+			    for (i, comparison) in comparisons.iter().enumerate() {
+				result += format!("\n[{}] {comparison}\n", i + 1).as_str();
+			    }
+			    cli_interface.comparison = Some((prompt.to_string(), comparisons));
+			    result
+			}
+			Err(err) => format!("{err}"),
+		    }
+		}
+                ModelMode::Chat => match if cli_interface.stream {
+		    let mut on_text = |text: &str| {
+			print!("{text}");
+			_ = std::io::stdout().flush();
+		    };
+		    api_interface.chat_stream(prompt, cli_interface.model.as_str(), &mut on_text)
+		} else {
+		    api_interface.chat(prompt, cli_interface.model.as_str())
+		} {
                     Ok(apt_result) => {
                         // Get ready
                         cli_interface.cost = apt_result
@@ -928,27 +2475,33 @@ fn main() -> Result<(), Box<dyn Error>> {
                             .unwrap()
                             .parse::<f64>()
                             .unwrap();
-			fn update_spent(cost: f64) -> impl FnMut(SharedState) -> SharedState {
-			    move |mut ss| {
-				ss.spent += cost;
-				ss
-			    }
-			}
+			let spent = CliInterface::spent(&api_interface);
 
-			// Call read_write_atomic with the closure
-			let ss: SharedState = match SharedState::read_write_atomic(update_spent(cli_interface.cost)){
-			    Ok(ss) => ss,
-			    Err(err) => panic!("{err}: Failed to update costs"),
+			// Say so if a fallback model answered
+			let answered = match apt_result.headers.get(MODEL_HEADER) {
+			    Some(model) if *model != cli_interface.model => format!(" Answered by: {model}"),
+			    _ => String::new(),
 			};
-
 			let this_cost = cli_interface.cost;
-			let total_cost = ss.spent;
+			let total_cost = spent;
 			let conversation_cost = api_interface.context.cost;
-                        format!(
-                            "{:.2}/{:.2}/{:.2}:{}{}\n{}",
+			let summary = format!(
+                            "{:.2}/{:.2}/{:.2}:{}{answered}{}",
                             this_cost, conversation_cost, total_cost, api_interface.context.len(), cli_interface.after_request(apt_result.headers)?,
-                            apt_result.body,
-                        )
+			);
+			if cli_interface.shell_mode {
+			    proposed = shell::proposed_commands(apt_result.body.as_str());
+			}
+			if cli_interface.stream {
+			    display_text = Some(summary.clone());
+			} else if cli_interface.render {
+			    // The record file gets the reply as it is
+			    rendered = Some(format!(
+				"{summary}\n{}",
+				markdown::render(apt_result.body.as_str(), markdown::terminal_width())
+			    ));
+			}
+                        format!("{summary}\n{}", apt_result.body)
                     }
                     Err(err) => format!("{err}"),
                 },
@@ -968,7 +2521,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                         cli_interface.focus_image_url = Some(r.body);
                         // Open image
                         let url: String = cli_interface.focus_image_url.as_ref().unwrap().clone();
-                        match cli_interface.process_image_url(&url) {
+                        match cli_interface.process_image_url(&url, api_interface.transport().as_ref()) {
                             Ok(_) => format!("Opened: {url}"),
                             Err(err) => format!("{err}: Failed to open: {url}"),
                         }
@@ -983,7 +2536,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                     ) {
                         Ok(r) => {
                             // Open image
-                            match cli_interface.process_image_url(r.body.as_str()) {
+                            match cli_interface.process_image_url(r.body.as_str(), api_interface.transport().as_ref()) {
                                 Ok(_) => format!("Opened: {}", r.body),
                                 Err(err) => format!("{err}: Failed to open: {}", r.body),
                             }
@@ -995,6 +2548,12 @@ fn main() -> Result<(), Box<dyn Error>> {
             let end_time = Local::now();
             let duration = end_time.signed_duration_since(start_time);
             response_text = format!("{} seconds\n{response}", duration.num_seconds());
+	    if let Some(summary) = display_text.as_mut() {
+		*summary = format!("\n{} seconds\n{summary}", duration.num_seconds());
+	    }
+	    if let Some(rendered) = rendered {
+		display_text = Some(format!("{} seconds\n{rendered}", duration.num_seconds()));
+	    }
         }
 
         // Put state dependant logic here to display useful information
@@ -1014,7 +2573,30 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .as_bytes(),
             )
             .unwrap();
-        println! {"{response_text}"};
+        println! {"{}", display_text.unwrap_or(response_text)};
+        *in_flight.lock().unwrap() = None;
+        cli_interface.finish_resend(&mut api_interface);
+
+        // Offer to run the commands the model proposed
+        for command in proposed {
+            match cli_interface.run_command("sh", command.as_str()) {
+                Ok(s) => println!("{s}"),
+                Err(err) => println!("{err}: Cannot run {command}"),
+            }
+        }
+
+        if let Err(err) = cli_interface.save_session(&api_interface) {
+            eprintln!("{err}: Cannot save the session");
+        }
+
+        // A profile can change the record file
+        if cli_interface.record_file != record_file_name {
+            record_file_name = cli_interface.record_file.clone();
+            conversation_record_file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(record_file_name.as_str())?;
+        }
     }
 
     read_line
@@ -1023,3 +2605,144 @@ fn main() -> Result<(), Box<dyn Error>> {
     read_line.clear_history().unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn api_error(error_type: ApiErrorType) -> Box<dyn Error> {
+        Box::new(ApiError::new(error_type, HashMap::new()))
+    }
+
+    #[test]
+    fn exit_statuses() {
+        let status =
+            |code: u16| ApiErrorType::Status(StatusCode::from_u16(code).unwrap(), String::new());
+        assert_eq!(
+            exit_status(api_error(ApiErrorType::Error("No".to_string())).as_ref()),
+            EXIT_FAILED
+        );
+        assert_eq!(exit_status(api_error(status(400)).as_ref()), EXIT_FAILED);
+        assert_eq!(
+            exit_status(
+                api_error(ApiErrorType::BudgetExceeded {
+                    period: Period::Day,
+                    limit: 1.0,
+                    spent: 1.0,
+                    estimate: 0.5,
+                })
+                .as_ref()
+            ),
+            EXIT_OVER_BUDGET
+        );
+        assert_eq!(
+            exit_status(api_error(status(401)).as_ref()),
+            EXIT_UNAUTHORISED
+        );
+        assert_eq!(
+            exit_status(api_error(status(403)).as_ref()),
+            EXIT_UNAUTHORISED
+        );
+        assert_eq!(exit_status(api_error(status(429)).as_ref()), EXIT_TRY_AGAIN);
+        assert_eq!(exit_status(api_error(status(529)).as_ref()), EXIT_TRY_AGAIN);
+        assert_eq!(
+            exit_status(api_error(ApiErrorType::Cancelled).as_ref()),
+            EXIT_INTERRUPTED
+        );
+        let other: Box<dyn Error> = "Not an API error".into();
+        assert_eq!(exit_status(other.as_ref()), EXIT_FAILED);
+
+        // Nothing is sent
+        let cmd_line_opts = Arguments::parse_from(["cli"]);
+        assert_eq!(
+            ask(&cmd_line_opts, &[" ".to_string()], &[], None, false),
+            EXIT_NO_PROMPT
+        );
+    }
+
+    #[test]
+    fn ask_prints_only_the_reply() {
+        let headers = HashMap::from([
+            ("Cost".to_string(), "0.5".to_string()),
+            (MODEL_HEADER.to_string(), "gpt-4".to_string()),
+            ("Tokens prompt".to_string(), "10".to_string()),
+            ("Tokens completion".to_string(), "5".to_string()),
+            ("Tokens total".to_string(), "15".to_string()),
+        ]);
+        let result = ApiResult::new("Hello\nthere".to_string(), headers);
+        assert_eq!(ask_output(&result, false), "Hello\nthere");
+        let json: serde_json::Value =
+            serde_json::from_str(ask_output(&result, true).as_str()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "reply": "Hello\nthere",
+                "model": "gpt-4",
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+                "cost": 0.5,
+            })
+        );
+    }
+
+    #[test]
+    fn flags_override_the_profile() {
+        let profile = Profile {
+            model: Some("ollama/llama3".to_string()),
+            temperature: Some(0.2),
+            max_tokens: Some(500),
+            system_prompt: Some("Be brief".to_string()),
+            provider: Some(config::Provider {
+                ollama_url: Some("http://gpu-box:11434".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let args = ["cli", "-m", "gpt-4", "--ollama-url", "http://localhost:1"];
+        let matches = Arguments::command().get_matches_from(args);
+        let mut cmd_line_opts = Arguments::from_arg_matches(&matches).unwrap();
+        apply_profile(&mut cmd_line_opts, &matches, &profile);
+
+        // Given on the command line
+        assert_eq!(cmd_line_opts.model, "gpt-4");
+        assert_eq!(
+            cmd_line_opts.ollama_url.as_deref(),
+            Some("http://localhost:1")
+        );
+
+        // From the profile, over the defaults
+        assert_eq!(cmd_line_opts.temperature, 0.2);
+        assert_eq!(cmd_line_opts.max_tokens, 500);
+        assert_eq!(cmd_line_opts.system_prompt.as_deref(), Some("Be brief"));
+
+        // Neither
+        assert_eq!(cmd_line_opts.mode, DEFAULT_MODE);
+        assert_eq!(cmd_line_opts.record_file, DEFAULT_RECORD_FILE);
+    }
+
+    #[test]
+    fn stdin_takes_the_ask_flags() {
+        let args = [
+            "cli",
+            "--stdin",
+            "--json",
+            "-f",
+            "a.rs",
+            "--context",
+            "c.json",
+        ];
+        let cmd_line_opts = Arguments::try_parse_from(args).unwrap();
+        assert!(cmd_line_opts.json);
+        assert_eq!(cmd_line_opts.file, [PathBuf::from("a.rs")]);
+        assert_eq!(cmd_line_opts.context, Some(PathBuf::from("c.json")));
+
+        // Only with `--stdin`.  `ask` has its own
+        assert!(Arguments::try_parse_from(["cli", "--json"]).is_err());
+        let cmd_line_opts = Arguments::try_parse_from(["cli", "ask", "--json", "Hi"]).unwrap();
+        assert!(!cmd_line_opts.json);
+        assert!(matches!(
+            cmd_line_opts.command,
+            Some(Command::Ask { json: true, .. })
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+//! The configuration file, "config.toml" in the configuration
+//! directory.  It has named profiles, each a set of defaults:
+//!
+//! ```toml
+//! profile = "work"
+//!
+//! [profiles.work]
+//! model = "gpt-4"
+//! temperature = 0.2
+//! system_prompt = "You are a careful software engineer"
+//!
+//! [profiles.local]
+//! model = "ollama/llama3"
+//! record_file = "local.txt"
+//! provider = { ollama_url = "http://gpu-box:11434" }
+//! ```
+//!
+//! `profile` names the profile used when there is no `--profile`.
+//! Flags on the command line override the profile
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Where requests go.  Keys are not kept in the file, the names of
+/// the environment variables that hold them are
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Provider {
+    /// The environment variable with the OpenAI key
+    pub api_key_env: Option<String>,
+
+    /// The environment variable with the Anthropic key
+    pub anthropic_api_key_env: Option<String>,
+
+    pub ollama_url: Option<String>,
+
+    /// A JSON file that maps models to Azure OpenAI deployments
+    pub azure_config: Option<String>,
+}
+
+/// Defaults for the settings.  Those not set are left alone
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub model: Option<String>,
+    pub mode: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub system_prompt: Option<String>,
+    pub record_file: Option<String>,
+    pub provider: Option<Provider>,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut settings: Vec<String> = vec![];
+        if let Some(model) = self.model.as_ref() {
+            settings.push(format!("model: {model}"));
+        }
+        if let Some(mode) = self.mode.as_ref() {
+            settings.push(format!("mode: {mode}"));
+        }
+        if let Some(temperature) = self.temperature {
+            settings.push(format!("temperature: {temperature}"));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            settings.push(format!("max tokens: {max_tokens}"));
+        }
+        if let Some(system_prompt) = self.system_prompt.as_ref() {
+            settings.push(format!("system prompt: {system_prompt}"));
+        }
+        if let Some(record_file) = self.record_file.as_ref() {
+            settings.push(format!("record file: {record_file}"));
+        }
+        if let Some(provider) = self.provider.as_ref() {
+            if let Some(url) = provider.ollama_url.as_ref() {
+                settings.push(format!("ollama: {url}"));
+            }
+            if let Some(azure) = provider.azure_config.as_ref() {
+                settings.push(format!("azure: {azure}"));
+            }
+        }
+        write!(f, "{}", settings.join(" "))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The profile to use when none is asked for
+    pub profile: Option<String>,
+
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    /// "config.toml" in the configuration directory
+    pub fn default_path() -> PathBuf {
+        ProjectDirs::from("worik", "org", "llm-rs")
+            .unwrap()
+            .config_dir()
+            .join("config.toml")
+    }
+
+    /// Read the configuration at `path`.  If there is no file there
+    /// is no configuration
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        let config = toml::from_str(contents.as_str())
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+        Ok(config)
+    }
+
+    /// The profile called `name`
+    pub fn get(&self, name: &str) -> Result<&Profile, Box<dyn Error>> {
+        self.profiles.get(name).ok_or_else(|| {
+            format!(
+                "No profile: {name}.  Profiles are: {}",
+                self.profiles
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        assert!(Config::from_file(path.as_path())
+            .unwrap()
+            .profiles
+            .is_empty());
+
+        fs::write(
+            path.as_path(),
+            r#"profile = "work"
+
+[profiles.work]
+model = "gpt-4"
+temperature = 0.2
+
+[profiles.local]
+model = "ollama/llama3"
+provider = { ollama_url = "http://gpu-box:11434" }
+"#,
+        )
+        .unwrap();
+        let config = Config::from_file(path.as_path()).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("work"));
+        let work = config.get("work").unwrap();
+        assert_eq!(work.model.as_deref(), Some("gpt-4"));
+        assert_eq!(work.temperature, Some(0.2));
+        assert!(work.system_prompt.is_none());
+        assert_eq!(
+            config.get("local").unwrap().to_string(),
+            "model: ollama/llama3 ollama: http://gpu-box:11434"
+        );
+        let err = config.get("home").unwrap_err().to_string();
+        assert_eq!(err, "No profile: home.  Profiles are: local work");
+
+        // Unknown settings are mistakes
+        fs::write(path.as_path(), "[profiles.work]\nmodle = \"gpt-4\"\n").unwrap();
+        assert!(Config::from_file(path.as_path()).is_err());
+    }
+}
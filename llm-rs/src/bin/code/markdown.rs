@@ -0,0 +1,491 @@
+//! Render Markdown replies for the terminal.  Text is styled with
+//! ANSI escapes and wrapped to the width of the terminal.  Fenced
+//! code is highlighted by its language, and not wrapped, and keeps
+//! its fences so it can be copied.  Tables are drawn with box
+//! characters
+use pulldown_cmark::Alignment;
+use pulldown_cmark::CodeBlockKind;
+use pulldown_cmark::Event;
+use pulldown_cmark::HeadingLevel;
+use pulldown_cmark::Options;
+use pulldown_cmark::Parser;
+use pulldown_cmark::Tag;
+use pulldown_cmark::TagEnd;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::Theme;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+use syntect::util::LinesWithEndings;
+use textwrap::core::display_width;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const ITALIC: &str = "\x1b[3m";
+const UNDERLINE: &str = "\x1b[4m";
+const STRIKE: &str = "\x1b[9m";
+const HEADING: &str = "\x1b[1;35m";
+const CODE: &str = "\x1b[36m";
+const LINK: &str = "\x1b[4;34m";
+
+/// The width of the terminal.  80 if it is not known
+pub fn terminal_width() -> usize {
+    match terminal_size::terminal_size() {
+        Some((terminal_size::Width(width), _)) => width as usize,
+        None => 80,
+    }
+}
+
+/// Loading the syntaxes takes a while, so only do it once
+fn syntaxes() -> &'static (SyntaxSet, Theme) {
+    static SYNTAXES: OnceLock<(SyntaxSet, Theme)> = OnceLock::new();
+    SYNTAXES.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes
+            .themes
+            .remove("base16-ocean.dark")
+            .expect("Default theme");
+        (SyntaxSet::load_defaults_newlines(), theme)
+    })
+}
+
+/// `code` highlighted as `language`.  Unknown languages are plain
+fn highlight(code: &str, language: &str) -> Vec<String> {
+    let (syntax_set, theme) = syntaxes();
+    let syntax = syntax_set
+        .find_syntax_by_token(language)
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    LinesWithEndings::from(code)
+        .map(|line| match highlighter.highlight_line(line, syntax_set) {
+            Ok(ranges) => format!(
+                "{}{RESET}",
+                as_24_bit_terminal_escaped(&ranges, false).trim_end_matches('\n')
+            ),
+            Err(_) => line.trim_end_matches('\n').to_string(),
+        })
+        .collect()
+}
+
+/// A table being collected.  It is drawn when it is complete
+struct Table {
+    alignments: Vec<Alignment>,
+    rows: Vec<Vec<String>>,
+    row: Vec<String>,
+    cell: String,
+}
+
+struct Renderer {
+    width: usize,
+    out: Vec<String>,
+
+    /// The text of the block being rendered
+    text: String,
+
+    /// Styles in effect, to restore after one ends
+    styles: Vec<&'static str>,
+
+    /// For each list the next number, or `None` for bullets
+    lists: Vec<Option<u64>>,
+
+    /// How far the text of each list item is indented
+    item_indents: Vec<usize>,
+
+    /// The bullet, or number, for the next line of the item
+    marker: Option<String>,
+
+    quotes: usize,
+    code: Option<(String, String)>,
+    table: Option<Table>,
+    links: Vec<String>,
+}
+
+impl Renderer {
+    fn new(width: usize) -> Self {
+        Self {
+            width: width.max(20),
+            out: vec![],
+            text: String::new(),
+            styles: vec![],
+            lists: vec![],
+            item_indents: vec![],
+            marker: None,
+            quotes: 0,
+            code: None,
+            table: None,
+            links: vec![],
+        }
+    }
+
+    fn push_style(&mut self, style: &'static str) {
+        self.styles.push(style);
+        self.push_text(style);
+    }
+
+    fn pop_style(&mut self) {
+        self.styles.pop();
+        let restore = format!("{RESET}{}", self.styles.concat());
+        self.push_text(restore.as_str());
+    }
+
+    /// Add to the table cell, or the block, being rendered
+    fn push_text(&mut self, text: &str) {
+        match self.table.as_mut() {
+            Some(table) => table.cell.push_str(text),
+            None => self.text.push_str(text),
+        }
+    }
+
+    /// Quotes and enclosing list items
+    fn indent(&self) -> String {
+        format!(
+            "{}{}",
+            "│ ".repeat(self.quotes),
+            " ".repeat(self.item_indents.iter().sum())
+        )
+    }
+
+    /// The indent of the first line, with the list marker if there
+    /// is one
+    fn first_indent(&mut self) -> String {
+        match self.marker.take() {
+            Some(marker) => {
+                let outer: usize = self.item_indents.iter().rev().skip(1).sum();
+                format!("{}{}{marker}", "│ ".repeat(self.quotes), " ".repeat(outer))
+            }
+            None => self.indent(),
+        }
+    }
+
+    /// Wrap the text of the block and output it
+    fn flush(&mut self) {
+        // Styles still in effect go on to the next block
+        let text = std::mem::replace(&mut self.text, self.styles.concat());
+        if display_width(text.trim()) == 0 {
+            return;
+        }
+        let first = self.first_indent();
+        let rest = self.indent();
+        let options = textwrap::Options::new(self.width)
+            .initial_indent(first.as_str())
+            .subsequent_indent(rest.as_str());
+        // Styles carry on over the ends of lines
+        let lines = textwrap::wrap(text.trim(), options);
+        let last = lines.len() - 1;
+        for (i, line) in lines.into_iter().enumerate() {
+            if i == last {
+                self.out.push(format!("{line}{RESET}"));
+            } else {
+                self.out.push(line.to_string());
+            }
+        }
+    }
+
+    /// A blank line between blocks.  Not in tight lists
+    fn gap(&mut self) {
+        if self.item_indents.is_empty() && self.out.last().is_some_and(|l| !l.is_empty()) {
+            self.out.push(String::new());
+        }
+    }
+
+    fn code_block(&mut self, language: &str, code: &str) {
+        if self.marker.is_some() {
+            let first = self.first_indent();
+            self.out.push(first);
+        }
+        let indent = self.indent();
+        self.out.push(format!("{indent}{DIM}```{language}{RESET}"));
+        for line in highlight(code, language) {
+            self.out.push(format!("{indent}{line}"));
+        }
+        self.out.push(format!("{indent}{DIM}```{RESET}"));
+    }
+
+    fn draw_table(&mut self, table: Table) {
+        let columns = table.rows.iter().map(|r| r.len()).max().unwrap_or(0);
+        let mut widths = vec![0; columns];
+        for row in table.rows.iter() {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(display_width(cell));
+            }
+        }
+        let indent = self.indent();
+        for (r, row) in table.rows.iter().enumerate() {
+            let cells: Vec<String> = (0..columns)
+                .map(|i| {
+                    let cell = row.get(i).map(|c| c.as_str()).unwrap_or("");
+                    let padding = widths[i] - display_width(cell);
+                    let (left, right) = match table.alignments.get(i) {
+                        Some(Alignment::Right) => (padding, 0),
+                        Some(Alignment::Center) => (padding / 2, padding - padding / 2),
+                        _ => (0, padding),
+                    };
+                    let style = if r == 0 { BOLD } else { "" };
+                    format!(
+                        "{}{style}{cell}{RESET}{}",
+                        " ".repeat(left),
+                        " ".repeat(right)
+                    )
+                })
+                .collect();
+            self.out.push(format!("{indent}{}", cells.join(" │ ")));
+            if r == 0 {
+                let rule: Vec<String> = widths.iter().map(|w| "─".repeat(*w)).collect();
+                self.out.push(format!("{indent}{}", rule.join("─┼─")));
+            }
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        if let Some((_, code)) = self.code.as_mut() {
+            match event {
+                Event::Text(text) => code.push_str(&text),
+                Event::End(TagEnd::CodeBlock) => {
+                    let (language, code) = self.code.take().unwrap();
+                    self.code_block(language.as_str(), code.as_str());
+                    self.gap();
+                }
+                _ => (),
+            }
+            return;
+        }
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.push_text(&text),
+            Event::Code(code) => {
+                self.push_style(CODE);
+                self.push_text(&code);
+                self.pop_style();
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) => self.push_text(&math),
+            Event::Html(html) | Event::InlineHtml(html) => self.push_text(&html),
+            Event::FootnoteReference(label) => self.push_text(&format!("[^{label}]")),
+            Event::SoftBreak => self.push_text(" "),
+            Event::HardBreak => self.push_text("\n"),
+            Event::Rule => {
+                self.flush();
+                let rule = "─".repeat(self.width.saturating_sub(display_width(&self.indent())));
+                self.out
+                    .push(format!("{}{DIM}{rule}{RESET}", self.indent()));
+                self.gap();
+            }
+            Event::TaskListMarker(done) => self.push_text(if done { "[x] " } else { "[ ] " }),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => (),
+            Tag::Heading { level, .. } => {
+                self.flush();
+                self.push_style(HEADING);
+                if level == HeadingLevel::H1 {
+                    self.push_style(UNDERLINE);
+                }
+            }
+            Tag::BlockQuote(_) => {
+                self.flush();
+                self.quotes += 1;
+                self.push_style(DIM);
+            }
+            Tag::CodeBlock(kind) => {
+                self.flush();
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.code = Some((language, String::new()));
+            }
+            Tag::List(start) => {
+                self.flush();
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.flush();
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.item_indents.push(display_width(&marker));
+                self.marker = Some(marker);
+            }
+            Tag::Table(alignments) => {
+                self.flush();
+                self.table = Some(Table {
+                    alignments,
+                    rows: vec![],
+                    row: vec![],
+                    cell: String::new(),
+                });
+            }
+            Tag::Emphasis => self.push_style(ITALIC),
+            Tag::Strong => self.push_style(BOLD),
+            Tag::Strikethrough => self.push_style(STRIKE),
+            Tag::Link { dest_url, .. } => {
+                self.links.push(dest_url.to_string());
+                self.push_style(LINK);
+            }
+            Tag::Image { dest_url, .. } => {
+                self.links.push(dest_url.to_string());
+                self.push_text("[image: ");
+            }
+            _ => (),
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph => {
+                self.flush();
+                self.gap();
+            }
+            TagEnd::Heading(level) => {
+                if level == HeadingLevel::H1 {
+                    self.pop_style();
+                }
+                self.pop_style();
+                self.flush();
+                self.gap();
+            }
+            TagEnd::BlockQuote(_) => {
+                self.flush();
+                self.pop_style();
+                self.text.clear();
+                self.quotes -= 1;
+                self.gap();
+            }
+            TagEnd::List(_) => {
+                self.flush();
+                self.lists.pop();
+                self.gap();
+            }
+            TagEnd::Item => {
+                self.flush();
+                self.item_indents.pop();
+                self.marker = None;
+            }
+            TagEnd::TableHead | TagEnd::TableRow => {
+                if let Some(table) = self.table.as_mut() {
+                    let row = std::mem::take(&mut table.row);
+                    table.rows.push(row);
+                }
+            }
+            TagEnd::TableCell => {
+                if let Some(table) = self.table.as_mut() {
+                    let cell = std::mem::take(&mut table.cell);
+                    table.row.push(cell.trim().to_string());
+                }
+            }
+            TagEnd::Table => {
+                if let Some(table) = self.table.take() {
+                    self.draw_table(table);
+                }
+                self.gap();
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => self.pop_style(),
+            TagEnd::Link => {
+                self.pop_style();
+                if let Some(url) = self.links.pop() {
+                    self.push_text(&format!(" {DIM}({url}){RESET}{}", self.styles.concat()));
+                }
+            }
+            TagEnd::Image => {
+                if let Some(url) = self.links.pop() {
+                    self.push_text(&format!("] {DIM}({url}){RESET}{}", self.styles.concat()));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// `markdown` styled for a terminal `width` columns wide
+pub fn render(markdown: &str, width: usize) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let mut renderer = Renderer::new(width);
+    for event in Parser::new_ext(markdown, options) {
+        renderer.event(event);
+    }
+    renderer.flush();
+    while renderer.out.last().is_some_and(|l| l.is_empty()) {
+        renderer.out.pop();
+    }
+    renderer.out.join("\n")
+}
+
+/// `markdown` as it is displayed.  Styled if `render`, otherwise as
+/// it is, as escapes would be garbage in a file or a pipe
+pub fn display(markdown: &str, render: bool) -> String {
+    if render {
+        self::render(markdown, terminal_width())
+    } else {
+        markdown.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `text` without its ANSI escapes
+    fn plain(text: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                // To the end of the escape, "m"
+                for c in chars.by_ref() {
+                    if c == 'm' {
+                        break;
+                    }
+                }
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn headings_code_and_lists() {
+        let markdown =
+            "# Title\n\nSome *text* that is long enough to be wrapped at twenty columns\n\n\
+                        - one\n- two\n  1. nested\n\n\
+                        ```rust\nfn main() {}\n```\n";
+        let rendered = render(markdown, 20);
+        assert!(rendered.contains(format!("{HEADING}{UNDERLINE}Title").as_str()));
+        assert!(rendered.contains(format!("{ITALIC}text").as_str()));
+        assert_eq!(
+            plain(rendered.as_str()),
+            "Title\n\n\
+             Some text that is\n\
+             long enough to be\n\
+             wrapped at twenty\n\
+             columns\n\n\
+             • one\n\
+             • two\n\
+             \x20 1. nested\n\n\
+             ```rust\n\
+             fn main() {}\n\
+             ```"
+        );
+    }
+
+    #[test]
+    fn plain_when_not_rendered() {
+        let markdown = "# Title\n\n```rust\nfn main() {}\n```\n";
+        assert_eq!(display(markdown, false), markdown);
+        assert_ne!(display(markdown, true), markdown);
+    }
+}
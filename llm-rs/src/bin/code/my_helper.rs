@@ -1,18 +1,33 @@
+use rustyline::completion::Completer;
 use rustyline::completion::FilenameCompleter;
-use rustyline::highlight::{Highlighter, MatchingBracketHighlighter};
+use rustyline::completion::Pair;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
 use rustyline::hint::HistoryHinter;
 //use rustyline::history::FileHistory;
 use rustyline::validate::MatchingBracketValidator;
 use rustyline::Validator;
 //use rustyline::{Cmd, CompletionType, Config, EditMode, Editor, Event, EventHandler, KeyEvent};
-use rustyline::{Completer, Helper, Hinter};
+use rustyline::Context;
+use rustyline::{Helper, Hinter};
 use std::borrow::Cow::{self, Borrowed, Owned};
+use std::collections::HashMap;
 
 /// `MyHelper` is copied from the examples in `RustyLine` crate
-#[derive(Helper, Completer, Hinter, Validator)]
+#[derive(Helper, Hinter, Validator)]
 pub struct MyHelper {
-    #[rustyline(Completer)]
+    /// For anything the others do not complete
     pub completer: FilenameCompleter,
+
+    /// The meta commands, completed after "!"
+    pub commands: Vec<String>,
+
+    /// What the first argument of a meta command can be: models
+    /// after "ms", modes after "m"...
+    pub arguments: HashMap<String, Vec<String>>,
+
+    /// Names of `local_data`, completed after "{"
+    pub variables: Vec<String>,
+
     pub highlighter: MatchingBracketHighlighter,
     #[rustyline(Validator)]
     pub validator: MatchingBracketValidator,
@@ -43,7 +58,120 @@ impl Highlighter for MyHelper {
         self.highlighter.highlight(line, pos)
     }
 
-    fn highlight_char(&self, line: &str, pos: usize) -> bool {
-        self.highlighter.highlight_char(line, pos)
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.highlighter.highlight_char(line, pos, kind)
+    }
+}
+
+/// The candidates that start with `partial`, replacing `partial`
+fn matching<'a>(
+    candidates: impl Iterator<Item = &'a String>,
+    partial: &str,
+    suffix: &str,
+) -> Vec<Pair> {
+    candidates
+        .filter(|c| c.starts_with(partial))
+        .map(|c| Pair {
+            display: c.clone(),
+            replacement: format!("{c}{suffix}"),
+        })
+        .collect()
+}
+
+impl Completer for MyHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+
+        // A variable: "{name}"
+        if let Some(brace) = before.rfind('{') {
+            let partial = &before[brace + 1..];
+            if partial.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                let variables: Vec<String> =
+                    self.variables.iter().map(|v| format!("{{{v}")).collect();
+                let candidates = matching(variables.iter(), &before[brace..], "}");
+                if !candidates.is_empty() {
+                    return Ok((brace, candidates));
+                }
+            }
+        }
+
+        // A meta command, or its first argument
+        if let Some(meta) = before.strip_prefix('!') {
+            let words: Vec<&str> = meta.split_whitespace().collect();
+            let partial_start = before
+                .rfind(char::is_whitespace)
+                .map(|i| i + 1)
+                .unwrap_or(1);
+            let partial = &before[partial_start..];
+            let typing_new_word = partial.is_empty();
+            match (words.len(), typing_new_word) {
+                (0, _) | (1, false) => {
+                    return Ok((partial_start, matching(self.commands.iter(), partial, " ")));
+                }
+                (1, true) | (2, false) => {
+                    if let Some(arguments) = self.arguments.get(words[0]) {
+                        return Ok((partial_start, matching(arguments.iter(), partial, " ")));
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.completer.complete(line, pos, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::DefaultHistory;
+
+    fn helper() -> MyHelper {
+        MyHelper {
+            completer: FilenameCompleter::new(),
+            commands: ["ms", "m", "md", "sx"].map(String::from).to_vec(),
+            arguments: HashMap::from([(
+                "ms".to_string(),
+                ["gpt-4", "gpt-3.5-turbo", "claude-3"]
+                    .map(String::from)
+                    .to_vec(),
+            )]),
+            variables: ["name", "notes"].map(String::from).to_vec(),
+            highlighter: MatchingBracketHighlighter::new(),
+            validator: MatchingBracketValidator::new(),
+            hinter: HistoryHinter::new(),
+            colored_prompt: String::new(),
+        }
+    }
+
+    /// (where the completion starts, the replacements)
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let (start, pairs) = helper()
+            .complete(line, line.len(), &Context::new(&history))
+            .unwrap();
+        (start, pairs.into_iter().map(|p| p.replacement).collect())
+    }
+
+    #[test]
+    fn completes_commands_arguments_and_variables() {
+        assert_eq!(
+            complete("! m"),
+            (2, vec!["ms ".into(), "m ".into(), "md ".into()])
+        );
+        assert_eq!(complete("!s"), (1, vec!["sx ".into()]));
+        assert_eq!(
+            complete("! ms gpt"),
+            (5, vec!["gpt-4 ".into(), "gpt-3.5-turbo ".into()])
+        );
+        assert_eq!(complete("! ms ").1.len(), 3);
+        assert_eq!(complete("Say hello to {na"), (13, vec!["{name}".into()]));
+        assert_eq!(complete("{n").1, vec!["{name}", "{notes}"]);
     }
 }
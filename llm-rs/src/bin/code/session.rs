@@ -0,0 +1,175 @@
+//! Named sessions.  A session is a conversation with the model and
+//! mode it uses.  The current session is saved after every turn, in
+//! "sessions" in the data directory, so it can be resumed, weeks
+//! later, with `--session <name>` or `! ss <name>`
+use chrono::DateTime;
+use chrono::Local;
+use directories::ProjectDirs;
+use llm_rs::context::Context;
+use llm_rs::conversation_tree::ConversationTree;
+use serde::Deserialize;
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub model: String,
+
+    /// As `ModelMode` displays it
+    pub mode: String,
+
+    /// The conversation.  With the system prompt and the cost so far
+    pub context: Context,
+
+    /// All the branches of the conversation.  `context` is the one in
+    /// use.  None in sessions saved before conversations branched
+    #[serde(default)]
+    pub tree: Option<ConversationTree>,
+
+    pub updated: DateTime<Local>,
+}
+
+/// The sessions, a JSON file each
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    /// "sessions" in the data directory
+    pub fn new() -> Self {
+        Self {
+            dir: ProjectDirs::from("worik", "org", "llm-rs")
+                .unwrap()
+                .data_dir()
+                .join("sessions"),
+        }
+    }
+
+    /// The file for `name`.  Names cannot be paths
+    fn path(&self, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("Bad session name: {name}").into());
+        }
+        Ok(self.dir.join(format!("{name}.json")))
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.path(name).map(|path| path.exists()).unwrap_or(false)
+    }
+
+    pub fn load(&self, name: &str) -> Result<Session, Box<dyn Error>> {
+        let path = self.path(name)?;
+        let contents = fs::read_to_string(path.as_path())
+            .map_err(|err| format!("{err}: Cannot read session {name}"))?;
+        Ok(serde_json::from_str(contents.as_str())?)
+    }
+
+    /// Written to a temporary file that replaces the old one, so a
+    /// session is never left half written
+    pub fn save(&self, name: &str, session: &Session) -> Result<(), Box<dyn Error>> {
+        let path = self.path(name)?;
+        fs::create_dir_all(self.dir.as_path())?;
+        let temporary = path.with_extension("json.tmp");
+        fs::write(temporary.as_path(), serde_json::to_string(session)?)?;
+        fs::rename(temporary, path)?;
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+        if self.exists(to) {
+            return Err(format!("There is already a session {to}").into());
+        }
+        fs::rename(self.path(from)?, self.path(to)?)
+            .map_err(|err| format!("{err}: Cannot rename session {from}"))?;
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(self.path(name)?)
+            .map_err(|err| format!("{err}: Cannot delete session {name}"))?;
+        Ok(())
+    }
+
+    /// The sessions, most recently used first
+    pub fn list(&self) -> Result<Vec<(String, Session)>, Box<dyn Error>> {
+        let mut sessions: Vec<(String, Session)> = vec![];
+        let entries = match fs::read_dir(self.dir.as_path()) {
+            Ok(entries) => entries,
+            // No sessions yet
+            Err(_) => return Ok(sessions),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                match self.load(name) {
+                    Ok(session) => sessions.push((name.to_string(), session)),
+                    Err(err) => log::warn!("{err}"),
+                }
+            }
+        }
+        sessions.sort_by_key(|(_, session)| std::cmp::Reverse(session.updated));
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(model: &str, updated: DateTime<Local>) -> Session {
+        let mut context = Context::new("Be brief");
+        context.push_turn("One".to_string(), "1".to_string(), 1.5);
+        Session {
+            model: model.to_string(),
+            mode: "chat".to_string(),
+            tree: Some(ConversationTree::new(&context)),
+            context,
+            updated,
+        }
+    }
+
+    #[test]
+    fn save_load_rename_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SessionStore {
+            dir: dir.path().join("sessions"),
+        };
+        assert!(store.list().unwrap().is_empty());
+        let now = Local::now();
+        store.save("work", &session("gpt-4", now)).unwrap();
+        store
+            .save(
+                "play",
+                &session("ollama/llama3", now - chrono::Duration::days(1)),
+            )
+            .unwrap();
+        assert!(store.exists("work"));
+        assert!(store.save("../work", &session("gpt-4", now)).is_err());
+
+        let work = store.load("work").unwrap();
+        assert_eq!(work.model, "gpt-4");
+        assert_eq!(work.context.purpose, "Be brief");
+        assert_eq!(work.context.cost, 1.5);
+        assert!(work.tree.is_some());
+
+        // Most recent first
+        let names: Vec<String> = store.list().unwrap().into_iter().map(|s| s.0).collect();
+        assert_eq!(names, vec!["work", "play"]);
+
+        assert!(store.rename("work", "play").is_err());
+        store.rename("work", "job").unwrap();
+        assert!(!store.exists("work"));
+        assert_eq!(store.load("job").unwrap().model, "gpt-4");
+
+        store.delete("job").unwrap();
+        assert!(store.load("job").is_err());
+        assert!(store.delete("job").is_err());
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+}
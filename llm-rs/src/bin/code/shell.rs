@@ -0,0 +1,81 @@
+//! Run local commands, for `! sh` and for commands the model
+//! proposes.  Nothing runs without the user agreeing first
+use llm_rs::code_block::code_blocks;
+use std::error::Error;
+use std::io::Write;
+use std::process::Command;
+
+/// Languages of code blocks that are shell commands
+const SHELLS: [&str; 5] = ["sh", "bash", "shell", "zsh", "console"];
+
+/// Ask `question`.  True if the answer is "y"
+pub fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Run `command` with the shell.  Returns the exit status, -1 if it
+/// was killed, and what it wrote to standard output then standard
+/// error
+pub fn run(command: &str) -> Result<(i32, String), Box<dyn Error>> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()?
+    } else {
+        Command::new("sh").args(["-c", command]).output()?
+    };
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text += String::from_utf8_lossy(&output.stderr).as_ref();
+    Ok((output.status.code().unwrap_or(-1), text))
+}
+
+/// The shell commands in code blocks in `reply`.  The "$ " of
+/// console sessions is removed, and their output left out
+pub fn proposed_commands(reply: &str) -> Vec<String> {
+    code_blocks(reply)
+        .into_iter()
+        .filter(|block| SHELLS.contains(&block.language.as_str()))
+        .map(|block| {
+            if block.language == "console" {
+                block
+                    .code
+                    .lines()
+                    .filter_map(|l| l.strip_prefix("$ "))
+                    .collect::<Vec<&str>>()
+                    .join("\n")
+            } else {
+                block.code.trim_end().to_string()
+            }
+        })
+        .filter(|command| !command.trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_in_a_reply() {
+        let reply = "List the files:\n\n\
+                     ```bash\nls -l\ncd /tmp\n```\n\n\
+                     Not this:\n\n```rust\nfn main() {}\n```\n\n\
+                     ```console\n$ cargo build\n   Compiling llm-rs\n$ cargo test\n```\n\n\
+                     ```sh\n\n```\n";
+        assert_eq!(
+            proposed_commands(reply),
+            vec!["ls -l\ncd /tmp", "cargo build\ncargo test"]
+        );
+        assert!(proposed_commands("No code here").is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn runs_a_command() {
+        let (status, output) = run("echo out && echo err >&2 && exit 3").unwrap();
+        assert_eq!(status, 3);
+        assert_eq!(output, "out\nerr\n");
+    }
+}
@@ -0,0 +1,241 @@
+//! Limits on spending, checked before a request is sent.
+//!
+//! There are budgets per request, per conversation (the `Context`),
+//! per day and per month, all in cents.  A chat is refused with
+//! `ApiErrorType::BudgetExceeded` if what it could cost would take
+//! spending past any of them.  What it could cost is the price of the
+//! prompt, at about four characters a token, and of `max_tokens` of
+//! reply, so it is an over estimate: replies are no longer than
+//! `max_tokens`.  Requests that are free are not refused.  Requests to
+//! models with no known price are, as what they cost cannot be known.
+//!
+//! Spending per day and per month is added up from the `Ledger`.
+//! With a file it adds up across runs, and across programmes sharing
+//! the file
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use chrono::DateTime;
+use chrono::Local;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// What a budget is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Request,
+    Conversation,
+    Day,
+    Month,
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str = match self {
+            Period::Request => "request",
+            Period::Conversation => "conversation",
+            Period::Day => "day",
+            Period::Month => "month",
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl FromStr for Period {
+    type Err = ApiError;
+    fn from_str(period: &str) -> Result<Self, Self::Err> {
+        match period {
+            "request" => Ok(Period::Request),
+            "conversation" => Ok(Period::Conversation),
+            "day" => Ok(Period::Day),
+            "month" => Ok(Period::Month),
+            _ => Err(ApiError::new(
+                ApiErrorType::Error(format!(
+                    "Unknown budget: {period}.  Use request, conversation, day or month"
+                )),
+                HashMap::new(),
+            )),
+        }
+    }
+}
+
+/// In cents.  `None` is no limit
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+    pub per_request: Option<f64>,
+    pub per_conversation: Option<f64>,
+    pub per_day: Option<f64>,
+    pub per_month: Option<f64>,
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = |limit: Option<f64>| match limit {
+            Some(limit) => format!("{limit:.2}"),
+            None => "none".to_string(),
+        };
+        write!(
+            f,
+            "Per request: {} Per conversation: {} Per day: {} Per month: {}",
+            limit(self.per_request),
+            limit(self.per_conversation),
+            limit(self.per_day),
+            limit(self.per_month),
+        )
+    }
+}
+
+impl Budget {
+    pub fn get(&self, period: Period) -> Option<f64> {
+        match period {
+            Period::Request => self.per_request,
+            Period::Conversation => self.per_conversation,
+            Period::Day => self.per_day,
+            Period::Month => self.per_month,
+        }
+    }
+
+    pub fn set(&mut self, period: Period, limit: Option<f64>) {
+        match period {
+            Period::Request => self.per_request = limit,
+            Period::Conversation => self.per_conversation = limit,
+            Period::Day => self.per_day = limit,
+            Period::Month => self.per_month = limit,
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == Budget::default()
+    }
+
+    /// Can a request that could cost `estimate` be sent, when
+    /// `conversation` has been spent on the conversation?
+    pub fn check(
+        &self,
+        estimate: f64,
+        conversation: f64,
+        spending: &Spending,
+    ) -> Result<(), Box<dyn Error>> {
+        if estimate <= 0.0 {
+            return Ok(());
+        }
+        let now = Local::now();
+        for (period, spent) in [
+            (Period::Request, 0.0),
+            (Period::Conversation, conversation),
+            (Period::Day, spending.today(now)),
+            (Period::Month, spending.this_month(now)),
+        ] {
+            match self.get(period) {
+                Some(limit) if spent + estimate > limit => {
+                    return Err(Box::new(ApiError::new(
+                        ApiErrorType::BudgetExceeded {
+                            period,
+                            limit,
+                            spent,
+                            estimate,
+                        },
+                        HashMap::new(),
+                    )));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Estimated tokens in `text`.  About four characters each
+pub fn estimate_tokens(text: &str) -> u32 {
+    (text.chars().count() as u32).div_ceil(4)
+}
+
+/// Spending in the current day and month, in cents
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Spending {
+    /// "YYYY-mm-dd"
+    day: String,
+    day_total: f64,
+
+    /// "YYYY-mm"
+    month: String,
+    month_total: f64,
+}
+
+impl Spending {
+    pub fn today(&self, now: DateTime<Local>) -> f64 {
+        if self.day == now.format("%Y-%m-%d").to_string() {
+            self.day_total
+        } else {
+            0.0
+        }
+    }
+
+    pub fn this_month(&self, now: DateTime<Local>) -> f64 {
+        if self.month == now.format("%Y-%m").to_string() {
+            self.month_total
+        } else {
+            0.0
+        }
+    }
+
+    /// Add `cost` spent at `now`.  A new day, or month, starts from
+    /// zero
+    pub fn add(&mut self, cost: f64, now: DateTime<Local>) {
+        self.day_total = self.today(now) + cost;
+        self.month_total = self.this_month(now) + cost;
+        self.day = now.format("%Y-%m-%d").to_string();
+        self.month = now.format("%Y-%m").to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::Charge;
+    use crate::ledger::Ledger;
+    use chrono::TimeZone;
+
+    #[test]
+    fn refuses_what_would_overspend() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = Ledger::with_file(dir.path().join("ledger.jsonl"));
+        ledger
+            .record(&Charge::new("gpt-4", "chat", 3.0, None))
+            .unwrap();
+        ledger
+            .record(&Charge::new("gpt-4", "chat", 4.0, None))
+            .unwrap();
+        let spending = Ledger::with_file(dir.path().join("ledger.jsonl"))
+            .spending()
+            .unwrap();
+        assert_eq!(spending.today(Local::now()), 7.0);
+
+        let mut budget = Budget::default();
+        assert!(budget.check(100.0, 100.0, &spending).is_ok());
+        budget.set(Period::Day, Some(10.0));
+        budget.set(Period::Request, Some(5.0));
+        assert!(budget.check(2.0, 0.0, &spending).is_ok());
+        let err = budget.check(4.0, 0.0, &spending).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>().map(|e| &e.error_type),
+            Some(ApiErrorType::BudgetExceeded {
+                period: Period::Day,
+                ..
+            })
+        ));
+        // Free requests are not refused
+        assert!(budget.check(0.0, 0.0, &spending).is_ok());
+
+        // A new day
+        let mut spending = spending;
+        let tomorrow = Local::now() + chrono::Duration::days(1);
+        assert_eq!(spending.today(tomorrow), 0.0);
+        let new_month = Local.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap();
+        spending.add(1.0, new_month);
+        assert_eq!(spending.this_month(new_month), 1.0);
+    }
+}
@@ -0,0 +1,359 @@
+//! An on-disk cache of responses.  It is opt-in: models are rarely
+//! deterministic, and a cached reply is returned however often the
+//! question is asked.  Useful for scripts that ask the same
+//! temperature 0 questions again and again.
+//!
+//! Only chats and completions are cached, and only if they succeed.
+//! The key is a hash of the endpoint, the model and the request body
+//! with its keys sorted.  Each response is a file in the cache
+//! directory.  Entries expire after a time, and
+//! the oldest are removed when the cache gets too big.
+//!
+//! Responses carry a "Cache" header: "hit" or "miss"
+use crate::transport::Body;
+use crate::transport::ChunkHandler;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::Method;
+use crate::transport::Transport;
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// The header that reports if a response came from the cache
+pub const CACHE_HEADER: &str = "Cache";
+
+/// How long entries last unless configured
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How big the cache can get unless configured.  In bytes
+pub const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+/// The endpoints whose responses are cached: OpenAI (and Azure) chats
+/// and completions, Anthropic messages, Ollama chats and generations.
+/// Everything else changes something, or should not be repeated
+const CACHED_ENDPOINTS: [&str; 4] = ["/completions", "/messages", "/api/chat", "/api/generate"];
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Where the entries are stored
+    pub dir: PathBuf,
+
+    /// Entries older than this are not used
+    pub ttl: Duration,
+
+    /// When the entries use more than this many bytes the oldest are
+    /// removed
+    pub max_bytes: u64,
+}
+
+impl CacheConfig {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            ttl: DEFAULT_TTL,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+/// What is stored for each response
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Seconds since the epoch
+    created: i64,
+    status: u16,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Entries: {} Bytes: {} Hits: {} Misses: {}",
+            self.entries, self.bytes, self.hits, self.misses
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    config: CacheConfig,
+
+    /// If set the cache is neither read nor written
+    bypass: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(config.dir.as_path())?;
+        Ok(Self {
+            config,
+            bypass: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// Stop, or restart, using the cache
+    pub fn set_bypass(&self, bypass: bool) {
+        self.bypass.store(bypass, Ordering::Relaxed);
+    }
+
+    pub fn bypass(&self) -> bool {
+        self.bypass.load(Ordering::Relaxed)
+    }
+
+    /// The key for `request`.  `None` if it is not cached
+    pub fn key(request: &HttpRequest) -> Option<String> {
+        let data = match (&request.method, &request.body) {
+            (Method::Post, Body::Json(data)) => data,
+            _ => return None,
+        };
+        let endpoint = request.uri.as_str();
+        let path = endpoint.split('?').next().unwrap_or_default();
+        if !CACHED_ENDPOINTS.iter().any(|e| path.ends_with(e)) {
+            return None;
+        }
+        let model = data["model"].as_str().unwrap_or_default();
+        let body = canonical(data).to_string();
+        let mut hasher = Sha256::new();
+        for part in [endpoint, model, body.as_str()] {
+            hasher.update(part.as_bytes());
+            // Separate the parts so they cannot run together
+            hasher.update([0_u8]);
+        }
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    /// A response from the cache, if there is a fresh one
+    pub fn get(&self, key: &str) -> Option<HttpResponse> {
+        let path = self.path(key);
+        let entry: CacheEntry = serde_json::from_slice(&fs::read(path.as_path()).ok()?).ok()?;
+        if Utc::now().timestamp() - entry.created > self.config.ttl.as_secs() as i64 {
+            let _ = fs::remove_file(path);
+            return None;
+        }
+        Some(HttpResponse {
+            status: StatusCode::from_u16(entry.status).ok()?,
+            headers: entry.headers,
+            body: entry.body.into_bytes(),
+        })
+    }
+
+    /// Store a response.  Only successful responses with a text body
+    /// are stored
+    pub fn put(&self, key: &str, response: &HttpResponse) -> Result<(), Box<dyn Error>> {
+        if !response.status.is_success() {
+            return Ok(());
+        }
+        let body = match String::from_utf8(response.body.clone()) {
+            Ok(body) => body,
+            Err(_) => return Ok(()),
+        };
+        let entry = CacheEntry {
+            created: Utc::now().timestamp(),
+            status: response.status.as_u16(),
+            headers: response.headers.clone(),
+            body,
+        };
+        fs::write(self.path(key), serde_json::to_vec(&entry)?)?;
+        self.evict()
+    }
+
+    /// Remove every entry.  Returns how many there were
+    pub fn clear(&self) -> Result<usize, Box<dyn Error>> {
+        let entries = self.entries()?;
+        for (path, _, _) in entries.iter() {
+            fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, Box<dyn Error>> {
+        let entries = self.entries()?;
+        Ok(CacheStats {
+            entries: entries.len(),
+            bytes: entries.iter().map(|(_, size, _)| size).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.config.dir.join(key).with_extension("json")
+    }
+
+    /// The entries: (path, size, modified)
+    #[allow(clippy::type_complexity)]
+    fn entries(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>, Box<dyn Error>> {
+        let mut result = vec![];
+        for entry in fs::read_dir(self.config.dir.as_path())? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let metadata = fs::metadata(path.as_path())?;
+            result.push((path, metadata.len(), metadata.modified()?));
+        }
+        Ok(result)
+    }
+
+    /// Remove the oldest entries until the cache is small enough
+    fn evict(&self) -> Result<(), Box<dyn Error>> {
+        let mut entries = self.entries()?;
+        let mut bytes: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        for (path, size, _) in entries.iter() {
+            if bytes <= self.config.max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            bytes -= size;
+        }
+        Ok(())
+    }
+}
+
+/// `value` with the keys of every object sorted, so equal requests
+/// have equal keys however they were built
+pub(crate) fn canonical(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let mut result = serde_json::Map::new();
+            for key in keys {
+                result.insert(key.clone(), canonical(&map[key]));
+            }
+            Value::Object(result)
+        }
+        Value::Array(values) => Value::Array(values.iter().map(canonical).collect()),
+        _ => value.clone(),
+    }
+}
+
+/// A `Transport` that answers from the cache when it can
+#[derive(Debug)]
+pub struct CachingTransport {
+    inner: Arc<dyn Transport>,
+    cache: Arc<ResponseCache>,
+}
+
+impl CachingTransport {
+    pub fn new(inner: Arc<dyn Transport>, cache: Arc<ResponseCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+impl Transport for CachingTransport {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let key = match ResponseCache::key(request) {
+            Some(key) if !self.cache.bypass() => key,
+            _ => return self.inner.send(request, on_chunk),
+        };
+        if let Some(mut response) = self.cache.get(key.as_str()) {
+            self.cache.hits.fetch_add(1, Ordering::Relaxed);
+            if let Some(on_chunk) = on_chunk {
+                on_chunk(&response.body);
+            }
+            response
+                .headers
+                .insert(CACHE_HEADER.to_string(), "hit".to_string());
+            return Ok(response);
+        }
+        self.cache.misses.fetch_add(1, Ordering::Relaxed);
+        let mut response = self.inner.send(request, on_chunk)?;
+        if let Err(err) = self.cache.put(key.as_str(), &response) {
+            log::warn!("Cannot cache response: {err}");
+        }
+        response
+            .headers
+            .insert(CACHE_HEADER.to_string(), "miss".to_string());
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn key_ignores_key_order() {
+        let a = HttpRequest::post_json(
+            "https://example.com/v1/chat/completions",
+            json!({"model": "gpt-4", "temperature": 0, "messages": []}),
+        );
+        let b = HttpRequest::post_json(
+            "https://example.com/v1/chat/completions",
+            json!({"messages": [], "temperature": 0, "model": "gpt-4"}),
+        );
+        assert_eq!(ResponseCache::key(&a), ResponseCache::key(&b));
+        assert!(ResponseCache::key(&HttpRequest::get("https://example.com/v1/files")).is_none());
+        let fine_tune = HttpRequest::post_json(
+            "https://example.com/v1/fine-tunes",
+            json!({"model": "gpt-4", "training_file": "file-abc"}),
+        );
+        assert!(ResponseCache::key(&fine_tune).is_none());
+    }
+
+    #[test]
+    fn stores_and_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = CacheConfig::new(dir.path().to_path_buf());
+        let cache = ResponseCache::new(config.clone()).unwrap();
+        let response = HttpResponse {
+            status: StatusCode::OK,
+            headers: HashMap::new(),
+            body: b"{}".to_vec(),
+        };
+        cache.put("key", &response).unwrap();
+        assert_eq!(cache.get("key").unwrap().body, b"{}");
+        assert_eq!(cache.stats().unwrap().entries, 1);
+
+        // Stored longer ago than the TTL: expired, so removed
+        config.ttl = Duration::from_secs(60);
+        let cache = ResponseCache { config, ..cache };
+        let path = cache.path("key");
+        let mut entry: CacheEntry = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        entry.created -= 61;
+        fs::write(&path, serde_json::to_vec(&entry).unwrap()).unwrap();
+        assert!(cache.get("key").is_none());
+        assert_eq!(cache.clear().unwrap(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+//! Stop requests that are under way.
+//!
+//! A `CancellationToken` goes with a request in `HttpRequest::cancel`.
+//! When it is cancelled, from any thread, `HttpTransport` drops the
+//! connection and the request fails with `ApiErrorType::Cancelled`.
+//! Dropping the connection is what tells a provider to stop
+//! generating, and charging for, the reply.
+//!
+//! Requests are built in many places, so rather than pass a token to
+//! each a `CancellableTransport` attaches its token to every request
+//! that goes through it.  `ApiInterface::cancel_with` sets the token
+//! for an `ApiInterface`
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::transport::ChunkHandler;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::Transport;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Clones share the state: cancel one and all are cancelled.  Once
+/// cancelled it stays cancelled, so use a new token for each request
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the token when it is dropped.  Hold it in a future to
+    /// cancel the request if the future is abandoned: when a client
+    /// disconnects
+    pub fn drop_guard(&self) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+/// See `CancellationToken::drop_guard`
+#[derive(Debug)]
+pub struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// The error for a cancelled request
+pub fn cancelled() -> Box<dyn Error> {
+    Box::new(ApiError::new(ApiErrorType::Cancelled, HashMap::new()))
+}
+
+/// Passes requests to another `Transport` with its token attached.
+/// Requests that already have a token keep it
+#[derive(Debug)]
+pub struct CancellableTransport {
+    inner: Arc<dyn Transport>,
+    token: Mutex<Option<CancellationToken>>,
+}
+
+impl CancellableTransport {
+    pub fn new(inner: Arc<dyn Transport>, token: Option<CancellationToken>) -> Self {
+        Self {
+            inner,
+            token: Mutex::new(token),
+        }
+    }
+
+    /// Requests sent from now on can be cancelled with `token`.
+    /// `None` and they cannot be
+    pub fn set_token(&self, token: Option<CancellationToken>) {
+        *self.token.lock().unwrap() = token;
+    }
+}
+
+impl Transport for CancellableTransport {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let token = self.token.lock().unwrap().clone();
+        match token {
+            Some(token) if request.cancel.is_none() => {
+                if token.is_cancelled() {
+                    return Err(cancelled());
+                }
+                self.inner
+                    .send(&request.clone().cancel_with(token), on_chunk)
+            }
+            _ => self.inner.send(request, on_chunk),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::HttpTransport;
+    use crate::transport::TransportConfig;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn cancel_drops_the_connection() {
+        // A server that reads the request and never replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!(
+            "http://{}/v1/chat/completions",
+            listener.local_addr().unwrap()
+        );
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0_u8; 1024];
+            // Returns 0 when the client hangs up
+            while stream.read(&mut buffer).unwrap_or(0) > 0 {}
+        });
+
+        let token = CancellationToken::new();
+        let transport = CancellableTransport::new(
+            Arc::new(HttpTransport::new(&TransportConfig::default()).unwrap()),
+            Some(token.clone()),
+        );
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                token.cancel();
+            })
+        };
+        let start = Instant::now();
+        let err = transport
+            .send(
+                &HttpRequest::post_json(uri.as_str(), serde_json::json!({})),
+                None,
+            )
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            err.downcast_ref::<ApiError>().map(|e| &e.error_type),
+            Some(ApiErrorType::Cancelled)
+        ));
+        canceller.join().unwrap();
+        server.join().unwrap();
+
+        // Still cancelled.  Nothing is sent
+        assert!(transport
+            .send(&HttpRequest::get(uri.as_str()), None)
+            .is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! Import the conversations in a ChatGPT data export,
+//! "conversations.json".
+//!
+//! Each conversation there is a tree of messages: editing a prompt or
+//! regenerating a reply starts a branch.  `current_node` is the last
+//! message of the branch that was showing, so the conversation is
+//! that message and its ancestors.  Only text the user and the
+//! assistant sent each other is kept.  Tool calls, tool output and
+//! hidden messages are dropped.  The first system message, if there
+//! is one, is the purpose
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::context::Context;
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The model reported when the export does not say
+const UNKNOWN_MODEL: &str = "chatgpt";
+
+#[derive(Debug, Deserialize)]
+pub struct ChatGptConversation {
+    #[serde(default)]
+    pub title: Option<String>,
+
+    /// Seconds since the epoch
+    #[serde(default)]
+    pub create_time: Option<f64>,
+
+    mapping: HashMap<String, ChatGptNode>,
+
+    #[serde(default)]
+    current_node: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptNode {
+    #[serde(default)]
+    message: Option<ChatGptMessage>,
+
+    #[serde(default)]
+    parent: Option<String>,
+
+    #[serde(default)]
+    children: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptMessage {
+    author: ChatGptAuthor,
+    content: ChatGptContent,
+
+    /// "all" if it is for the user.  Otherwise it is a tool call
+    #[serde(default)]
+    recipient: Option<String>,
+
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptAuthor {
+    role: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatGptContent {
+    content_type: String,
+
+    /// Text, or for "multimodal_text" objects describing images
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+/// A message in the active branch
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedMessage {
+    /// "system", "user" or "assistant"
+    pub role: String,
+    pub content: String,
+
+    /// The model that wrote it.  Only for "assistant"
+    pub model: Option<String>,
+}
+
+impl ChatGptMessage {
+    /// The message, if it is text that was shown
+    fn imported(&self) -> Option<ImportedMessage> {
+        if !["system", "user", "assistant"].contains(&self.author.role.as_str())
+            || !["text", "multimodal_text"].contains(&self.content.content_type.as_str())
+            || self.recipient.as_deref().is_some_and(|r| r != "all")
+            || self.metadata["is_visually_hidden_from_conversation"] == true
+        {
+            return None;
+        }
+        let content: String = self
+            .content
+            .parts
+            .iter()
+            .filter_map(|p| p.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        if content.trim().is_empty() {
+            return None;
+        }
+        Some(ImportedMessage {
+            role: self.author.role.clone(),
+            content,
+            model: self.metadata["model_slug"].as_str().map(|m| m.to_string()),
+        })
+    }
+}
+
+impl ChatGptConversation {
+    /// The node that ends the active branch.  `current_node`, or if
+    /// that is missing the last child at each step from the root
+    fn last_node(&self) -> Option<&str> {
+        if let Some(node) = self.current_node.as_deref() {
+            if self.mapping.contains_key(node) {
+                return Some(node);
+            }
+        }
+        let mut node = self
+            .mapping
+            .iter()
+            .find(|(_, n)| n.parent.is_none())
+            .map(|(id, _)| id.as_str())?;
+        while let Some(child) = self.mapping.get(node).and_then(|n| n.children.last()) {
+            node = child.as_str();
+        }
+        Some(node)
+    }
+
+    /// The messages in the active branch, in order.  Consecutive
+    /// messages from the same author are joined, so prompts and
+    /// replies alternate
+    pub fn messages(&self) -> Vec<ImportedMessage> {
+        let mut branch: Vec<&ChatGptNode> = vec![];
+        let mut next = self.last_node();
+        while let Some(node) = next.and_then(|id| self.mapping.get(id)) {
+            // A cycle would be a broken export
+            if branch.len() > self.mapping.len() {
+                break;
+            }
+            branch.push(node);
+            next = node.parent.as_deref();
+        }
+        let mut result: Vec<ImportedMessage> = vec![];
+        for message in branch
+            .iter()
+            .rev()
+            .filter_map(|n| n.message.as_ref()?.imported())
+        {
+            match result.last_mut() {
+                Some(last) if last.role == message.role => {
+                    last.content += "\n\n";
+                    last.content += message.content.as_str();
+                    if message.model.is_some() {
+                        last.model = message.model;
+                    }
+                }
+                _ => result.push(message),
+            }
+        }
+        result
+    }
+
+    /// The active branch as prompts and their replies: (prompt,
+    /// reply, model).  A prompt with no reply is dropped
+    pub fn exchanges(&self) -> Vec<(String, String, String)> {
+        let mut result = vec![];
+        let mut prompt: Option<String> = None;
+        for message in self.messages() {
+            match message.role.as_str() {
+                "user" => prompt = Some(message.content),
+                "assistant" => {
+                    if let Some(prompt) = prompt.take() {
+                        result.push((
+                            prompt,
+                            message.content,
+                            message.model.unwrap_or(UNKNOWN_MODEL.to_string()),
+                        ));
+                    }
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+
+    /// The conversation as a `Context`, as `! sx` saves and `! rx`
+    /// loads
+    pub fn to_context(&self) -> Context {
+        let purpose = self
+            .messages()
+            .into_iter()
+            .find(|m| m.role == "system")
+            .map(|m| m.content)
+            .unwrap_or_default();
+        let mut context = Context::new(purpose.as_str());
+        for (prompt, reply, _) in self.exchanges() {
+            context.push(prompt);
+            context.push(reply);
+        }
+        context
+    }
+
+    /// A file name from the date and title: "2024-01-31-a-title.json"
+    pub fn file_name(&self) -> String {
+        let date = self
+            .create_time
+            .and_then(|t| DateTime::from_timestamp(t as i64, 0))
+            .map(|d| d.format("%Y-%m-%d-").to_string())
+            .unwrap_or_default();
+        let title: String = self
+            .title
+            .as_deref()
+            .unwrap_or("untitled")
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        let title: Vec<&str> = title.split('-').filter(|s| !s.is_empty()).collect();
+        let title = if title.is_empty() {
+            "untitled".to_string()
+        } else {
+            title.join("-").chars().take(60).collect()
+        };
+        format!("{date}{title}.json")
+    }
+}
+
+/// The conversations in the contents of "conversations.json"
+pub fn parse_export(json: &str) -> Result<Vec<ChatGptConversation>, Box<dyn Error>> {
+    serde_json::from_str(json).map_err(|err| {
+        Box::new(ApiError::new(
+            ApiErrorType::BadJson(format!("{err}: Not a ChatGPT conversations export")),
+            HashMap::new(),
+        )) as Box<dyn Error>
+    })
+}
+
+/// Save each conversation in the export at `path` as a `Context`
+/// file in `dir`.  Conversations with no replies are skipped.
+/// Existing files are not replaced.  Returns the files written
+pub fn import_to_dir(path: &Path, dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let conversations = parse_export(fs::read_to_string(path)?.as_str())?;
+    fs::create_dir_all(dir)?;
+    let mut result = vec![];
+    for conversation in conversations.iter() {
+        let context = conversation.to_context();
+        if context.is_empty() {
+            continue;
+        }
+        let name = conversation.file_name();
+        let mut file = dir.join(name.as_str());
+        let mut n = 1;
+        while file.exists() {
+            n += 1;
+            file = dir.join(name.replace(".json", format!("-{n}.json").as_str()));
+        }
+        fs::write(file.as_path(), serde_json::to_string(&context)?)?;
+        result.push(file);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A conversation where the first reply was regenerated.  The
+    /// second reply is the one that was showing
+    const EXPORT: &str = r#"[{
+        "title": "Rust: hello?",
+        "create_time": 1706659200.5,
+        "current_node": "d",
+        "mapping": {
+            "root": {"message": null, "parent": null, "children": ["s"]},
+            "s": {"message": {"author": {"role": "system"}, "content": {"content_type": "text", "parts": [""]},
+                  "metadata": {"is_visually_hidden_from_conversation": true}}, "parent": "root", "children": ["a"]},
+            "a": {"message": {"author": {"role": "user"}, "content": {"content_type": "text", "parts": ["Say hello"]},
+                  "recipient": "all", "metadata": {}}, "parent": "s", "children": ["b", "c"]},
+            "b": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["Hi"]},
+                  "recipient": "all", "metadata": {"model_slug": "gpt-4"}}, "parent": "a", "children": []},
+            "c": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "code", "text": "search"},
+                  "recipient": "browser", "metadata": {}}, "parent": "a", "children": ["d"]},
+            "d": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["Hello!"]},
+                  "recipient": "all", "metadata": {"model_slug": "gpt-4o"}}, "parent": "c", "children": []}
+        }
+    }]"#;
+
+    #[test]
+    fn follows_the_active_branch() {
+        let conversations = parse_export(EXPORT).unwrap();
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(
+            conversation.exchanges(),
+            [(
+                "Say hello".to_string(),
+                "Hello!".to_string(),
+                "gpt-4o".to_string()
+            )]
+        );
+        let context = conversation.to_context();
+        assert_eq!(context.prompt_response, ["Say hello", "Hello!"]);
+        assert_eq!(context.purpose, "");
+        assert_eq!(conversation.file_name(), "2024-01-31-rust-hello.json");
+    }
+
+    #[test]
+    fn imports_to_context_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let export = dir.path().join("conversations.json");
+        fs::write(export.as_path(), EXPORT).unwrap();
+        let out = dir.path().join("imported");
+        import_to_dir(export.as_path(), out.as_path()).unwrap();
+        let files = import_to_dir(export.as_path(), out.as_path()).unwrap();
+        assert!(files[0].ends_with("2024-01-31-rust-hello-2.json"));
+        let context: Context =
+            serde_json::from_str(fs::read_to_string(files[0].as_path()).unwrap().as_str()).unwrap();
+        assert_eq!(context.len(), 2);
+        assert!(parse_export("{}").is_err());
+    }
+}
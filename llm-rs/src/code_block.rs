@@ -0,0 +1,132 @@
+//! The fenced code blocks in a reply, so code can be written to
+//! files without copying it by hand.  Fences are "```" or "~~~", of
+//! three or more, and a block ends at a fence of the same kind at
+//! least as long.  A block left open ends with the reply
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeBlock {
+    /// From the opening fence.  Empty if it was not given
+    pub language: String,
+
+    /// Ends with a new line, unless it is empty
+    pub code: String,
+}
+
+impl CodeBlock {
+    /// Is it a unified diff?  Either it says so, or it looks like one
+    pub fn is_diff(&self) -> bool {
+        matches!(self.language.as_str(), "diff" | "patch")
+            || (self.code.lines().any(|l| l.starts_with("--- "))
+                && self.code.lines().any(|l| l.starts_with("+++ "))
+                && self.code.lines().any(|l| l.starts_with("@@")))
+    }
+}
+
+impl fmt::Display for CodeBlock {
+    /// A summary: the language, the size and the first line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines = self.code.lines().count();
+        let first = self
+            .code
+            .lines()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("");
+        let language = if self.language.is_empty() {
+            "text"
+        } else {
+            self.language.as_str()
+        };
+        write!(
+            f,
+            "{language} {lines} line{}{}: {}",
+            if lines == 1 { "" } else { "s" },
+            if self.is_diff() { " (diff)" } else { "" },
+            first.trim()
+        )
+    }
+}
+
+/// The opening fence of a block: the character, its length and the
+/// language
+fn opening_fence(line: &str) -> Option<(char, usize, String)> {
+    let trimmed = line.trim_start();
+    let fence = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = trimmed.chars().take_while(|c| *c == fence).count();
+    if length < 3 {
+        return None;
+    }
+    let info = trimmed[length..].trim();
+    if fence == '`' && info.contains('`') {
+        // Inline code, not a fence
+        return None;
+    }
+    let language = info.split_whitespace().next().unwrap_or("").to_string();
+    Some((fence, length, language))
+}
+
+fn is_closing_fence(line: &str, fence: char, length: usize) -> bool {
+    let trimmed = line.trim();
+    trimmed.chars().take_while(|c| *c == fence).count() >= length
+        && trimmed.chars().all(|c| c == fence)
+}
+
+/// The code blocks in `text`, in order
+pub fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks: Vec<CodeBlock> = vec![];
+    let mut open: Option<(char, usize, CodeBlock)> = None;
+    for line in text.lines() {
+        match open.as_mut() {
+            Some((fence, length, _)) if is_closing_fence(line, *fence, *length) => {
+                blocks.push(open.take().unwrap().2);
+            }
+            Some((_, _, block)) => {
+                block.code.push_str(line);
+                block.code.push('\n');
+            }
+            None => {
+                if let Some((fence, length, language)) = opening_fence(line) {
+                    let block = CodeBlock {
+                        language,
+                        code: String::new(),
+                    };
+                    open = Some((fence, length, block));
+                }
+            }
+        }
+    }
+    if let Some((_, _, block)) = open {
+        blocks.push(block);
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_blocks() {
+        let reply = "Here:\n\
+                     ```rust\n\
+                     fn main() {}\n\
+                     ```\n\
+                     and `inline` code, then\n\
+                     ~~~~\n\
+                     ```\n\
+                     not a fence\n\
+                     ~~~~\n\
+                     ```diff\n\
+                     --- a/x\n";
+        let blocks = code_blocks(reply);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].language, "rust");
+        assert_eq!(blocks[0].code, "fn main() {}\n");
+        assert_eq!(blocks[1].language, "");
+        assert_eq!(blocks[1].code, "```\nnot a fence\n");
+        // Left open
+        assert_eq!(blocks[2].code, "--- a/x\n");
+        assert!(blocks[2].is_diff());
+        assert!(!blocks[0].is_diff());
+    }
+}
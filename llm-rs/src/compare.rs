@@ -0,0 +1,143 @@
+//! Send one prompt to several chat models at once, with the same
+//! context, to compare their replies.  The context is not changed
+//! until a reply is adopted with `ApiInterface::adopt`.  Every reply
+//! costs, adopted or not, so the costs are added to the context's
+//! cost as they arrive
+use crate::cache::CACHE_HEADER;
+use crate::json::Usage;
+use crate::openai_interface::ApiInterface;
+use std::error::Error;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// One model's reply to a compared prompt
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub model: String,
+
+    /// From sending the prompt to getting the whole reply
+    pub latency: Duration,
+
+    /// The reply, or why there is none
+    pub reply: Result<String, String>,
+
+    pub usage: Option<Usage>,
+
+    /// In cents
+    pub cost: f64,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:.2}s", self.model, self.latency.as_secs_f64())?;
+        if let Some(usage) = self.usage.as_ref() {
+            write!(
+                f,
+                " Tokens: {} ({}/{})",
+                usage.total_tokens, usage.prompt_tokens, usage.completion_tokens
+            )?;
+        }
+        write!(f, " Cost: {:.4}", self.cost)?;
+        match self.reply.as_ref() {
+            Ok(reply) => write!(f, "\n{reply}"),
+            Err(err) => write!(f, "\nFailed: {err}"),
+        }
+    }
+}
+
+impl ApiInterface {
+    /// Send `prompt`, after the context, to each of `models` at the
+    /// same time.  The replies are in the order of `models`.  The
+    /// fallback models are not used: a failure is a result.  It is an
+    /// error if together the models could cost more than the budget
+    /// allows, and then none are sent
+    pub fn compare(
+        &mut self,
+        prompt: &str,
+        models: &[String],
+    ) -> Result<Vec<Comparison>, Box<dyn Error>> {
+        let messages = self.chat_messages(prompt);
+        let names: Vec<&str> = models.iter().map(|m| m.as_str()).collect();
+        self.check_budget(&names, &messages)?;
+        let this: &ApiInterface = self;
+        let result: Vec<Comparison> = thread::scope(|scope| {
+            let handles: Vec<_> = models
+                .iter()
+                .map(|model| {
+                    let messages = &messages;
+                    scope.spawn(move || {
+                        let start = Instant::now();
+                        let result = this.send_chat_unchecked(model.as_str(), messages);
+                        let latency = start.elapsed();
+                        match result {
+                            Ok((headers, info)) => {
+                                // A cached response was paid for when it was cached
+                                let cost = if headers.get(CACHE_HEADER).map(|s| s.as_str())
+                                    == Some("hit")
+                                {
+                                    0.0
+                                } else {
+                                    ApiInterface::cost(info.usage.clone(), model.as_str())
+                                };
+                                Comparison {
+                                    model: model.clone(),
+                                    latency,
+                                    reply: Ok(info.choices[0].message.content.clone()),
+                                    usage: Some(info.usage),
+                                    cost,
+                                }
+                            }
+                            Err(err) => Comparison {
+                                model: model.clone(),
+                                latency,
+                                reply: Err(format!("{err}")),
+                                usage: None,
+                                cost: 0.0,
+                            },
+                        }
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .zip(models.iter())
+                .map(|(handle, model)| {
+                    handle.join().unwrap_or_else(|_| Comparison {
+                        model: model.clone(),
+                        latency: Duration::ZERO,
+                        reply: Err("Panicked".to_string()),
+                        usage: None,
+                        cost: 0.0,
+                    })
+                })
+                .collect()
+        });
+        let cost = result.iter().map(|c| c.cost).sum::<f64>();
+        self.context.cost += cost;
+        for comparison in result.iter() {
+            self.record_charge(
+                comparison.model.as_str(),
+                "compare",
+                comparison.cost,
+                comparison.usage.as_ref(),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Continue the conversation with `comparison`'s reply to
+    /// `prompt`.  False if it has no reply
+    pub fn adopt(&mut self, prompt: &str, comparison: &Comparison) -> bool {
+        match comparison.reply.as_ref() {
+            Ok(reply) => {
+                // Comparing was paid for when the replies came
+                self.context
+                    .push_turn(prompt.to_string(), reply.clone(), 0.0);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
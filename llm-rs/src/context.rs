@@ -6,6 +6,22 @@ pub struct Context {
     pub purpose: String,
     pub cost: f64, // IN cents, and fraction of a cent
     pub prompt_response: Vec<String>,
+
+    /// What each prompt and reply cost, in cents.  Turns added with
+    /// `push` cost nothing
+    #[serde(default)]
+    pub turn_costs: Vec<f64>,
+}
+
+/// A prompt, its reply and what it cost.  Taken off the context by
+/// `undo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Turn {
+    pub prompt: String,
+
+    /// None if the prompt was not answered
+    pub reply: Option<String>,
+    pub cost: f64,
 }
 
 impl Context {
@@ -14,6 +30,7 @@ impl Context {
             purpose: purpose.to_string(),
             cost: 0.0,
             prompt_response: Vec::new(),
+            turn_costs: Vec::new(),
         }
     }
     pub fn len(&self) -> usize {
@@ -35,11 +52,61 @@ impl Context {
         }
         format!("Purpose: {purpose}{exchange}\n")
     }
+    /// The reply to the last prompt, if it has one
+    pub fn last_reply(&self) -> Option<&str> {
+        match self.prompt_response.len() {
+            n if n > 0 && n % 2 == 0 => Some(self.prompt_response[n - 1].as_str()),
+            _ => None,
+        }
+    }
     pub fn push(&mut self, s: String) {
         self.prompt_response.push(s);
     }
+
+    /// Add a prompt and its reply, that cost `cost` cents
+    pub fn push_turn(&mut self, prompt: String, reply: String, cost: f64) {
+        self.turn_costs.resize(self.prompt_response.len() / 2, 0.0);
+        self.turn_costs.push(cost);
+        self.cost += cost;
+        self.push(prompt);
+        self.push(reply);
+    }
+
+    /// Take the last prompt, and its reply, off the context.  What
+    /// they cost comes off the cost of the conversation
+    pub fn undo(&mut self) -> Option<Turn> {
+        let reply = match self.prompt_response.len() {
+            0 => return None,
+            n if n % 2 == 0 => self.prompt_response.pop(),
+            _ => None,
+        };
+        let prompt = self.prompt_response.pop()?;
+        let cost = if reply.is_some() {
+            self.turn_costs
+                .resize(self.prompt_response.len() / 2 + 1, 0.0);
+            self.turn_costs.pop().unwrap_or(0.0)
+        } else {
+            0.0
+        };
+        self.cost = (self.cost - cost).max(0.0);
+        Some(Turn {
+            prompt,
+            reply,
+            cost,
+        })
+    }
+
+    /// Put back a turn taken off by `undo`
+    pub fn restore(&mut self, turn: Turn) {
+        match turn.reply {
+            Some(reply) => self.push_turn(turn.prompt, reply, turn.cost),
+            None => self.push(turn.prompt),
+        }
+    }
+
     pub fn clear(&mut self) {
         self.prompt_response.clear();
+        self.turn_costs.clear();
         self.cost = 0.0;
     }
     pub fn sz(&self) -> usize {
@@ -59,3 +126,37 @@ impl Context {
         purpose_size + vec_size + strs_size
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_and_restore() {
+        let mut context = Context::new("Test");
+        // Imported, so free
+        context.push("First".to_string());
+        context.push("One".to_string());
+        context.push_turn("Second".to_string(), "Two".to_string(), 1.5);
+        assert_eq!(context.turn_costs, vec![0.0, 1.5]);
+        assert_eq!(context.cost, 1.5);
+
+        let turn = context.undo().unwrap();
+        assert_eq!(turn.prompt, "Second");
+        assert_eq!(turn.reply.as_deref(), Some("Two"));
+        assert_eq!(turn.cost, 1.5);
+        assert_eq!(context.cost, 0.0);
+        assert_eq!(context.last_reply(), Some("One"));
+
+        context.restore(turn.clone());
+        assert_eq!(context.cost, 1.5);
+        assert_eq!(context.last_reply(), Some("Two"));
+        assert_eq!(context.undo(), Some(turn));
+
+        // An unanswered prompt comes off alone
+        context.push("Third".to_string());
+        assert_eq!(context.undo().unwrap().reply, None);
+        assert_eq!(context.undo().unwrap().cost, 0.0);
+        assert_eq!(context.undo(), None);
+    }
+}
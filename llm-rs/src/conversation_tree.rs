@@ -0,0 +1,357 @@
+//! Conversations that branch.  A `Context` is one line of questioning;
+//! a `ConversationTree` keeps many that share their beginnings.  Each
+//! turn, a prompt and its reply, is a node, and a branch is a named
+//! last turn.  A branch can be forked from any of its turns, so
+//! another line of questioning can be explored without losing the
+//! first.
+//!
+//! The `Context` being used is the branch in use.  `update` records
+//! it in the tree, and is called before the tree is used
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::context::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// The branch a tree starts with
+pub const MAIN: &str = "main";
+
+fn tree_error(msg: String) -> Box<dyn Error> {
+    Box::new(ApiError::new(ApiErrorType::Error(msg), HashMap::new()))
+}
+
+/// A prompt and its reply
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    /// The turn before.  None for the first turn
+    parent: Option<usize>,
+    prompt: String,
+    reply: String,
+
+    /// In cents
+    cost: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Branch {
+    /// The last turn.  None if there are none
+    head: Option<usize>,
+
+    /// The system prompt
+    purpose: String,
+
+    /// The cost of the conversation, in cents
+    cost: f64,
+
+    /// A prompt at the end with no reply
+    unanswered: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationTree {
+    nodes: Vec<Node>,
+    branches: BTreeMap<String, Branch>,
+
+    /// The branch in use
+    current: String,
+}
+
+/// The turns two branches do not share
+#[derive(Debug, Clone)]
+pub struct BranchDiff {
+    pub from: String,
+    pub to: String,
+
+    /// How many turns, from the start, they share
+    pub common: usize,
+
+    /// (prompt, reply)
+    pub only_from: Vec<(String, String)>,
+    pub only_to: Vec<(String, String)>,
+}
+
+impl fmt::Display for BranchDiff {
+    /// Like a unified diff, every line of the turns only in `from`
+    /// starting with "-" and only in `to` with "+"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "--- {}\n+++ {}", self.from, self.to)?;
+        write!(f, "@@ {} turns in common @@", self.common)?;
+        for (mark, turns) in [("-", &self.only_from), ("+", &self.only_to)] {
+            for (prompt, reply) in turns.iter() {
+                for (role, text) in [("user", prompt), ("assistant", reply)] {
+                    write!(f, "\n{mark}{role}:")?;
+                    for line in text.lines() {
+                        write!(f, "\n{mark}{line}")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConversationTree {
+    /// A tree with one branch, "main", that is `context`
+    pub fn new(context: &Context) -> Self {
+        let mut tree = Self {
+            nodes: vec![],
+            branches: BTreeMap::new(),
+            current: MAIN.to_string(),
+        };
+        tree.update(context);
+        tree
+    }
+
+    /// The name of the branch in use
+    pub fn current(&self) -> &str {
+        self.current.as_str()
+    }
+
+    /// The names of the branches, with how many turns they have
+    pub fn branches(&self) -> Vec<(String, usize)> {
+        self.branches
+            .iter()
+            .map(|(name, branch)| (name.clone(), self.path(branch.head).len()))
+            .collect()
+    }
+
+    /// Make the branch in use `context`.  Turns it shares with other
+    /// branches are shared in the tree
+    pub fn update(&mut self, context: &Context) {
+        let mut head: Option<usize> = None;
+        for (i, turn) in context.prompt_response.chunks_exact(2).enumerate() {
+            let (prompt, reply) = (&turn[0], &turn[1]);
+            let found = self
+                .nodes
+                .iter()
+                .position(|n| n.parent == head && n.prompt == *prompt && n.reply == *reply);
+            head = Some(match found {
+                Some(id) => id,
+                None => {
+                    self.nodes.push(Node {
+                        parent: head,
+                        prompt: prompt.clone(),
+                        reply: reply.clone(),
+                        cost: context.turn_costs.get(i).copied().unwrap_or(0.0),
+                    });
+                    self.nodes.len() - 1
+                }
+            });
+        }
+        let unanswered = match context.prompt_response.len() % 2 {
+            1 => context.prompt_response.last().cloned(),
+            _ => None,
+        };
+        self.branches.insert(
+            self.current.clone(),
+            Branch {
+                head,
+                purpose: context.purpose.clone(),
+                cost: context.cost,
+                unanswered,
+            },
+        );
+        self.prune();
+    }
+
+    /// The conversation on the branch `name`
+    pub fn context(&self, name: &str) -> Result<Context, Box<dyn Error>> {
+        let branch = self.branch(name)?;
+        let mut context = Context::new(branch.purpose.as_str());
+        for id in self.path(branch.head) {
+            let node = &self.nodes[id];
+            context.push_turn(node.prompt.clone(), node.reply.clone(), node.cost);
+        }
+        context.cost = branch.cost;
+        if let Some(prompt) = branch.unanswered.as_ref() {
+            context.push(prompt.clone());
+        }
+        Ok(context)
+    }
+
+    /// Change to the branch `name`.  Returns its conversation
+    pub fn switch(&mut self, name: &str) -> Result<Context, Box<dyn Error>> {
+        let context = self.context(name)?;
+        self.current = name.to_string();
+        Ok(context)
+    }
+
+    /// Start the branch `name` with the first `turns` turns of the
+    /// branch in use, all of them if None, and change to it.  Returns
+    /// its conversation
+    pub fn fork(&mut self, name: &str, turns: Option<usize>) -> Result<Context, Box<dyn Error>> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(tree_error(format!("Bad branch name: \"{name}\"")));
+        }
+        if self.branches.contains_key(name) {
+            return Err(tree_error(format!("There is a branch called {name}")));
+        }
+        let branch = self.branch(self.current.as_str())?.clone();
+        let path = self.path(branch.head);
+        let turns = turns.unwrap_or(path.len());
+        if turns > path.len() {
+            return Err(tree_error(format!(
+                "{} has {} turns, not {turns}",
+                self.current,
+                path.len()
+            )));
+        }
+        let head = turns.checked_sub(1).map(|i| path[i]);
+        let cost = if head == branch.head {
+            branch.cost
+        } else {
+            path[..turns].iter().map(|id| self.nodes[*id].cost).sum()
+        };
+        self.branches.insert(
+            name.to_string(),
+            Branch {
+                head,
+                purpose: branch.purpose,
+                cost,
+                unanswered: None,
+            },
+        );
+        self.switch(name)
+    }
+
+    /// How the branches `from` and `to` differ
+    pub fn diff(&self, from: &str, to: &str) -> Result<BranchDiff, Box<dyn Error>> {
+        let from_path = self.path(self.branch(from)?.head);
+        let to_path = self.path(self.branch(to)?.head);
+        let common = from_path
+            .iter()
+            .zip(to_path.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let turns = |path: &[usize]| -> Vec<(String, String)> {
+            path[common..]
+                .iter()
+                .map(|id| {
+                    (
+                        self.nodes[*id].prompt.clone(),
+                        self.nodes[*id].reply.clone(),
+                    )
+                })
+                .collect()
+        };
+        Ok(BranchDiff {
+            from: from.to_string(),
+            to: to.to_string(),
+            common,
+            only_from: turns(&from_path),
+            only_to: turns(&to_path),
+        })
+    }
+
+    fn branch(&self, name: &str) -> Result<&Branch, Box<dyn Error>> {
+        self.branches.get(name).ok_or_else(|| {
+            tree_error(format!(
+                "No branch: {name}.  Branches are: {}",
+                self.branches
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ))
+        })
+    }
+
+    /// The turns up to `head`, first turn first
+    fn path(&self, head: Option<usize>) -> Vec<usize> {
+        let mut path = vec![];
+        let mut next = head;
+        while let Some(id) = next {
+            path.push(id);
+            next = self.nodes[id].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Remove turns no branch has, such as those undone or cleared
+    fn prune(&mut self) {
+        let mut keep = vec![false; self.nodes.len()];
+        for branch in self.branches.values() {
+            for id in self.path(branch.head) {
+                keep[id] = true;
+            }
+        }
+        if keep.iter().all(|k| *k) {
+            return;
+        }
+        // Parents come before their children, so the new index of a
+        // parent is known before it is needed
+        let mut new_id: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut nodes: Vec<Node> = vec![];
+        for (id, node) in self.nodes.drain(..).enumerate() {
+            if keep[id] {
+                new_id[id] = Some(nodes.len());
+                nodes.push(Node {
+                    parent: node.parent.and_then(|p| new_id[p]),
+                    ..node
+                });
+            }
+        }
+        self.nodes = nodes;
+        for branch in self.branches.values_mut() {
+            branch.head = branch.head.and_then(|h| new_id[h]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fork_switch_and_diff() {
+        let mut context = Context::new("Be brief");
+        context.push_turn("One".to_string(), "1".to_string(), 1.0);
+        context.push_turn("Two".to_string(), "2".to_string(), 2.0);
+        let mut tree = ConversationTree::new(&context);
+
+        // Back to the first turn, and ask something else
+        let mut other = tree.fork("other", Some(1)).unwrap();
+        assert_eq!(tree.current(), "other");
+        assert_eq!(other.len(), 2);
+        assert_eq!(other.cost, 1.0);
+        assert_eq!(other.purpose, "Be brief");
+        other.push_turn("Deux".to_string(), "2\nii".to_string(), 0.5);
+        tree.update(&other);
+        assert!(tree.fork("other", None).is_err());
+        assert!(tree.fork("more", Some(3)).is_err());
+
+        assert_eq!(
+            tree.branches(),
+            vec![("main".to_string(), 2), ("other".to_string(), 2)]
+        );
+        assert_eq!(tree.nodes.len(), 3);
+        let diff = tree.diff(MAIN, "other").unwrap();
+        assert_eq!(diff.common, 1);
+        assert_eq!(
+            diff.to_string(),
+            "--- main\n+++ other\n@@ 1 turns in common @@\n\
+             -user:\n-Two\n-assistant:\n-2\n\
+             +user:\n+Deux\n+assistant:\n+2\n+ii"
+        );
+
+        // The whole tree is kept
+        let json = serde_json::to_string(&tree).unwrap();
+        let mut tree: ConversationTree = serde_json::from_str(json.as_str()).unwrap();
+        let main = tree.switch(MAIN).unwrap();
+        assert_eq!(main.last_reply(), Some("2"));
+        assert_eq!(main.cost, 3.0);
+
+        // Undone turns go
+        let mut main = main;
+        main.undo();
+        tree.update(&main);
+        assert_eq!(tree.nodes.len(), 2);
+        assert_eq!(tree.context("other").unwrap().last_reply(), Some("2\nii"));
+        assert!(tree.switch("none").is_err());
+    }
+}
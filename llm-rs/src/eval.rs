@@ -0,0 +1,467 @@
+//! Evaluate prompts against chat models, to catch regressions when
+//! models or system prompts change.
+//!
+//! A suite is a JSON file of prompts with assertions about the
+//! replies:
+//!
+//! ```json
+//! {
+//!   "system": "You are a geography teacher.  Be brief",
+//!   "models": ["gpt-3.5-turbo", "gpt-4"],
+//!   "grader": "gpt-4o",
+//!   "cases": [
+//!     {
+//!       "name": "capital",
+//!       "prompt": "What is the capital of France?",
+//!       "assert": [
+//!         {"contains": "Paris"},
+//!         {"regex": "^[A-Z]"},
+//!         {"max_tokens": 50},
+//!         {"rubric": "Answers in one sentence"}
+//!       ]
+//!     },
+//!     {
+//!       "name": "as json",
+//!       "prompt": "Give the capital of France as JSON: {\"capital\": ...}",
+//!       "assert": [{"json_schema": {"type": "object", "required": ["capital"]}}]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `models` are used if no models are given to `run`.  A case can
+//! have its own `system` prompt.  "rubric" assertions are graded by
+//! asking the `grader` model, and cost as well.  Run against a
+//! `ReplayTransport` the results are reproducible
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::openai_interface::ApiInterface;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+/// Grades rubrics if the suite does not say
+pub const DEFAULT_GRADER: &str = "gpt-4o";
+
+/// The system prompt for grading rubrics
+const GRADER_PROMPT: &str = "You grade replies from an AI assistant.  \
+    You are given a rubric, a prompt and the reply to it.  \
+    If the reply meets the rubric answer PASS, otherwise answer FAIL.  \
+    Then give a reason in one sentence";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Assertion {
+    /// The reply contains the text
+    Contains(String),
+
+    /// The reply matches the regular expression
+    Regex(String),
+
+    /// The reply is JSON that is valid for the schema.  A reply in a
+    /// code fence is taken out of the fence
+    JsonSchema(Value),
+
+    /// The reply is no more than this many tokens
+    MaxTokens(u32),
+
+    /// The grader model agrees that the reply meets the rubric
+    Rubric(String),
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Assertion::Contains(text) => write!(f, "contains {text:?}"),
+            Assertion::Regex(regex) => write!(f, "matches /{regex}/"),
+            Assertion::JsonSchema(_) => write!(f, "JSON schema"),
+            Assertion::MaxTokens(tokens) => write!(f, "max tokens {tokens}"),
+            Assertion::Rubric(rubric) => write!(f, "rubric {rubric:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Case {
+    pub name: String,
+    pub prompt: String,
+
+    /// In place of the suite's system prompt
+    #[serde(default)]
+    pub system: Option<String>,
+
+    #[serde(default, rename = "assert")]
+    pub assertions: Vec<Assertion>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Suite {
+    #[serde(default)]
+    pub system: String,
+
+    #[serde(default)]
+    pub models: Vec<String>,
+
+    #[serde(default)]
+    pub grader: Option<String>,
+
+    pub cases: Vec<Case>,
+}
+
+impl Suite {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            ApiError::new(
+                ApiErrorType::Error(format!("{err}: Cannot read {}", path.display())),
+                HashMap::new(),
+            )
+        })?;
+        Ok(serde_json::from_str(contents.as_str())?)
+    }
+}
+
+/// How a case went with a model
+#[derive(Debug, Clone, Serialize)]
+pub struct CaseResult {
+    pub model: String,
+    pub case: String,
+    pub passed: bool,
+
+    /// Why it did not pass
+    pub failures: Vec<String>,
+
+    pub reply: Option<String>,
+
+    /// Completion tokens
+    pub tokens: Option<u32>,
+
+    /// In cents.  Includes grading
+    pub cost: f64,
+
+    pub latency_ms: u128,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EvalReport {
+    pub results: Vec<CaseResult>,
+}
+
+impl EvalReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    /// In cents
+    pub fn cost(&self) -> f64 {
+        self.results.iter().map(|r| r.cost).sum()
+    }
+}
+
+impl fmt::Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut models: Vec<&str> = vec![];
+        for result in self.results.iter() {
+            if !models.contains(&result.model.as_str()) {
+                models.push(result.model.as_str());
+            }
+            writeln!(
+                f,
+                "{} {} {}: {:.4} {}ms",
+                if result.passed { "PASS" } else { "FAIL" },
+                result.model,
+                result.case,
+                result.cost,
+                result.latency_ms,
+            )?;
+            for failure in result.failures.iter() {
+                writeln!(f, "\t{failure}")?;
+            }
+        }
+        for model in models {
+            let results: Vec<&CaseResult> =
+                self.results.iter().filter(|r| r.model == model).collect();
+            writeln!(
+                f,
+                "{model}: {}/{} passed Cost: {:.4}",
+                results.iter().filter(|r| r.passed).count(),
+                results.len(),
+                results.iter().map(|r| r.cost).sum::<f64>(),
+            )?;
+        }
+        write!(
+            f,
+            "Total: {} passed {} failed Cost: {:.4}",
+            self.passed(),
+            self.failed(),
+            self.cost()
+        )
+    }
+}
+
+/// `reply` without a code fence around it
+fn unfenced(reply: &str) -> &str {
+    let reply = reply.trim();
+    match reply
+        .strip_prefix("```")
+        .and_then(|r| r.strip_suffix("```"))
+    {
+        // Drop the language after the opening fence
+        Some(inner) => inner.split_once('\n').map_or(inner, |(_, code)| code),
+        None => reply,
+    }
+}
+
+/// Check the assertions that need no model.  `None` if it holds,
+/// else why not.  Rubrics hold here
+pub fn check(assertion: &Assertion, reply: &str, tokens: Option<u32>) -> Option<String> {
+    match assertion {
+        Assertion::Contains(text) => {
+            (!reply.contains(text.as_str())).then(|| format!("Does not contain {text:?}"))
+        }
+        Assertion::Regex(regex) => match Regex::new(regex.as_str()) {
+            Ok(re) => (!re.is_match(reply)).then(|| format!("Does not match /{regex}/")),
+            Err(err) => Some(format!("{err}: Bad regex")),
+        },
+        Assertion::JsonSchema(schema) => {
+            let validator = match jsonschema::validator_for(schema) {
+                Ok(validator) => validator,
+                Err(err) => return Some(format!("{err}: Bad schema")),
+            };
+            match serde_json::from_str::<Value>(unfenced(reply)) {
+                Ok(value) => validator
+                    .validate(&value)
+                    .err()
+                    .map(|err| format!("Not valid for the schema: {err}")),
+                Err(err) => Some(format!("{err}: Not JSON")),
+            }
+        }
+        Assertion::MaxTokens(max) => match tokens {
+            Some(tokens) if tokens > *max => Some(format!("{tokens} tokens.  More than {max}")),
+            _ => None,
+        },
+        Assertion::Rubric(_) => None,
+    }
+}
+
+/// Ask `grader` if `reply` to `prompt` meets `rubric`.  Returns
+/// (passed, reason, cost)
+fn grade(
+    api_interface: &mut ApiInterface,
+    grader: &str,
+    rubric: &str,
+    prompt: &str,
+    reply: &str,
+) -> Result<(bool, String, f64), Box<dyn Error>> {
+    api_interface.clear_context();
+    api_interface.context.purpose = GRADER_PROMPT.to_string();
+    let result = api_interface.chat(
+        format!("Rubric: {rubric}\n\nPrompt: {prompt}\n\nReply: {reply}").as_str(),
+        grader,
+    )?;
+    let cost: f64 = result
+        .headers
+        .get("Cost")
+        .and_then(|c| c.parse().ok())
+        .unwrap_or_default();
+    let verdict = result.body.trim();
+    Ok((
+        verdict.to_uppercase().starts_with("PASS"),
+        verdict.to_string(),
+        cost,
+    ))
+}
+
+/// Run each case in `suite` with each of `models`, or the suite's
+/// models if there are none.  Each case starts with an empty context.
+/// The fallback models are not used, and the context is restored
+/// at the end
+pub fn run(api_interface: &mut ApiInterface, suite: &Suite, models: &[String]) -> EvalReport {
+    let models = if models.is_empty() {
+        suite.models.as_slice()
+    } else {
+        models
+    };
+    let grader = suite.grader.as_deref().unwrap_or(DEFAULT_GRADER);
+    let saved_context = api_interface.context.clone();
+    let saved_fallback = std::mem::take(&mut api_interface.fallback);
+    let saved_mode = std::mem::replace(&mut api_interface.charge_mode, "eval");
+    let mut report = EvalReport::default();
+    for model in models.iter() {
+        for case in suite.cases.iter() {
+            api_interface.clear_context();
+            api_interface.context.purpose = case.system.clone().unwrap_or(suite.system.clone());
+            let start = Instant::now();
+            let result = api_interface.chat(case.prompt.as_str(), model.as_str());
+            let latency_ms = start.elapsed().as_millis();
+            let mut case_result = CaseResult {
+                model: model.clone(),
+                case: case.name.clone(),
+                passed: false,
+                failures: vec![],
+                reply: None,
+                tokens: None,
+                cost: 0.0,
+                latency_ms,
+            };
+            match result {
+                Ok(result) => {
+                    let reply = result.body;
+                    let tokens: Option<u32> = result
+                        .headers
+                        .get("Tokens completion")
+                        .and_then(|t| t.parse().ok());
+                    case_result.cost = result
+                        .headers
+                        .get("Cost")
+                        .and_then(|c| c.parse().ok())
+                        .unwrap_or_default();
+                    for assertion in case.assertions.iter() {
+                        let failure = match assertion {
+                            Assertion::Rubric(rubric) => match grade(
+                                api_interface,
+                                grader,
+                                rubric,
+                                case.prompt.as_str(),
+                                reply.as_str(),
+                            ) {
+                                Ok((passed, reason, cost)) => {
+                                    case_result.cost += cost;
+                                    (!passed).then(|| format!("{assertion}: {reason}"))
+                                }
+                                Err(err) => Some(format!("{assertion}: {err}: Grading failed")),
+                            },
+                            _ => check(assertion, reply.as_str(), tokens),
+                        };
+                        case_result.failures.extend(failure);
+                    }
+                    case_result.passed = case_result.failures.is_empty();
+                    case_result.reply = Some(reply);
+                    case_result.tokens = tokens;
+                }
+                Err(err) => case_result
+                    .failures
+                    .push(format!("{err}").trim().to_string()),
+            }
+            report.results.push(case_result);
+        }
+    }
+    api_interface.context = saved_context;
+    api_interface.fallback = saved_fallback;
+    api_interface.charge_mode = saved_mode;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::ChatRequestInfo;
+    use crate::json::Usage;
+    use crate::transport::ChunkHandler;
+    use crate::transport::HttpRequest;
+    use crate::transport::HttpResponse;
+    use crate::transport::Transport;
+    use reqwest::StatusCode;
+    use std::sync::Arc;
+
+    /// Every chat gets the reply `{"capital": "Paris"}`.  The grader
+    /// is told it passes
+    #[derive(Debug)]
+    struct FakeTransport;
+
+    impl Transport for FakeTransport {
+        fn send(
+            &self,
+            request: &HttpRequest,
+            _on_chunk: Option<ChunkHandler>,
+        ) -> Result<HttpResponse, Box<dyn Error>> {
+            let grading = format!("{:?}", request.body).contains("Rubric");
+            let reply = ChatRequestInfo::new(
+                "id".to_string(),
+                "gpt-4".to_string(),
+                Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 8,
+                    total_tokens: 18,
+                },
+                if grading {
+                    "PASS.  It is brief".to_string()
+                } else {
+                    "```json\n{\"capital\": \"Paris\"}\n```".to_string()
+                },
+                "stop".to_string(),
+            );
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HashMap::new(),
+                body: serde_json::to_vec(&reply)?,
+            })
+        }
+    }
+
+    #[test]
+    fn checks_assertions() {
+        let reply = "```json\n{\"capital\": \"Paris\"}\n```";
+        let schema = serde_json::json!({
+            "type": "object",
+            "required": ["capital"],
+            "properties": {"capital": {"type": "string"}}
+        });
+        assert_eq!(check(&Assertion::JsonSchema(schema), reply, None), None);
+        let schema = serde_json::json!({"type": "array"});
+        assert!(check(&Assertion::JsonSchema(schema), reply, None).is_some());
+        assert!(check(&Assertion::Contains("Rome".to_string()), reply, None).is_some());
+        assert_eq!(
+            check(&Assertion::Regex("Par+is".to_string()), reply, None),
+            None
+        );
+        assert!(check(&Assertion::MaxTokens(5), reply, Some(8)).is_some());
+    }
+
+    #[test]
+    fn runs_suite_against_models() {
+        let suite: Suite = serde_json::from_str(
+            r#"{
+                "system": "Reply in JSON",
+                "models": ["gpt-3.5-turbo", "gpt-4"],
+                "cases": [
+                    {"name": "capital", "prompt": "Capital of France?",
+                     "assert": [{"contains": "Paris"}, {"rubric": "Is brief"}]},
+                    {"name": "short", "prompt": "Capital of France?",
+                     "assert": [{"max_tokens": 5}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.0)
+            .transport(Arc::new(FakeTransport))
+            .build()
+            .unwrap();
+        let report = run(&mut api_interface, &suite, &[]);
+        assert_eq!(report.results.len(), 4);
+        assert_eq!((report.passed(), report.failed()), (2, 2));
+        assert!(report.cost() > 0.0);
+        assert!(report.to_string().ends_with(&format!(
+            "Total: 2 passed 2 failed Cost: {:.4}",
+            report.cost()
+        )));
+        assert!(api_interface.context.is_empty());
+        let charges = api_interface.ledger().charges().unwrap();
+        assert_eq!(charges.len(), 6);
+        assert!(charges.iter().all(|c| c.mode == "eval"));
+        assert_eq!(api_interface.charge_mode, "chat");
+
+        let report = run(&mut api_interface, &suite, &["gpt-4".to_string()]);
+        assert_eq!(report.results.len(), 2);
+    }
+}
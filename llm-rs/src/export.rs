@@ -0,0 +1,259 @@
+//! Export a `Context` to share it or train on it.
+//!
+//! * Markdown.  The messages are copied as they are, so code fences
+//!   are kept
+//! * HTML.  A page that stands alone, with its own style
+//! * Fine-tuning JSONL.  One line per conversation in the format
+//!   OpenAI uses for fine-tuning chat models:
+//!   `{"messages": [{"role": "system", ...}, {"role": "user", ...},
+//!   {"role": "assistant", ...}]}`
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::context::Context;
+use crate::json::Message;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    FineTune,
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+            ExportFormat::FineTune => "jsonl",
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ApiError;
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "html" => Ok(ExportFormat::Html),
+            "jsonl" | "ft" => Ok(ExportFormat::FineTune),
+            _ => Err(ApiError::new(
+                ApiErrorType::Error(format!(
+                    "Unknown export format: {format}.  Use md, html or jsonl"
+                )),
+                HashMap::new(),
+            )),
+        }
+    }
+}
+
+/// `context` in `format`
+pub fn export(context: &Context, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Markdown => to_markdown(context),
+        ExportFormat::Html => to_html(context),
+        ExportFormat::FineTune => to_fine_tune(context),
+    }
+}
+
+/// The messages in `context`: (role, content).  The prompts and
+/// replies alternate, starting with a prompt
+fn messages(context: &Context) -> impl Iterator<Item = (&'static str, &str)> {
+    context
+        .prompt_response
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (if i % 2 == 0 { "user" } else { "assistant" }, s.as_str()))
+}
+
+pub fn to_markdown(context: &Context) -> String {
+    let mut result = "# Conversation\n".to_string();
+    if !context.purpose.is_empty() {
+        result += format!("\n> {}\n", context.purpose.replace('\n', "\n> ")).as_str();
+    }
+    for (role, content) in messages(context) {
+        let heading = if role == "user" { "User" } else { "Assistant" };
+        result += format!("\n## {heading}\n\n{}\n", content.trim_end()).as_str();
+
+        // A fence left open would swallow the rest of the document
+        if content
+            .lines()
+            .filter(|l| l.trim_start().starts_with("```"))
+            .count()
+            % 2
+            == 1
+        {
+            result += "```\n";
+        }
+    }
+    result
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A message as HTML.  Fenced code is in `<pre>`, the rest is
+/// paragraphs
+fn message_html(content: &str) -> String {
+    let mut result = String::new();
+    let mut paragraph: Vec<&str> = vec![];
+    let mut code: Option<Vec<&str>> = None;
+    let end_paragraph = |paragraph: &mut Vec<&str>, result: &mut String| {
+        if !paragraph.is_empty() {
+            *result += format!(
+                "<p>{}</p>\n",
+                escape_html(&paragraph.join("\n")).replace('\n', "<br>\n")
+            )
+            .as_str();
+            paragraph.clear();
+        }
+    };
+    for line in content.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (code.as_mut(), fence) {
+            (Some(lines), None) => lines.push(line),
+            (Some(lines), Some(_)) => {
+                result += format!("{}</code></pre>\n", escape_html(&lines.join("\n"))).as_str();
+                code = None;
+            }
+            (None, Some(language)) => {
+                end_paragraph(&mut paragraph, &mut result);
+                let language = language.trim();
+                result += if language.is_empty() {
+                    "<pre><code>".to_string()
+                } else {
+                    format!("<pre><code class=\"language-{}\">", escape_html(language))
+                }
+                .as_str();
+                code = Some(vec![]);
+            }
+            (None, None) if line.trim().is_empty() => end_paragraph(&mut paragraph, &mut result),
+            (None, None) => paragraph.push(line),
+        }
+    }
+    if let Some(lines) = code {
+        result += format!("{}</code></pre>\n", escape_html(&lines.join("\n"))).as_str();
+    }
+    end_paragraph(&mut paragraph, &mut result);
+    result
+}
+
+pub fn to_html(context: &Context) -> String {
+    let mut body = String::new();
+    if !context.purpose.is_empty() {
+        body += format!(
+            "<div class=\"system\">{}</div>\n",
+            escape_html(context.purpose.as_str())
+        )
+        .as_str();
+    }
+    for (role, content) in messages(context) {
+        body += format!(
+            "<div class=\"{role}\">\n<h2>{}</h2>\n{}</div>\n",
+            if role == "user" { "User" } else { "Assistant" },
+            message_html(content)
+        )
+        .as_str();
+    }
+    format!(
+        "<!DOCTYPE html>\n\
+	 <html>\n\
+	 <head>\n\
+	 <meta charset=\"utf-8\">\n\
+	 <title>Conversation</title>\n\
+	 <style>\n\
+	 body {{ font-family: sans-serif; max-width: 50em; margin: auto; }}\n\
+	 h2 {{ font-size: 1em; }}\n\
+	 .system {{ font-style: italic; }}\n\
+	 .user, .assistant {{ padding: 0.5em 1em; margin: 1em 0; border-radius: 0.5em; }}\n\
+	 .user {{ background: #eef; }}\n\
+	 .assistant {{ background: #efe; }}\n\
+	 pre {{ background: #f4f4f4; padding: 0.5em; overflow-x: auto; }}\n\
+	 </style>\n\
+	 </head>\n\
+	 <body>\n\
+	 <h1>Conversation</h1>\n\
+	 {body}\
+	 </body>\n\
+	 </html>\n"
+    )
+}
+
+/// One line of fine-tuning data.  A prompt with no reply is left
+/// out: an example has to end with the reply to learn
+pub fn to_fine_tune(context: &Context) -> String {
+    let mut messages: Vec<Message> = messages(context)
+        .map(|(role, content)| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        })
+        .collect();
+    if messages.len() % 2 == 1 {
+        messages.pop();
+    }
+    if !context.purpose.is_empty() {
+        messages.insert(
+            0,
+            Message {
+                role: "system".to_string(),
+                content: context.purpose.clone(),
+            },
+        );
+    }
+    format!("{}\n", json!({ "messages": messages }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        let mut context = Context::new("Be brief");
+        context.push("Print hello in Rust".to_string());
+        context.push("Like this:\n```rust\nprintln!(\"<hello>\");\n```".to_string());
+        context
+    }
+
+    #[test]
+    fn markdown_and_html_keep_code() {
+        let markdown = to_markdown(&context());
+        assert!(markdown.contains("## User\n\nPrint hello in Rust\n"));
+        assert!(markdown.contains("```rust\nprintln!(\"<hello>\");\n```\n"));
+
+        let html = to_html(&context());
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains(
+            "<pre><code class=\"language-rust\">println!(&quot;&lt;hello&gt;&quot;);</code></pre>"
+        ));
+        assert!(html.contains("<p>Like this:</p>"));
+    }
+
+    #[test]
+    fn fine_tune_is_one_line_ending_with_a_reply() {
+        let mut context = context();
+        context.push("Unanswered".to_string());
+        let line = to_fine_tune(&context);
+        assert_eq!(line.lines().count(), 1);
+        let value: serde_json::Value = serde_json::from_str(line.as_str()).unwrap();
+        let roles: Vec<&str> = value["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant"]);
+        assert_eq!(
+            "ft".parse::<ExportFormat>().unwrap(),
+            ExportFormat::FineTune
+        );
+        assert!("pdf".parse::<ExportFormat>().is_err());
+    }
+}
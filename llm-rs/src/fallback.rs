@@ -0,0 +1,134 @@
+//! Fallback chains.  If a model is rate limited, overloaded or too
+//! slow the request is sent to the next model in the chain, so
+//! "gpt-4 gpt-4-turbo gpt-3.5-turbo" still gets a reply when "gpt-4"
+//! is busy.  Other errors (a bad key, a bad request) would fail the
+//! same way for every model, so they are returned at once.
+//!
+//! Which model answered is in the "Model" header of chat responses
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use std::error::Error;
+use std::io;
+
+/// The header that names the model that answered
+pub const MODEL_HEADER: &str = "Model";
+
+/// `model` followed by the models in `fallback`, without repeats
+pub fn chain(model: &str, fallback: &[String]) -> Vec<String> {
+    let mut result = vec![model.to_string()];
+    for model in fallback.iter() {
+        if !result.contains(model) {
+            result.push(model.clone());
+        }
+    }
+    result
+}
+
+/// If another model might succeed where `err` failed: rate limits,
+/// overloaded or failing servers, and timeouts
+pub fn is_retryable(err: &(dyn Error + 'static)) -> bool {
+    let mut source: Option<&(dyn Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<ApiError>() {
+            return match err.error_type {
+                // 529 is Anthropic's "overloaded"
+                ApiErrorType::Status(status, _) => {
+                    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504 | 529)
+                }
+                // Anthropic reports these in the body as well
+                ApiErrorType::Error(ref msg) => {
+                    msg.starts_with("rate_limit_error") || msg.starts_with("overloaded_error")
+                }
+                _ => false,
+            };
+        }
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            if err.is_timeout() {
+                return true;
+            }
+        }
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if err.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Call `attempt` with each model in `models` until one succeeds, or
+/// fails with an error that is not retryable.  Returns the model that
+/// answered and its answer.  If every model fails the last error is
+/// returned
+pub fn with_fallback<T>(
+    models: &[String],
+    mut attempt: impl FnMut(&str) -> Result<T, Box<dyn Error>>,
+) -> Result<(String, T), Box<dyn Error>> {
+    let mut last_error: Option<Box<dyn Error>> = None;
+    for model in models.iter() {
+        match attempt(model.as_str()) {
+            Ok(result) => return Ok((model.clone(), result)),
+            Err(err) if is_retryable(err.as_ref()) => {
+                log::warn!("{model} failed, trying the next model: {err}");
+                last_error = Some(err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        Box::new(ApiError::new(
+            ApiErrorType::Error("No model to try".to_string()),
+            Default::default(),
+        ))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use std::collections::HashMap;
+
+    fn status_error(status: StatusCode) -> Box<dyn Error> {
+        Box::new(ApiError::new(
+            ApiErrorType::Status(status, status.to_string()),
+            HashMap::new(),
+        ))
+    }
+
+    #[test]
+    fn falls_back_on_retryable_errors_only() {
+        let models = chain(
+            "gpt-4",
+            &[
+                "gpt-4-turbo".to_string(),
+                "gpt-4".to_string(),
+                "gpt-3.5-turbo".to_string(),
+            ],
+        );
+        assert_eq!(models, ["gpt-4", "gpt-4-turbo", "gpt-3.5-turbo"]);
+
+        let mut tried = vec![];
+        let (model, answer) = with_fallback(&models, |model| {
+            tried.push(model.to_string());
+            match model {
+                "gpt-4" => Err(status_error(StatusCode::TOO_MANY_REQUESTS)),
+                "gpt-4-turbo" => Err(Box::new(io::Error::from(io::ErrorKind::TimedOut)) as _),
+                _ => Ok("Hello"),
+            }
+        })
+        .unwrap();
+        assert_eq!((model.as_str(), answer), ("gpt-3.5-turbo", "Hello"));
+        assert_eq!(tried.len(), 3);
+
+        // A bad key is not going to get better
+        let mut tried = 0;
+        let result: Result<(String, ()), _> = with_fallback(&models, |_| {
+            tried += 1;
+            Err(status_error(StatusCode::UNAUTHORIZED))
+        });
+        assert!(result.is_err());
+        assert_eq!(tried, 1);
+    }
+}
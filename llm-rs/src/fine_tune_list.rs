@@ -1,4 +1,3 @@
-use chrono::NaiveDateTime;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -76,7 +75,9 @@ impl fmt::Display for File {
             self.purpose,
             self.status,
             DateTime::<Utc>::from_naive_utc_and_offset(
-                NaiveDateTime::from_timestamp_opt(self.created_at, 0).unwrap(),
+                DateTime::from_timestamp(self.created_at, 0)
+                    .unwrap()
+                    .naive_utc(),
                 Utc,
             )
         )
@@ -103,7 +104,9 @@ impl fmt::Display for FineTuneList {
                     "Q {} {} {}",
                     x.id,
                     DateTime::<Utc>::from_naive_utc_and_offset(
-                        NaiveDateTime::from_timestamp_opt(x.created_at, 0).unwrap(),
+                        DateTime::from_timestamp(x.created_at, 0)
+                            .unwrap()
+                            .naive_utc(),
                         Utc,
                     ),
                     x.status
@@ -1,4 +1,3 @@
-use chrono::NaiveDateTime;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use std::fmt::{Display, Formatter, Result as FmtResult};
@@ -56,11 +55,11 @@ impl Display for FineTuneRetrieve {
             "object: {}\nid: {}\norganization_id: {}\nmodel: {}\ncreated_at: {}\nupdated_at: {}\nstatus: {}",
             self.object, self.id, self.organization_id, self.model,
             DateTime::<Utc>::from_naive_utc_and_offset(
-                NaiveDateTime::from_timestamp_opt(self.created_at, 0).unwrap(),
+                DateTime::from_timestamp(self.created_at, 0).unwrap().naive_utc(),
                 Utc,
             ),
             DateTime::<Utc>::from_naive_utc_and_offset(
-                NaiveDateTime::from_timestamp_opt(self.updated_at, 0).unwrap(),
+                DateTime::from_timestamp(self.updated_at, 0).unwrap().naive_utc(),
                 Utc,
             ), self.status
         )?;
@@ -107,7 +106,9 @@ impl Display for FineTuneRetrieve {
                 event.object,
                 event.message,
                 DateTime::<Utc>::from_naive_utc_and_offset(
-                    NaiveDateTime::from_timestamp_opt(event.created_at, 0).unwrap(),
+                    DateTime::from_timestamp(event.created_at, 0)
+                        .unwrap()
+                        .naive_utc(),
                     Utc,
                 )
             )?;
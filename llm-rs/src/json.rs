@@ -40,7 +40,7 @@ pub struct CompletionRequestInfo {
 /// Response for a chats request.  See
 /// https://platform.openai.com/docs/api-reference/chat/create
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
@@ -74,8 +74,34 @@ pub struct ChatRequestInfo {
     pub choices: Vec<ChatChoice>,
 }
 
-/// To facilitate testing the front end whithout bothering the LLM
 impl ChatRequestInfo {
+    /// Build a response for a reply that did not come from OpenAI,
+    /// so the rest of the system can treat all chat providers alike
+    pub fn new(
+        id: String,
+        model: String,
+        usage: Usage,
+        content: String,
+        finish_reason: String,
+    ) -> Self {
+        ChatRequestInfo {
+            id,
+            object: "chat.completion".to_string(),
+            created: chrono::Utc::now().timestamp() as u64,
+            model,
+            usage,
+            choices: vec![ChatChoice {
+                index: 0,
+                message: Message {
+                    role: "assistant".to_string(),
+                    content,
+                },
+                finish_reason,
+            }],
+        }
+    }
+
+    /// To facilitate testing the front end whithout bothering the LLM
     pub fn test_instance() -> Self {
         ChatRequestInfo {
             id: "TestID".to_string(),
@@ -99,6 +125,105 @@ impl ChatRequestInfo {
     }
 }
 
+/// Response from the Anthropic Messages API.  See
+/// https://docs.anthropic.com/claude/reference/messages_post
+#[derive(Debug, Deserialize)]
+pub struct AnthropicResponse {
+    pub id: String,
+    pub model: String,
+    pub content: Vec<AnthropicContent>,
+    pub stop_reason: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+/// A block of content in an Anthropic reply.  Only "text" blocks are used
+#[derive(Debug, Deserialize)]
+pub struct AnthropicContent {
+    #[serde(rename = "type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub text: String,
+}
+
+/// Anthropic counts tokens in and out, but not the total
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct AnthropicUsage {
+    #[serde(default)]
+    pub input_tokens: u32,
+    #[serde(default)]
+    pub output_tokens: u32,
+}
+
+impl From<AnthropicUsage> for Usage {
+    fn from(usage: AnthropicUsage) -> Self {
+        Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }
+    }
+}
+
+/// Anthropic reports failures in the body: {"type": "error", "error": {...}}
+#[derive(Debug, Deserialize)]
+pub struct AnthropicError {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+/// Response from Ollama's /api/chat.  See
+/// https://github.com/ollama/ollama/blob/main/docs/api.md
+#[derive(Debug, Deserialize)]
+pub struct OllamaChatResponse {
+    pub model: String,
+    pub message: Message,
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    /// Tokens in the prompt.  Absent if the prompt was cached
+    #[serde(default)]
+    pub prompt_eval_count: u32,
+    /// Tokens in the reply
+    #[serde(default)]
+    pub eval_count: u32,
+}
+
+/// Response from Ollama's /api/generate
+#[derive(Debug, Deserialize)]
+pub struct OllamaGenerateResponse {
+    pub model: String,
+    pub response: String,
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    #[serde(default)]
+    pub prompt_eval_count: u32,
+    #[serde(default)]
+    pub eval_count: u32,
+}
+
+/// The models installed locally.  From Ollama's /api/tags
+#[derive(Debug, Deserialize)]
+pub struct OllamaTags {
+    pub models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaModel {
+    pub name: String,
+    pub modified_at: String,
+    pub size: u64,
+}
+
+/// Ollama's /api/pull streams one of these per line
+#[derive(Debug, Deserialize)]
+pub struct OllamaPullStatus {
+    #[serde(default)]
+    pub status: String,
+    pub total: Option<u64>,
+    pub completed: Option<u64>,
+    pub error: Option<String>,
+}
+
 /// To receive the transcribed text
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AudioTranscriptionResponse {
@@ -213,11 +338,11 @@ pub struct ModelReturned {
 
 /// Response for a "models" query
 #[derive(Debug, Serialize, Deserialize)]
-struct ModelData {
-    id: String,
+pub struct ModelData {
+    pub id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct ModelRequestInfo {
-    data: Vec<ModelData>,
+pub struct ModelRequestInfo {
+    pub data: Vec<ModelData>,
 }
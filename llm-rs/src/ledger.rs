@@ -0,0 +1,422 @@
+//! The spending ledger.  Every charged request is a line of JSON in a
+//! file.  Lines are only ever added, with the file locked, so more
+//! than one programme can use it at once.  Spending per day and month,
+//! for budgets, is added up from it.
+//!
+//! Spending used to be one total in "shared_state.json".  The first
+//! time a ledger with a `carry_over` file is used that total becomes
+//! its first charge
+use crate::budget::Spending;
+use crate::json::Usage;
+use chrono::DateTime;
+use chrono::Local;
+use fs2::FileExt;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// The model of the charge carried over from "shared_state.json"
+const EARLIER: &str = "(before the ledger)";
+
+/// A charged request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Charge {
+    pub timestamp: DateTime<Local>,
+
+    /// The model that answered
+    pub model: String,
+
+    /// As `ModelMode` displays it
+    pub mode: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+
+    /// In cents
+    pub cost: f64,
+
+    /// The session in use
+    pub session: Option<String>,
+}
+
+impl Charge {
+    /// A charge made now
+    pub fn new(model: &str, mode: &str, cost: f64, session: Option<&str>) -> Self {
+        Self {
+            timestamp: Local::now(),
+            model: model.to_string(),
+            mode: mode.to_string(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cost,
+            session: session.map(|s| s.to_string()),
+        }
+    }
+
+    /// With the tokens in `usage`
+    pub fn with_usage(mut self, usage: &Usage) -> Self {
+        self.prompt_tokens = usage.prompt_tokens;
+        self.completion_tokens = usage.completion_tokens;
+        self
+    }
+}
+
+/// Keeps `Charge`s.  In a file, locked while it is used, or for this
+/// programme only
+#[derive(Debug, Default)]
+pub struct Ledger {
+    path: Option<PathBuf>,
+
+    /// The old "shared_state.json"
+    carry_over: Option<PathBuf>,
+
+    /// The charges when there is no file
+    charges: Mutex<Vec<Charge>>,
+}
+
+impl Ledger {
+    /// The ledger in the file at `path`
+    pub fn with_file(path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            ..Self::default()
+        }
+    }
+
+    /// Start a new ledger with the total spent in the
+    /// "shared_state.json" at `path`
+    pub fn carry_over(mut self, path: PathBuf) -> Self {
+        self.carry_over = Some(path);
+        self
+    }
+
+    /// Open the ledger, making it if there is none
+    fn open(&self, path: &Path) -> Result<File, Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            // New.  Checked again with the lock, in case another
+            // programme is making it too
+            file.lock_exclusive()?;
+            if file.metadata()?.len() == 0 {
+                if let Some(charge) = self.carry_over.as_deref().and_then(earlier_spending) {
+                    let mut line = serde_json::to_string(&charge)?;
+                    line.push('\n');
+                    (&file).write_all(line.as_bytes())?;
+                }
+            }
+            file.unlock()?;
+        }
+        Ok(file)
+    }
+
+    /// The charges in `file`.  Lines that cannot be read are left out
+    fn read(file: &File) -> Result<Vec<Charge>, Box<dyn Error>> {
+        let mut reader = BufReader::new(file);
+        reader.rewind()?;
+        let mut charges = vec![];
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Charge>(line.as_str()) {
+                Ok(charge) => charges.push(charge),
+                Err(err) => log::warn!("{err}: Ledger line {} left out", i + 1),
+            }
+        }
+        Ok(charges)
+    }
+
+    /// Add `charge`
+    pub fn record(&self, charge: &Charge) -> Result<(), Box<dyn Error>> {
+        let path = match self.path.as_deref() {
+            Some(path) => path,
+            None => {
+                self.charges.lock().unwrap().push(charge.clone());
+                return Ok(());
+            }
+        };
+        let file = self.open(path)?;
+        file.lock_exclusive()?;
+        let mut line = serde_json::to_string(charge)?;
+        line.push('\n');
+        (&file).write_all(line.as_bytes())?;
+        file.unlock()?;
+        Ok(())
+    }
+
+    /// The total spent, in cents
+    pub fn spent(&self) -> Result<f64, Box<dyn Error>> {
+        Ok(self.charges()?.iter().map(|c| c.cost).sum())
+    }
+
+    /// Spending in the current day and month.  The total carried over
+    /// was spent over days that are not known, so is left out
+    pub fn spending(&self) -> Result<Spending, Box<dyn Error>> {
+        let mut spending = Spending::default();
+        for charge in self.charges()?.iter().filter(|c| c.model != EARLIER) {
+            spending.add(charge.cost, charge.timestamp);
+        }
+        Ok(spending)
+    }
+
+    /// Every charge, oldest first
+    pub fn charges(&self) -> Result<Vec<Charge>, Box<dyn Error>> {
+        let path = match self.path.as_deref() {
+            Some(path) => path,
+            None => return Ok(self.charges.lock().unwrap().clone()),
+        };
+        let file = self.open(path)?;
+        file.lock_shared()?;
+        let charges = Self::read(&file);
+        file.unlock()?;
+        charges
+    }
+}
+
+/// The total in the "shared_state.json" at `path`, dated when it was
+/// last changed
+fn earlier_spending(path: &Path) -> Option<Charge> {
+    let contents = fs::read_to_string(path).ok()?;
+    let state: serde_json::Value = serde_json::from_str(contents.as_str()).ok()?;
+    let spent = state["spent"].as_f64().filter(|s| *s > 0.0)?;
+    let mut charge = Charge::new(EARLIER, "", spent, None);
+    if let Ok(modified) = fs::metadata(path).and_then(|m| m.modified()) {
+        charge.timestamp = modified.into();
+    }
+    Some(charge)
+}
+
+/// Spending on a day, with a model, or in a session
+#[derive(Debug, Clone, Default)]
+pub struct Total {
+    pub requests: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+
+    /// In cents
+    pub cost: f64,
+}
+
+impl Total {
+    fn add(&mut self, charge: &Charge) {
+        self.requests += 1;
+        self.prompt_tokens += charge.prompt_tokens as u64;
+        self.completion_tokens += charge.completion_tokens as u64;
+        self.cost += charge.cost;
+    }
+}
+
+impl fmt::Display for Total {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} requests {:>9} tokens in {:>9} out {:>10.2}",
+            self.requests, self.prompt_tokens, self.completion_tokens, self.cost
+        )
+    }
+}
+
+/// Spending by day, model and session
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// "YYYY-mm-dd"
+    pub by_day: BTreeMap<String, Total>,
+    pub by_model: BTreeMap<String, Total>,
+
+    /// "none" for no session
+    pub by_session: BTreeMap<String, Total>,
+    pub total: Total,
+}
+
+impl Report {
+    pub fn new(charges: &[Charge]) -> Self {
+        let mut report = Report::default();
+        for charge in charges {
+            let day = charge.timestamp.format("%Y-%m-%d").to_string();
+            report.by_day.entry(day).or_default().add(charge);
+            report
+                .by_model
+                .entry(charge.model.clone())
+                .or_default()
+                .add(charge);
+            let session = charge.session.clone().unwrap_or("none".to_string());
+            report.by_session.entry(session).or_default().add(charge);
+            report.total.add(charge);
+        }
+        report
+    }
+
+    /// "by,key,requests,prompt_tokens,completion_tokens,cost" with a
+    /// row for each day, model and session and one for the total
+    pub fn to_csv(&self) -> String {
+        let mut csv = "by,key,requests,prompt_tokens,completion_tokens,cost\n".to_string();
+        let mut row = |by: &str, key: &str, total: &Total| {
+            csv += format!(
+                "{by},{},{},{},{},{:.4}\n",
+                csv_field(key),
+                total.requests,
+                total.prompt_tokens,
+                total.completion_tokens,
+                total.cost
+            )
+            .as_str();
+        };
+        for (by, totals) in [
+            ("day", &self.by_day),
+            ("model", &self.by_model),
+            ("session", &self.by_session),
+        ] {
+            for (key, total) in totals.iter() {
+                row(by, key, total);
+            }
+        }
+        row("total", "", &self.total);
+        csv
+    }
+}
+
+impl fmt::Display for Report {
+    /// Costs are in cents
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.total.requests == 0 {
+            return write!(f, "Nothing spent");
+        }
+        let width = self
+            .by_day
+            .keys()
+            .chain(self.by_model.keys())
+            .chain(self.by_session.keys())
+            .map(|k| k.chars().count())
+            .max()
+            .unwrap_or(0);
+        for (heading, totals) in [
+            ("By day", &self.by_day),
+            ("By model", &self.by_model),
+            ("By session", &self.by_session),
+        ] {
+            writeln!(f, "{heading}")?;
+            for (key, total) in totals.iter() {
+                writeln!(f, "  {key:width$} {total}")?;
+            }
+        }
+        write!(f, "  {:width$} {}", "Total", self.total)
+    }
+}
+
+/// Quoted if it must be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn charge(day: u32, model: &str, cost: f64, session: Option<&str>) -> Charge {
+        let mut charge = Charge::new(model, "chat", cost, session);
+        charge.timestamp = Local.with_ymd_and_hms(2024, 5, day, 12, 0, 0).unwrap();
+        charge.prompt_tokens = 10;
+        charge.completion_tokens = 5;
+        charge
+    }
+
+    #[test]
+    fn records_and_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.jsonl");
+        let ledger = Ledger::with_file(path.clone());
+        assert_eq!(ledger.spent().unwrap(), 0.0);
+        ledger
+            .record(&charge(1, "gpt-4", 3.0, Some("work")))
+            .unwrap();
+        ledger
+            .record(&charge(1, "gpt-3.5-turbo", 0.5, None))
+            .unwrap();
+        ledger
+            .record(&charge(2, "gpt-4", 1.5, Some("a, \"b\"")))
+            .unwrap();
+
+        // Read again, as another programme would
+        let ledger = Ledger::with_file(path);
+        assert_eq!(ledger.spent().unwrap(), 5.0);
+        let report = Report::new(&ledger.charges().unwrap());
+        assert_eq!(report.by_day.len(), 2);
+        assert_eq!(report.by_day["2024-05-01"].requests, 2);
+        assert_eq!(report.by_day["2024-05-01"].cost, 3.5);
+        assert_eq!(report.by_model["gpt-4"].cost, 4.5);
+        assert_eq!(report.by_model["gpt-4"].prompt_tokens, 20);
+        assert_eq!(report.by_session["none"].cost, 0.5);
+        assert_eq!(report.by_session["work"].requests, 1);
+        assert_eq!(report.total.requests, 3);
+        assert_eq!(report.total.completion_tokens, 15);
+
+        assert_eq!(
+            report.to_csv(),
+            "by,key,requests,prompt_tokens,completion_tokens,cost\n\
+             day,2024-05-01,2,20,10,3.5000\n\
+             day,2024-05-02,1,10,5,1.5000\n\
+             model,gpt-3.5-turbo,1,10,5,0.5000\n\
+             model,gpt-4,2,20,10,4.5000\n\
+             session,\"a, \"\"b\"\"\",1,10,5,1.5000\n\
+             session,none,1,10,5,0.5000\n\
+             session,work,1,10,5,3.0000\n\
+             total,,3,30,15,5.0000\n"
+        );
+        assert_eq!(Report::new(&[]).to_string(), "Nothing spent");
+    }
+
+    #[test]
+    fn carries_over_shared_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared_state = dir.path().join("shared_state.json");
+        fs::write(shared_state.as_path(), r#"{"spent": 12.5}"#).unwrap();
+        let ledger =
+            Ledger::with_file(dir.path().join("ledger.jsonl")).carry_over(shared_state.clone());
+        assert_eq!(ledger.spent().unwrap(), 12.5);
+        ledger
+            .record(&Charge::new("gpt-4", "chat", 1.0, None))
+            .unwrap();
+
+        // Only once: the ledger is not new now
+        fs::write(shared_state.as_path(), r#"{"spent": 99.0}"#).unwrap();
+        let charges = ledger.charges().unwrap();
+        assert_eq!(charges.len(), 2);
+        assert_eq!(charges[0].model, EARLIER);
+        assert_eq!(ledger.spent().unwrap(), 13.5);
+
+        // When, over the days before, is not known
+        assert_eq!(ledger.spending().unwrap().today(Local::now()), 1.0);
+    }
+
+    #[test]
+    fn in_memory() {
+        let ledger = Ledger::default();
+        ledger.record(&charge(1, "gpt-4", 2.0, None)).unwrap();
+        assert_eq!(ledger.charges().unwrap().len(), 1);
+        assert_eq!(ledger.spent().unwrap(), 2.0);
+    }
+}
@@ -1,10 +1,33 @@
+pub mod anthropic_interface;
 pub mod api_error;
 pub mod api_result;
+pub mod azure;
+pub mod budget;
+pub mod cache;
+pub mod cancel;
+pub mod chatgpt_import;
+pub mod code_block;
+pub mod compare;
 pub mod context;
+pub mod conversation_tree;
+pub mod eval;
+pub mod export;
+pub mod fallback;
 pub mod fine_tune_create;
 pub mod fine_tune_list;
 pub mod fine_tune_retrieve;
 pub mod json;
+pub mod ledger;
 pub mod model_info;
 pub mod model_mode;
+pub mod model_registry;
+pub mod ollama_interface;
 pub mod openai_interface;
+pub mod patch;
+pub mod provider;
+pub mod replay;
+pub mod telemetry;
+pub mod template;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod transport;
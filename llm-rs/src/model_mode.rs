@@ -1,3 +1,4 @@
+use crate::model_registry::ModelRegistry;
 use std::str::FromStr;
 /// The modes for the interaction with Open AI
 #[derive(Debug, Clone, PartialEq)]
@@ -8,18 +9,6 @@ pub enum ModelMode {
     ImageEdit,
     AudioTranscription,
 }
-const MODELS_COMPLETIONS: [&str; 6] = [
-    "text-babbage-001",
-    "text-curie-001",
-    "text-davinci-001",
-    "text-davinci-002",
-    "text-davinci-003",
-    "text-davinci-edit-001",
-];
-const MODELS_CHAT: [&str; 2] = ["gpt-3.5-turbo", "gpt-4"];
-
-const MODELS_AUDIOTRANSCRIPTION: [&str; 1] = ["whisper-1"];
-
 impl std::fmt::Display for ModelMode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
@@ -51,13 +40,13 @@ impl FromStr for ModelMode {
 }
 
 impl ModelMode {
-    pub fn models_available(&self) -> Vec<&str> {
-        match self {
-            ModelMode::Completions => MODELS_COMPLETIONS.to_vec(),
-            ModelMode::Chat => MODELS_CHAT.to_vec(),
-            ModelMode::Image => [].to_vec(),
-            ModelMode::ImageEdit => [].to_vec(),
-            ModelMode::AudioTranscription => MODELS_AUDIOTRANSCRIPTION.to_vec(),
-        }
+    /// The well known models for this mode.  A `ModelRegistry` that
+    /// has asked the providers knows more
+    pub fn models_available(&self) -> Vec<String> {
+        ModelRegistry::builtin()
+            .models_for(self)
+            .iter()
+            .map(|m| m.id.clone())
+            .collect()
     }
 }
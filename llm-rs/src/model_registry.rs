@@ -0,0 +1,329 @@
+//! What models there are and what they can do.
+//!
+//! The registry starts with the capabilities of well known models,
+//! built in.  A capability file can add models or replace what is
+//! known about them.  It is JSON, the same as the built in
+//! capabilities in "models.json": model id => capabilities:
+//!
+//! ```json
+//! {
+//!   "gpt-4o": {
+//!     "name": "GPT-4o",
+//!     "modes": ["chat"],
+//!     "context_window": 128000,
+//!     "modalities": ["text", "image"],
+//!     "tools": true,
+//!     "pricing_tier": "medium"
+//!   }
+//! }
+//! ```
+//!
+//! Then the models a provider offers (OpenAI's "/models", Ollama's
+//! installed models) are merged in with `merge_listed`.  Models the
+//! provider did not list are marked unavailable.  Models that are not
+//! in the registry are added with capabilities guessed from the name
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::model_mode::ModelMode;
+use crate::provider::Provider;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The capabilities of well known models
+const BUILTIN_MODELS: &str = include_str!("models.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PricingTier {
+    Free,
+    Low,
+    Medium,
+    High,
+}
+
+impl fmt::Display for PricingTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PricingTier::Free => write!(f, "free"),
+            PricingTier::Low => write!(f, "low"),
+            PricingTier::Medium => write!(f, "medium"),
+            PricingTier::High => write!(f, "high"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    /// The name the provider uses.  The key in the capability file
+    #[serde(default)]
+    pub id: String,
+
+    /// For people
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The `ModelMode`s the model can be used in: "chat",
+    /// "completions", "image", "image_edit", "audio_transcription"
+    #[serde(default)]
+    pub modes: Vec<String>,
+
+    /// In tokens
+    #[serde(default)]
+    pub context_window: Option<u32>,
+
+    /// What it accepts: "text", "image", "audio"
+    #[serde(default)]
+    pub modalities: Vec<String>,
+
+    /// Can call tools (functions)
+    #[serde(default)]
+    pub tools: bool,
+
+    #[serde(default)]
+    pub pricing_tier: Option<PricingTier>,
+
+    /// `Some(false)` if the provider was asked and did not list it.
+    /// `None` if the provider has not been asked
+    #[serde(skip)]
+    pub available: Option<bool>,
+}
+
+impl ModelCapabilities {
+    /// Capabilities for a model that is not in the registry, guessed
+    /// from its name.  Models that are not for text, images or audio
+    /// transcription (embeddings, moderation, text to speech...) get
+    /// no modes
+    pub fn guess(id: &str) -> Self {
+        let modes: &[&str] = if Provider::from_model(id) == Provider::Ollama {
+            &["chat", "completions"]
+        } else if id.contains("whisper") {
+            &["audio_transcription"]
+        } else if id.starts_with("dall-e") {
+            &["image", "image_edit"]
+        } else if id.contains("instruct") || id.contains("davinci") || id.contains("babbage") {
+            &["completions"]
+        } else if id.contains("embedding")
+            || id.contains("moderation")
+            || id.contains("tts")
+            || id.contains("realtime")
+        {
+            &[]
+        } else {
+            &["chat"]
+        };
+        Self {
+            id: id.to_string(),
+            name: None,
+            modes: modes.iter().map(|m| m.to_string()).collect(),
+            context_window: None,
+            modalities: vec![],
+            tools: false,
+            pricing_tier: match Provider::from_model(id) {
+                Provider::Ollama => Some(PricingTier::Free),
+                _ => None,
+            },
+            available: None,
+        }
+    }
+
+    /// The name for people: `name` if there is one
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.id.as_str())
+    }
+
+    pub fn supports(&self, mode: &ModelMode) -> bool {
+        let mode = mode.to_string();
+        self.modes.contains(&mode)
+    }
+}
+
+impl fmt::Display for ModelCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(name) = self.name.as_ref() {
+            write!(f, " ({name})")?;
+        }
+        if let Some(context_window) = self.context_window {
+            write!(f, " Context: {context_window}")?;
+        }
+        if !self.modalities.is_empty() {
+            write!(f, " Input: {}", self.modalities.join("/"))?;
+        }
+        if self.tools {
+            write!(f, " Tools")?;
+        }
+        if let Some(pricing_tier) = self.pricing_tier {
+            write!(f, " Price: {pricing_tier}")?;
+        }
+        if self.available == Some(false) {
+            write!(f, " [Unavailable]")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ModelRegistry {
+    models: BTreeMap<String, ModelCapabilities>,
+}
+
+impl ModelRegistry {
+    /// The registry of well known models
+    pub fn builtin() -> Self {
+        Self::from_json(BUILTIN_MODELS).expect("Built in model capabilities")
+    }
+
+    /// A registry from JSON: model id => capabilities
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn Error>> {
+        let models: HashMap<String, ModelCapabilities> = serde_json::from_str(json)?;
+        Ok(Self {
+            models: models
+                .into_iter()
+                .map(|(id, mut model)| {
+                    model.id = id.clone();
+                    (id, model)
+                })
+                .collect(),
+        })
+    }
+
+    /// Add the models in the capability file at `path`.  They
+    /// replace any models with the same id
+    pub fn load_file(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            ApiError::new(
+                ApiErrorType::Error(format!("{err}: Cannot read {}", path.display())),
+                HashMap::new(),
+            )
+        })?;
+        self.models
+            .extend(Self::from_json(contents.as_str())?.models);
+        Ok(())
+    }
+
+    /// `listed` are the models `provider` offers.  They are
+    /// available, and added if they are unknown.  Other models from
+    /// `provider` are not available
+    pub fn merge_listed(&mut self, provider: Provider, listed: &[String]) {
+        for model in self
+            .models
+            .values_mut()
+            .filter(|m| Provider::from_model(m.id.as_str()) == provider)
+        {
+            model.available = Some(false);
+        }
+        for id in listed.iter() {
+            self.models
+                .entry(id.clone())
+                .or_insert_with(|| ModelCapabilities::guess(id))
+                .available = Some(true);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModelCapabilities> {
+        self.models.get(id)
+    }
+
+    /// All the models, sorted by id
+    pub fn models(&self) -> impl Iterator<Item = &ModelCapabilities> {
+        self.models.values()
+    }
+
+    /// The models that can be used in `mode`, less those known to be
+    /// unavailable
+    pub fn models_for(&self, mode: &ModelMode) -> Vec<&ModelCapabilities> {
+        self.models
+            .values()
+            .filter(|m| m.available != Some(false) && m.supports(mode))
+            .collect()
+    }
+
+    /// If `model` can be used in `mode`.  `None` if the model is not
+    /// known
+    pub fn supports(&self, model: &str, mode: &ModelMode) -> Option<bool> {
+        self.get(model).map(|m| m.supports(mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_covers_every_mode() {
+        let registry = ModelRegistry::builtin();
+        for mode in [
+            ModelMode::Completions,
+            ModelMode::Chat,
+            ModelMode::Image,
+            ModelMode::ImageEdit,
+            ModelMode::AudioTranscription,
+        ] {
+            assert!(!registry.models_for(&mode).is_empty(), "{mode}");
+        }
+        assert_eq!(
+            registry.supports("whisper-1", &ModelMode::AudioTranscription),
+            Some(true)
+        );
+        assert_eq!(
+            registry.supports("gpt-4", &ModelMode::Completions),
+            Some(false)
+        );
+        assert_eq!(registry.supports("no-such-model", &ModelMode::Chat), None);
+    }
+
+    #[test]
+    fn listed_models_are_merged() {
+        let mut registry = ModelRegistry::builtin();
+        registry.merge_listed(
+            Provider::OpenAi,
+            &[
+                "gpt-4".to_string(),
+                "gpt-5".to_string(),
+                "text-embedding-3-small".to_string(),
+            ],
+        );
+        registry.merge_listed(Provider::Ollama, &["ollama/llama3".to_string()]);
+        let chat: Vec<&str> = registry
+            .models_for(&ModelMode::Chat)
+            .iter()
+            .map(|m| m.id.as_str())
+            .collect();
+        assert!(chat.contains(&"gpt-4"));
+        assert!(chat.contains(&"gpt-5"));
+        assert!(chat.contains(&"ollama/llama3"));
+        // Not listed by OpenAI
+        assert!(!chat.contains(&"gpt-3.5-turbo"));
+        // Anthropic was not asked
+        assert!(chat.contains(&"claude-3-opus-20240229"));
+        assert!(!chat.contains(&"text-embedding-3-small"));
+        assert_eq!(
+            registry.get("ollama/llama3").unwrap().pricing_tier,
+            Some(PricingTier::Free)
+        );
+    }
+
+    #[test]
+    fn capability_file_replaces_models() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("models.json");
+        fs::write(
+            path.as_path(),
+            r#"{"gpt-4": {"modes": ["chat"], "context_window": 32768}, "local-model": {"modes": ["completions"]}}"#,
+        )
+        .unwrap();
+        let mut registry = ModelRegistry::builtin();
+        registry.load_file(path.as_path()).unwrap();
+        assert_eq!(registry.get("gpt-4").unwrap().context_window, Some(32768));
+        assert_eq!(
+            registry.supports("local-model", &ModelMode::Completions),
+            Some(true)
+        );
+    }
+}
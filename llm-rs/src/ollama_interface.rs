@@ -0,0 +1,364 @@
+//! Talk to models running locally in [Ollama](https://ollama.com)
+//! using its native API.  Documented
+//! [here](https://github.com/ollama/ollama/blob/main/docs/api.md)
+//!
+//! Local models are named "ollama/<name>" in `llm-rs` so they can be
+//! told apart from the hosted models
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::json::ChatRequestInfo;
+use crate::json::Message;
+use crate::json::OllamaChatResponse;
+use crate::json::OllamaGenerateResponse;
+use crate::json::OllamaPullStatus;
+use crate::json::OllamaTags;
+use crate::json::Usage;
+use crate::openai_interface::ApiInterface;
+use crate::transport::ChunkHandler;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::Transport;
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Where Ollama listens if `OLLAMA_HOST` is not set
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Local models are named with this prefix
+pub const OLLAMA_PREFIX: &str = "ollama/";
+
+#[derive(Debug)]
+pub struct OllamaInterface {
+    transport: Arc<dyn Transport>,
+
+    /// The base URL of the Ollama server
+    pub url: String,
+}
+
+impl OllamaInterface {
+    /// `url` can be as `OLLAMA_HOST` is often set, with no scheme:
+    /// "127.0.0.1:11434" is "http://127.0.0.1:11434"
+    pub fn new(url: &str, transport: Arc<dyn Transport>) -> Self {
+        let url = url.trim_end_matches('/');
+        Self {
+            transport,
+            url: if url.contains("://") {
+                url.to_string()
+            } else {
+                format!("http://{url}")
+            },
+        }
+    }
+
+    /// The name Ollama knows a model by: "ollama/llama3" => "llama3"
+    pub fn local_name(model: &str) -> &str {
+        model.strip_prefix(OLLAMA_PREFIX).unwrap_or(model)
+    }
+
+    /// Chat with a local model.  The reply is translated into a
+    /// `ChatRequestInfo` so it can be treated like any other chat
+    pub fn chat(
+        &self,
+        model: &str,
+        messages: &[Message],
+        temperature: f32,
+        max_tokens: u32,
+    ) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+        // Ollama does not want the empty system prompt that starts
+        // a conversation
+        let messages: Vec<&Message> = messages
+            .iter()
+            .filter(|m| m.role != "system" || !m.content.is_empty())
+            .collect();
+        let data = json!({
+            "model": Self::local_name(model),
+            "messages": messages,
+            "stream": false,
+            "options": {
+                "temperature": temperature,
+                "num_predict": max_tokens,
+            },
+        });
+        let response = self.post("chat", data, None)?;
+        let headers = response.headers.clone();
+        let response: OllamaChatResponse = response.json()?;
+        let usage = Self::usage(response.prompt_eval_count, response.eval_count);
+        let json = ChatRequestInfo::new(
+            String::new(),
+            format!("{OLLAMA_PREFIX}{}", response.model),
+            usage,
+            response.message.content,
+            response.done_reason.unwrap_or_default(),
+        );
+        Ok((headers, json))
+    }
+
+    /// Complete `prompt` with no context
+    pub fn generate(
+        &self,
+        model: &str,
+        prompt: &str,
+        temperature: f32,
+        max_tokens: u32,
+    ) -> Result<(HashMap<String, String>, String), Box<dyn Error>> {
+        let data = json!({
+            "model": Self::local_name(model),
+            "prompt": prompt,
+            "stream": false,
+            "options": {
+                "temperature": temperature,
+                "num_predict": max_tokens,
+            },
+        });
+        let response = self.post("generate", data, None)?;
+        let mut headers = response.headers.clone();
+        let response: OllamaGenerateResponse = response.json()?;
+        headers.extend(ApiInterface::usage_headers(Self::usage(
+            response.prompt_eval_count,
+            response.eval_count,
+        )));
+        Ok((headers, response.response))
+    }
+
+    /// The models installed locally, with the "ollama/" prefix
+    pub fn models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self
+            .tags()?
+            .models
+            .iter()
+            .map(|m| format!("{OLLAMA_PREFIX}{}", m.name))
+            .collect())
+    }
+
+    /// All the information Ollama has about the installed models
+    pub fn tags(&self) -> Result<OllamaTags, Box<dyn Error>> {
+        let uri = format!("{}/api/tags", self.url);
+        let response =
+            Self::check_status(self.transport.send(&HttpRequest::get(uri.as_str()), None)?)?;
+        Ok(response.json()?)
+    }
+
+    /// Download `model` to the Ollama server.  Ollama reports progress
+    /// as it goes.  Each report is passed to `on_progress`
+    pub fn pull(
+        &self,
+        model: &str,
+        on_progress: &mut dyn FnMut(&OllamaPullStatus),
+    ) -> Result<(), Box<dyn Error>> {
+        let data = json!({
+            "name": Self::local_name(model),
+            "stream": true,
+        });
+        // The reports are JSON objects, one per line.  Lines can be
+        // split over chunks, so buffer them
+        let mut line_buffer: Vec<u8> = vec![];
+        let mut error: Option<String> = None;
+        let mut on_chunk = |chunk: &[u8]| {
+            line_buffer.extend_from_slice(chunk);
+            while let Some(pos) = line_buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = line_buffer.drain(..=pos).collect();
+                Self::pull_report(&line, &mut error, on_progress);
+            }
+        };
+        let response = self.post("pull", data, Some(&mut on_chunk))?;
+
+        // The last report may have no new line after it
+        Self::pull_report(&line_buffer, &mut error, on_progress);
+        if let Some(error) = error {
+            return Err(Box::new(ApiError::new(
+                ApiErrorType::FailedRequest(error),
+                response.headers,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Pass the report of a pull in `line` to `on_progress`, or
+    /// keep the error in it.  Nothing is reported after an error
+    fn pull_report(
+        line: &[u8],
+        error: &mut Option<String>,
+        on_progress: &mut dyn FnMut(&OllamaPullStatus),
+    ) {
+        if error.is_some() || line.iter().all(|b| b.is_ascii_whitespace()) {
+            return;
+        }
+        match serde_json::from_slice::<OllamaPullStatus>(line) {
+            Ok(status) => match status.error.as_ref() {
+                Some(e) => *error = Some(e.clone()),
+                None => on_progress(&status),
+            },
+            Err(err) => *error = Some(format!("{err}")),
+        }
+    }
+
+    fn post(
+        &self,
+        endpoint: &str,
+        data: serde_json::Value,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let uri = format!("{}/api/{endpoint}", self.url);
+        let response = self
+            .transport
+            .send(&HttpRequest::post_json(uri.as_str(), data), on_chunk)?;
+        Self::check_status(response)
+    }
+
+    /// Ollama sends errors as {"error": "..."} with a failing status
+    fn check_status(response: HttpResponse) -> Result<HttpResponse, Box<dyn Error>> {
+        if response.status.is_success() {
+            return Ok(response);
+        }
+        let reason = match response.json::<serde_json::Value>() {
+            Ok(v) => v["error"].as_str().unwrap_or_default().to_string(),
+            Err(_) => response.reason(),
+        };
+        Err(Box::new(ApiError::new(
+            ApiErrorType::Status(response.status, reason),
+            response.headers,
+        )))
+    }
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::FakeOllama;
+    use crate::transport::Body;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn url_without_scheme() {
+        let transport = Arc::new(FakeOllama::default());
+        let url = |url: &str| OllamaInterface::new(url, transport.clone()).url;
+        assert_eq!(url("127.0.0.1:11434"), "http://127.0.0.1:11434");
+        assert_eq!(url("https://gpu-box:11434/"), "https://gpu-box:11434");
+        assert_eq!(url(DEFAULT_OLLAMA_URL), DEFAULT_OLLAMA_URL);
+    }
+
+    #[test]
+    fn chat_request_and_reply() {
+        let transport = Arc::new(FakeOllama::default());
+        let ollama = OllamaInterface::new("127.0.0.1:11434", transport.clone());
+        let messages = [
+            Message {
+                role: "system".to_string(),
+                content: String::new(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+            },
+        ];
+        let (_, reply) = ollama.chat("ollama/llama3", &messages, 0.5, 100).unwrap();
+        assert_eq!(reply.model, "ollama/llama3");
+        assert_eq!(reply.choices[0].message.content, "Hello");
+        assert_eq!(reply.choices[0].finish_reason, "stop");
+        assert_eq!(reply.usage.prompt_tokens, 10);
+        assert_eq!(reply.usage.total_tokens, 15);
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests[0].uri, "http://127.0.0.1:11434/api/chat");
+        match &requests[0].body {
+            Body::Json(data) => assert_eq!(
+                *data,
+                json!({
+                    "model": "llama3",
+                    "messages": [{"role": "user", "content": "Hi"}],
+                    "stream": false,
+                    "options": {"temperature": 0.5, "num_predict": 100},
+                })
+            ),
+            body => panic!("Not JSON: {body:?}"),
+        }
+    }
+
+    #[test]
+    fn generate_and_models() {
+        let transport = Arc::new(FakeOllama::default());
+        let ollama = OllamaInterface::new(DEFAULT_OLLAMA_URL, transport);
+        let (headers, text) = ollama.generate("ollama/llama3", "Once", 0.5, 10).unwrap();
+        assert_eq!(text, "Once upon a time");
+        assert_eq!(
+            headers.get("Tokens completion").map(|t| t.as_str()),
+            Some("4")
+        );
+        assert_eq!(
+            ollama.models().unwrap(),
+            vec!["ollama/llama3:latest", "ollama/mistral:7b"]
+        );
+    }
+
+    #[test]
+    fn pull_reports_progress() {
+        let transport = Arc::new(FakeOllama::default());
+        let ollama = OllamaInterface::new(DEFAULT_OLLAMA_URL, transport.clone());
+        let mut statuses: Vec<(String, Option<u64>)> = vec![];
+        ollama
+            .pull("ollama/llama3", &mut |s| {
+                statuses.push((s.status.clone(), s.completed))
+            })
+            .unwrap();
+        assert_eq!(
+            statuses,
+            vec![
+                ("pulling manifest".to_string(), None),
+                ("downloading".to_string(), Some(50)),
+                ("success".to_string(), None),
+            ]
+        );
+        match &transport.requests.lock().unwrap()[0].body {
+            Body::Json(data) => assert_eq!(*data, json!({"name": "llama3", "stream": true})),
+            body => panic!("Not JSON: {body:?}"),
+        }
+
+        let transport = Arc::new(FakeOllama {
+            pull_error: Some("pull model manifest: file does not exist".to_string()),
+            ..Default::default()
+        });
+        let ollama = OllamaInterface::new(DEFAULT_OLLAMA_URL, transport);
+        let mut reports = 0;
+        let err = ollama
+            .pull("ollama/nothing", &mut |_| reports += 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("file does not exist"));
+        assert_eq!(reports, 2);
+
+        // The last report is not lost for want of a new line
+        let transport = Arc::new(FakeOllama {
+            unterminated: true,
+            ..Default::default()
+        });
+        let ollama = OllamaInterface::new(DEFAULT_OLLAMA_URL, transport);
+        let mut last = String::new();
+        ollama
+            .pull("ollama/llama3", &mut |s| last = s.status.clone())
+            .unwrap();
+        assert_eq!(last, "success");
+    }
+
+    #[test]
+    fn errors_from_ollama() {
+        let transport = Arc::new(FakeOllama::default());
+        let ollama = OllamaInterface::new(DEFAULT_OLLAMA_URL, transport);
+        let err = ollama.post("show", json!({}), None).unwrap_err();
+        match err.downcast_ref::<ApiError>().map(|e| &e.error_type) {
+            Some(ApiErrorType::Status(status, reason)) => {
+                assert_eq!(*status, StatusCode::NOT_FOUND);
+                assert_eq!(reason, "model 'nothing' not found");
+            }
+            _ => panic!("Not a status error: {err}"),
+        }
+    }
+}
@@ -1,7 +1,20 @@
+use crate::anthropic_interface;
 use crate::api_error::ApiError;
 use crate::api_error::ApiErrorType;
 use crate::api_result::ApiResult;
+use crate::azure;
+use crate::azure::AzureConfig;
+use crate::budget;
+use crate::budget::Budget;
+use crate::budget::Spending;
+use crate::cache::CacheConfig;
+use crate::cache::CachingTransport;
+use crate::cache::ResponseCache;
+use crate::cache::CACHE_HEADER;
+use crate::cancel::CancellableTransport;
+use crate::cancel::CancellationToken;
 use crate::context::Context;
+use crate::fallback;
 use crate::fine_tune_create::FineTuneCreate;
 use crate::fine_tune_list::FineTuneList;
 use crate::fine_tune_retrieve::FineTuneRetrieve;
@@ -14,26 +27,39 @@ use crate::json::FileUploadResponse;
 use crate::json::Files;
 use crate::json::ImageRequestInfo;
 use crate::json::Message;
+use crate::json::OllamaPullStatus;
+use crate::json::OllamaTags;
 use crate::json::Usage;
+use crate::ledger::Charge;
+use crate::ledger::Ledger;
 use crate::model_info::ModelInfo;
-use chrono::{NaiveDateTime, TimeZone, Utc};
-use curl::easy::Easy;
-use curl::easy::List;
-use reqwest::blocking::multipart;
-use reqwest::blocking::Client;
-use reqwest::blocking::ClientBuilder;
-use reqwest::blocking::RequestBuilder;
-use reqwest::header::HeaderMap;
-use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
-use reqwest::StatusCode;
+use crate::model_registry::ModelRegistry;
+use crate::ollama_interface::OllamaInterface;
+use crate::ollama_interface::DEFAULT_OLLAMA_URL;
+use crate::provider::Provider;
+use crate::replay::RecordingTransport;
+use crate::replay::ReplayTransport;
+use crate::telemetry::ObservedTransport;
+use crate::telemetry::Telemetry;
+use crate::transport::ChunkHandler;
+use crate::transport::FormPart;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::HttpTransport;
+use crate::transport::Transport;
+use crate::transport::TransportConfig;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::de::DeserializeOwned;
 use serde_json::json;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
-use std::io::Read;
 use std::path::Path;
+use std::path::PathBuf;
 use std::result::Result;
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::Instant;
 
 // URLS:
@@ -62,14 +88,42 @@ use std::time::Instant;
 /// Bas URI for requests
 const API_URL: &str = "https://api.openai.com/v1";
 
+/// The model that transcribes audio.  In Azure mode it needs a
+/// deployment
+const AUDIO_MODEL: &str = "whisper-1";
+
+/// The model that makes images.  It is not sent to OpenAI, which
+/// uses its default, but in Azure mode it needs a deployment
+const IMAGE_MODEL: &str = "dall-e-2";
+
 #[derive(Debug)]
 pub struct ApiInterface {
-    /// Handles the communications with OpenAI
-    client: Client,
+    /// Handles the communications with all the providers
+    transport: Arc<dyn Transport>,
+
+    /// Every charged request.  Spending per day and month is added up
+    /// from it
+    ledger: Ledger,
+
+    /// The outermost layer of `transport`.  Attaches the token set by
+    /// `cancel_with`
+    cancellable: Arc<CancellableTransport>,
 
     /// The secret key from OpenAI
     api_key: String,
 
+    /// The secret key from Anthropic.  Needed for "claude-*" models
+    anthropic_api_key: Option<String>,
+
+    /// Local models, "ollama/*"
+    ollama: OllamaInterface,
+
+    /// If set OpenAI requests go to Azure OpenAI deployments
+    azure: Option<AzureConfig>,
+
+    /// If set responses to chats and completions are cached
+    cache: Option<Arc<ResponseCache>>,
+
     /// Restricts the amount of text returned
     pub tokens: u32,
 
@@ -78,6 +132,19 @@ pub struct ApiInterface {
 
     /// Chat keeps its state here.
     pub context: Context,
+
+    /// Models to try, in order, if the chat model is rate limited,
+    /// overloaded or times out
+    pub fallback: Vec<String>,
+
+    /// Chats that could cost more than this allows are refused
+    pub budget: Budget,
+
+    /// The session charges are recorded against
+    pub session: Option<String>,
+
+    /// The mode chats are recorded in the ledger as
+    pub(crate) charge_mode: &'static str,
 }
 
 impl Display for ApiInterface {
@@ -88,67 +155,334 @@ impl Display for ApiInterface {
 		     Tokens: {}\n\
 		     Context length: {}\n\
 		     Context cost: {:2}\n\
-		     System prompt: {}",
+		     System prompt: {}\n\
+		     Azure: {}\n\
+		     Fallback: {}\n\
+		     Budget: {}",
             self.temperature,
             self.tokens,
             self.context.len(),
             self.context.cost,
             self.context.purpose,
+            self.azure
+                .as_ref()
+                .map_or("No".to_string(), |a| a.endpoint.clone()),
+            self.fallback.join(" "),
+            self.budget,
         )
     }
 }
 
-impl ApiInterface {
-    pub fn new(api_key: String, tokens: u32, temperature: f32) -> ApiInterface {
-        ApiInterface {
-            client: ClientBuilder::new()
-                .timeout(std::time::Duration::from_secs(1200))
-                .pool_idle_timeout(None)
-                .connection_verbose(false)
-                .build()
-                .unwrap(),
+/// Builds an `ApiInterface`.  Configures the network: proxy, root
+/// certificates, time outs and extra headers.  Or supply a
+/// `Transport` to use instead
+pub struct ApiInterfaceBuilder {
+    api_key: String,
+    tokens: u32,
+    temperature: f32,
+    transport_config: TransportConfig,
+    transport: Option<Arc<dyn Transport>>,
+    telemetry_file: Option<PathBuf>,
+    cache: Option<CacheConfig>,
+    record_file: Option<PathBuf>,
+    replay_file: Option<PathBuf>,
+    ledger: Ledger,
+}
+
+impl ApiInterfaceBuilder {
+    pub fn new(api_key: String, tokens: u32, temperature: f32) -> Self {
+        Self {
             api_key,
             tokens,
             temperature,
-            // model: model.to_string(),
+            transport_config: TransportConfig::default(),
+            transport: None,
+            telemetry_file: None,
+            cache: None,
+            record_file: None,
+            replay_file: None,
+            ledger: Ledger::default(),
+        }
+    }
+
+    /// Send all requests through the proxy at `url`
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.transport_config.proxy = Some(url.to_string());
+        self
+    }
+
+    /// Trust the root certificates in the PEM file at `path`, as well
+    /// as the system's
+    pub fn root_certificate(mut self, path: PathBuf) -> Self {
+        self.transport_config.root_certificates.push(path);
+        self
+    }
+
+    /// How long to wait for a connection
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.transport_config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait for each read of a response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.transport_config.timeout = Some(timeout);
+        self
+    }
+
+    /// Send this header with every request
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.transport_config
+            .headers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Use `transport` for all requests.  The network settings above
+    /// are ignored
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// As well as logging them, append a `RequestEvent` for every
+    /// request to the file at `path`
+    pub fn telemetry_file(mut self, path: PathBuf) -> Self {
+        self.telemetry_file = Some(path);
+        self
+    }
+
+    /// Cache responses to chats and completions on disk.  Cached
+    /// responses are not sent, so are not reported by telemetry
+    pub fn cache(mut self, config: CacheConfig) -> Self {
+        self.cache = Some(config);
+        self
+    }
+
+    /// Record every response in the file at `path`, to replay later
+    pub fn record_file(mut self, path: PathBuf) -> Self {
+        self.record_file = Some(path);
+        self
+    }
+
+    /// Answer requests from the recording at `path`.  Nothing is sent
+    pub fn replay_file(mut self, path: PathBuf) -> Self {
+        self.replay_file = Some(path);
+        self
+    }
+
+    /// Record charges in `ledger`, so budgets apply across runs if
+    /// it has a file.  Otherwise they are kept in memory
+    pub fn ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = ledger;
+        self
+    }
+
+    /// The transport `build` uses, with recording and telemetry but
+    /// not the cache, for those that send requests without an
+    /// `ApiInterface`
+    pub fn build_transport(&self) -> Result<Arc<dyn Transport>, Box<dyn Error>> {
+        let mut transport: Arc<dyn Transport> = match (&self.transport, &self.replay_file) {
+            (Some(transport), _) => transport.clone(),
+            (None, Some(path)) => Arc::new(ReplayTransport::from_file(path.as_path())?),
+            (None, None) => Arc::new(HttpTransport::new(&self.transport_config)?),
+        };
+        if let Some(path) = self.record_file.as_deref() {
+            transport = Arc::new(RecordingTransport::new(transport, path)?);
+        }
+        let telemetry = match self.telemetry_file.as_deref() {
+            Some(path) => Telemetry::with_file(path)?,
+            None => Telemetry::default(),
+        };
+        Ok(Arc::new(ObservedTransport::new(transport, telemetry)))
+    }
+
+    pub fn build(self) -> Result<ApiInterface, Box<dyn Error>> {
+        let mut transport = self.build_transport()?;
+        let cache = match self.cache {
+            Some(config) => {
+                let cache = Arc::new(ResponseCache::new(config)?);
+                transport = Arc::new(CachingTransport::new(transport, cache.clone()));
+                Some(cache)
+            }
+            None => None,
+        };
+        let cancellable = Arc::new(CancellableTransport::new(transport, None));
+        let transport: Arc<dyn Transport> = cancellable.clone();
+        Ok(ApiInterface {
+            ollama: OllamaInterface::new(
+                std::env::var("OLLAMA_HOST")
+                    .unwrap_or(DEFAULT_OLLAMA_URL.to_string())
+                    .as_str(),
+                transport.clone(),
+            ),
+            transport,
+            cancellable,
+            ledger: self.ledger,
+            session: None,
+            charge_mode: "chat",
+            api_key: self.api_key,
+            anthropic_api_key: None,
+            azure: None,
+            cache,
+            tokens: self.tokens,
+            temperature: self.temperature,
             context: Context::new(""),
+            fallback: vec![],
+            budget: Budget::default(),
+        })
+    }
+}
+
+impl ApiInterface {
+    /// An interface with the default network settings
+    pub fn new(api_key: String, tokens: u32, temperature: f32) -> ApiInterface {
+        ApiInterfaceBuilder::new(api_key, tokens, temperature)
+            .build()
+            .expect("Default transport")
+    }
+
+    pub fn builder(api_key: String, tokens: u32, temperature: f32) -> ApiInterfaceBuilder {
+        ApiInterfaceBuilder::new(api_key, tokens, temperature)
+    }
+
+    /// The transport all requests go through
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.transport.clone()
+    }
+
+    /// Requests made from now on, by any method, are abandoned when
+    /// `token` is cancelled.  `None` and they run to the end
+    pub fn cancel_with(&self, token: Option<CancellationToken>) {
+        self.cancellable.set_token(token);
+    }
+
+    /// The response cache, if there is one
+    pub fn cache(&self) -> Option<Arc<ResponseCache>> {
+        self.cache.clone()
+    }
+
+    /// Set the key used for OpenAI models
+    pub fn set_api_key(&mut self, api_key: String) {
+        self.api_key = api_key;
+    }
+
+    /// Set the key used for Anthropic models
+    pub fn set_anthropic_api_key(&mut self, api_key: String) {
+        self.anthropic_api_key = Some(api_key);
+    }
+
+    /// Set where the Ollama server is for "ollama/*" models
+    pub fn set_ollama_url(&mut self, url: &str) {
+        self.ollama = OllamaInterface::new(url, self.transport.clone());
+    }
+
+    /// Send OpenAI requests to Azure OpenAI.  Models are mapped to
+    /// deployments by `config`
+    pub fn set_azure(&mut self, config: AzureConfig) {
+        self.azure = Some(config);
+    }
+
+    /// The Azure configuration, if in Azure mode
+    pub fn azure(&self) -> Option<&AzureConfig> {
+        self.azure.as_ref()
+    }
+
+    /// The models installed in Ollama, named "ollama/<name>"
+    pub fn local_models(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        self.ollama.models()
+    }
+
+    /// All the information Ollama has about the installed models
+    pub fn local_model_info(&self) -> Result<OllamaTags, Box<dyn Error>> {
+        self.ollama.tags()
+    }
+
+    /// Have Ollama download a model.  Progress reports are passed to
+    /// `on_progress`
+    pub fn pull_model(
+        &self,
+        model: &str,
+        on_progress: &mut dyn FnMut(&OllamaPullStatus),
+    ) -> Result<(), Box<dyn Error>> {
+        self.ollama.pull(model, on_progress)
+    }
+
+    fn get_anthropic_api_key(&self) -> Result<&str, Box<dyn Error>> {
+        match self.anthropic_api_key.as_deref() {
+            Some(key) => Ok(key),
+            None => Err(Box::new(ApiError::new(
+                ApiErrorType::Error("No Anthropic API key".to_string()),
+                HashMap::new(),
+            ))),
+        }
+    }
+
+    /// The authentication header for OpenAI requests: (name, value).
+    /// Azure uses "api-key" in place of a bearer token
+    fn auth_header(&self) -> (&'static str, String) {
+        match self.azure.as_ref() {
+            Some(azure) => ("api-key", azure.api_key.clone()),
+            None => ("Authorization", format!("Bearer {}", self.api_key)),
+        }
+    }
+
+    /// Send an OpenAI request, with authentication
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
+        let (name, value) = self.auth_header();
+        self.transport
+            .send(&request.header(name, value.as_str()), None)
+    }
+
+    /// As `send` but a failed status is an error
+    fn send_ok(&self, request: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
+        let response = self.send(request)?;
+        if !response.status.is_success() {
+            return Err(Box::new(ApiError::new(
+                ApiErrorType::Status(response.status, response.reason()),
+                response.headers,
+            )));
+        }
+        Ok(response)
+    }
+
+    /// The URI for an OpenAI `operation` that is not specific to a
+    /// model: "files", "models"...
+    fn uri(&self, operation: &str) -> String {
+        match self.azure.as_ref() {
+            Some(azure) => azure.uri(operation),
+            None => format!("{API_URL}/{operation}"),
+        }
+    }
+
+    /// The URI for an OpenAI `operation` using `model`.  In Azure
+    /// mode the model must have a deployment
+    fn model_uri(&self, model: &str, operation: &str) -> Result<String, Box<dyn Error>> {
+        match self.azure.as_ref() {
+            Some(azure) => azure.deployment_uri(model, operation),
+            None => Ok(format!("{API_URL}/{operation}")),
         }
     }
 
     /// Get information about a file
     pub fn file_info(&self, file_id: String) -> Result<ApiResult<String>, Box<dyn Error>> {
         // GET https://api.openai.com/v1/files/{file_id}
-        let uri = format!("{API_URL}/files/{file_id}");
-        let response = self
-            .client
-            .get(uri.as_str())
-            .header("Content-Type", "application/json")
-            .header(AUTHORIZATION, format!("Bearer {}", self.api_key))
-            .send()?;
-        let headers = Self::header_map_to_hash_map(response.headers());
-        if response.status() != StatusCode::OK {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
-            )))
-        } else {
-            let fir: FileInfoResponse = response.json()?;
-            let datetime = NaiveDateTime::from_timestamp_opt(fir.created_at, 0).unwrap();
-            let datetime_utc = Utc.from_utc_datetime(&datetime);
-
-            let datetime_string = datetime_utc.format("%Y-%m-%d %H:%M:%S").to_string();
-            Ok(ApiResult {
-                headers,
-                body: format!(
-                    "Size: {} Name: {} Created: {}",
-                    fir.bytes, fir.filename, datetime_string
-                ), //fir.to_string(),
-            })
-        }
+        let uri = self.uri(format!("files/{file_id}").as_str());
+        let response = self.send_ok(HttpRequest::get(uri.as_str()))?;
+        let fir: FileInfoResponse = response.json()?;
+        let datetime = DateTime::from_timestamp(fir.created_at, 0)
+            .unwrap()
+            .naive_utc();
+        let datetime_utc = Utc.from_utc_datetime(&datetime);
+
+        let datetime_string = datetime_utc.format("%Y-%m-%d %H:%M:%S").to_string();
+        Ok(ApiResult {
+            headers: response.headers,
+            body: format!(
+                "Size: {} Name: {} Created: {}",
+                fir.bytes, fir.filename, datetime_string
+            ), //fir.to_string(),
+        })
 
         // //let result = ;
 
@@ -158,94 +492,43 @@ impl ApiInterface {
     /// Get file cotents
     pub fn file_contents(&self, file_id: String) -> Result<ApiResult<String>, Box<dyn Error>> {
         // GET https://api.openai.com/v1/files/{file_id}/content
-        let uri = format!("{API_URL}/files/{file_id}/content");
-        let response = self
-            .client
-            .get(uri.as_str())
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()?;
-        let headers = Self::header_map_to_hash_map(response.headers());
-        if response.status() != StatusCode::OK {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
-            )))
-        } else {
-            let content = response.text()?;
-            Ok(ApiResult::new(content, headers))
-        }
+        let uri = self.uri(format!("files/{file_id}/content").as_str());
+        let response = self.send_ok(HttpRequest::get(uri.as_str()))?;
+        Ok(ApiResult::new(response.text(), response.headers))
     }
 
     /// Delete a file
     pub fn files_delete(&self, file_id: String) -> Result<ApiResult<()>, Box<dyn Error>> {
         // DELETE https://api.openai.com/v1/files/{file_id}
-        let uri = format!("{API_URL}/files/{file_id}");
-        let response = self
-            .client
-            .delete(uri.as_str())
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()?;
-        let headers = Self::header_map_to_hash_map(response.headers());
-        if response.status() != StatusCode::OK {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
+        let uri = self.uri(format!("files/{file_id}").as_str());
+        let response = self.send_ok(HttpRequest::delete(uri.as_str()))?;
+        let fdr: FileDeletedResponse = response.json()?;
+        if !fdr.deleted || fdr.object != *"file" || fdr.id != file_id {
             Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
+                ApiErrorType::Error(format!(
+                    "File delete response:{:?}  file_id: {file_id}",
+                    fdr
+                )),
+                response.headers,
             )))
         } else {
-            let fdr: FileDeletedResponse = response.json()?;
-            if !fdr.deleted || fdr.object != *"file" || fdr.id != file_id {
-                Err(Box::new(ApiError::new(
-                    ApiErrorType::Error(format!(
-                        "File delete response:{:?}  file_id: {file_id}",
-                        fdr
-                    )),
-                    headers,
-                )))
-            } else {
-                Ok(ApiResult::new_e(HashMap::new()))
-            }
+            Ok(ApiResult::new_e(HashMap::new()))
         }
     }
 
     /// Get a list of all files stored on OpenAI
+    #[allow(clippy::type_complexity)]
     pub fn files_list(&self) -> Result<ApiResult<Vec<(String, String)>>, Box<dyn Error>> {
         // GET https://api.openai.com/v1/files
-        let uri = format!("{}/files", API_URL);
-        let response = self
-            .client
-            .get(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()?;
-
-        let headers = Self::header_map_to_hash_map(response.headers());
-        let response_strings: Vec<(String, String)> = if response.status() != StatusCode::OK {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            return Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
-            )));
-        } else {
-            response
-                .json::<Files>()?
-                .data
-                .iter()
-                .map(|x| (x.filename.clone(), x.id.clone()))
-                .collect()
-        };
-        Ok(ApiResult::new_v(response_strings, headers))
+        let uri = self.uri("files");
+        let response = self.send_ok(HttpRequest::get(uri.as_str()))?;
+        let response_strings: Vec<(String, String)> = response
+            .json::<Files>()?
+            .data
+            .iter()
+            .map(|x| (x.filename.clone(), x.id.clone()))
+            .collect();
+        Ok(ApiResult::new_v(response_strings, response.headers))
     }
 
     /// Upload a file for fine-tuning.
@@ -269,34 +552,20 @@ impl ApiInterface {
         //   "purpose": "fine-tune"
         // }
 
-        let uri = format!("{}/files", API_URL);
-
-        let file_field = multipart::Part::file(file)?;
-        let purpose_field = multipart::Part::text("fine-tune");
-        let form = multipart::Form::new()
-            .part("file", file_field)
-            .part("purpose", purpose_field);
-        let response = self
-            .client
-            .post(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .multipart(form)
-            .send()?;
-        let headers = Self::header_map_to_hash_map(response.headers());
-        let response_text: String = if response.status() != StatusCode::OK {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            return Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
-            )));
-        } else {
-            response.json::<FileUploadResponse>()?.id
-        };
-
-        Ok(ApiResult::new(response_text, headers))
+        let uri = self.uri("files");
+        let parts = vec![
+            FormPart::File {
+                name: "file".to_string(),
+                path: file.to_path_buf(),
+            },
+            FormPart::Text {
+                name: "purpose".to_string(),
+                value: "fine-tune".to_string(),
+            },
+        ];
+        let response = self.send_ok(HttpRequest::post_multipart(uri.as_str(), parts))?;
+        let response_text: String = response.json::<FileUploadResponse>()?.id;
+        Ok(ApiResult::new(response_text, response.headers))
     }
 
     /// The audio file `audio_file` is tracscribed.  No `Usage` data
@@ -319,90 +588,62 @@ impl ApiInterface {
         //   "text": "Imagine the....that."
         // }
 
-        let uri = format!("{}/audio/transcriptions", API_URL);
+        let uri = self.model_uri(AUDIO_MODEL, "audio/transcriptions")?;
 
-        let file_field = multipart::Part::file(audio_file)?;
-        let model_field = multipart::Part::text("whisper-1");
-        let mut form = multipart::Form::new()
-            .part("file", file_field)
-            .part("model", model_field);
+        let mut parts = vec![
+            FormPart::File {
+                name: "file".to_string(),
+                path: audio_file.to_path_buf(),
+            },
+            FormPart::Text {
+                name: "model".to_string(),
+                value: AUDIO_MODEL.to_string(),
+            },
+        ];
         if let Some(prompt) = prompt {
-            let p: String = prompt.to_string();
-            let prompt_field = multipart::Part::text(p);
-            form = form.part("prompt", prompt_field);
+            parts.push(FormPart::Text {
+                name: "prompt".to_string(),
+                value: prompt.to_string(),
+            });
         }
 
-        // let client = reqwest::blocking::Client::new();
-        let response = self
-            .client
-            .post(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .multipart(form)
-            .send()?;
+        let response = self.send(HttpRequest::post_multipart(uri.as_str(), parts))?;
 
-        let headers = Self::header_map_to_hash_map(response.headers());
-        let response_text: String = if response.status() != StatusCode::OK {
+        let response_text: String = if !response.status.is_success() {
             format!(
                 "Failed: Status: {}.\nResponse.path({})",
-                response
-                    .status()
-                    .canonical_reason()
-                    .unwrap_or("Unknown Reason"),
-                response.url().path(),
+                response.reason(),
+                uri,
             )
         } else {
             response.json::<AudioTranscriptionResponse>()?.text
         };
 
-        Ok(ApiResult::new(response_text, headers))
+        Ok(ApiResult::new(response_text, response.headers))
     }
 
     pub fn fine_tune_retrieve(&self, id: &str) -> Result<ApiResult<String>, Box<dyn Error>> {
-        let uri = format!("{API_URL}/fine-tunes/{id}");
-        let response = self
-            .client
-            .get(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()?;
-
-        let headers = Self::header_map_to_hash_map(response.headers());
-        let body: String = if response.status() != StatusCode::OK {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            return Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
-            )));
-        } else {
-            format!("{}", response.json::<FineTuneRetrieve>()?)
-        };
-
-        Ok(ApiResult { headers, body })
+        let uri = self.uri(format!("fine-tunes/{id}").as_str());
+        let response = self.send_ok(HttpRequest::get(uri.as_str()))?;
+        let body = format!("{}", response.json::<FineTuneRetrieve>()?);
+        Ok(ApiResult {
+            headers: response.headers,
+            body,
+        })
     }
 
     pub fn fine_tune_create(
         &self,
         training_file_id: String,
     ) -> Result<ApiResult<String>, Box<dyn Error>> {
-        let uri = format!("{API_URL}/fine-tunes");
+        let uri = self.uri("fine-tunes");
         let request_body = json!({
                 "training_file": training_file_id.as_str()
         });
 
-        let mut response = self
-            .client
-            .post(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-            .json(&request_body)
-            .send()?;
-        let mut s = String::new();
-        _ = response.read_to_string(&mut s)?;
-        let headers = Self::header_map_to_hash_map(response.headers());
-        let st = s.as_str();
-        let fine_tune: FineTuneCreate = serde_json::from_str(st)?;
+        let response = self.send(HttpRequest::post_json(uri.as_str(), request_body))?;
+        let headers = response.headers.clone();
+        let fine_tune: FineTuneCreate = response.json()?;
 
         Ok(ApiResult {
             headers,
@@ -411,33 +652,20 @@ impl ApiInterface {
     }
 
     pub fn fine_tune_list(&self) -> Result<ApiResult<String>, Box<dyn Error>> {
-        let uri = format!("{API_URL}/fine-tunes");
-
-        let mut response = self
-            .client
-            .get(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-            .send()?;
-        let mut s = String::new();
-        _ = response.read_to_string(&mut s)?;
-        let st = s.as_str();
-        let fine_tune_list: FineTuneList = serde_json::from_str(st)?;
-        let headers = Self::header_map_to_hash_map(response.headers());
+        let uri = self.uri("fine-tunes");
+
+        let response = self.send(HttpRequest::get(uri.as_str()))?;
+        let fine_tune_list: FineTuneList = response.json()?;
+        let headers = response.headers;
         Ok(ApiResult {
             headers,
             body: fine_tune_list.as_string(),
         })
     }
 
-    /// Documented [here](https://platform.openai.com/docs/api-reference/chat)
-    pub fn chat(&mut self, prompt: &str, model: &str) -> Result<ApiResult<String>, Box<dyn Error>> {
-        // An ongoing conversation with the LLM
-
-        // Model can be any of: gpt-4, gpt-4-0314, gpt-4-32k,
-        // gpt-4-32k-0314, gpt-3.5-turbo, gpt-3.5-turbo-0301
-        // https://platform.openai.com/docs/models/model-endpoint-compatibility
-
+    /// The messages for a chat: The system prompt, the conversation
+    /// so far, and `prompt`
+    pub(crate) fn chat_messages(&self, prompt: &str) -> Vec<Message> {
         // Put the conversation so far in here
         let mut messages: Vec<Message> = vec![]; // = [Message { role, content }];
 
@@ -465,29 +693,228 @@ impl ApiInterface {
         let role = "user".to_string();
         let content = prompt.to_string();
         messages.push(Message { role, content });
+        messages
+    }
 
-        // The payload
-        let data = json!({
-            "messages": messages,
-            "model": model,
-        });
-        let headers_json: (HashMap<String, String>, ChatRequestInfo) =
-            Self::send_chat(self.api_key.as_str(), &data)?;
+    /// Documented [here](https://platform.openai.com/docs/api-reference/chat)
+    /// "claude-*" models are sent to Anthropic.  In Azure mode OpenAI
+    /// models are sent to their deployment
+    pub fn chat(&mut self, prompt: &str, model: &str) -> Result<ApiResult<String>, Box<dyn Error>> {
+        // An ongoing conversation with the LLM
+
+        // Model can be any of: gpt-4, gpt-4-0314, gpt-4-32k,
+        // gpt-4-32k-0314, gpt-3.5-turbo, gpt-3.5-turbo-0301
+        // https://platform.openai.com/docs/models/model-endpoint-compatibility
+        let messages = self.chat_messages(prompt);
+        let models = fallback::chain(model, &self.fallback);
+        let (model, headers_json) =
+            fallback::with_fallback(&models, |model| self.send_chat_to(model, &messages))?;
+        Ok(self.process_chat_response(prompt, model.as_str(), headers_json))
+    }
+
+    /// Send `messages` to the provider for `model`.  This maintains
+    /// no state
+    pub(crate) fn send_chat_to(
+        &self,
+        model: &str,
+        messages: &[Message],
+    ) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+        self.check_budget(&[model], messages)?;
+        self.send_chat_unchecked(model, messages)
+    }
+
+    /// As `send_chat_to` but the budget is not checked.  The caller
+    /// has checked it
+    pub(crate) fn send_chat_unchecked(
+        &self,
+        model: &str,
+        messages: &[Message],
+    ) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+        match Provider::from_model(model) {
+            Provider::OpenAi => {
+                // The payload.  The reply is capped, so it costs no
+                // more than the budget allowed for
+                let data = json!({
+                    "messages": messages,
+                    "model": model,
+                    "max_tokens": self.tokens,
+                    "temperature": self.temperature,
+                });
+                match self.azure.as_ref() {
+                    Some(azure_config) => {
+                        azure::send_chat(self.transport.as_ref(), azure_config, model, &data)
+                    }
+                    None => Self::send_chat(self.transport.as_ref(), self.api_key.as_str(), &data),
+                }
+            }
+            Provider::Anthropic => {
+                let data = anthropic_interface::payload(
+                    model,
+                    messages,
+                    self.tokens,
+                    self.temperature,
+                    false,
+                );
+                anthropic_interface::send_chat(
+                    self.transport.as_ref(),
+                    self.get_anthropic_api_key()?,
+                    &data,
+                )
+            }
+            Provider::Ollama => self
+                .ollama
+                .chat(model, messages, self.temperature, self.tokens),
+        }
+    }
+
+    /// As `chat` but the reply is passed to `on_text` as it arrives.
+    /// Only Anthropic models stream.  For the others `on_text` is
+    /// called once with the whole reply.  Once some of the reply has
+    /// been passed on there is no falling back to another model
+    pub fn chat_stream(
+        &mut self,
+        prompt: &str,
+        model: &str,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<ApiResult<String>, Box<dyn Error>> {
+        let messages = self.chat_messages(prompt);
+        let models = fallback::chain(model, &self.fallback);
+        let mut streamed = false;
+        let (model, headers_json) = fallback::with_fallback(&models, |model| {
+            let mut on_text = |text: &str| {
+                streamed = true;
+                on_text(text);
+            };
+            let result = match Provider::from_model(model) {
+                Provider::Anthropic => {
+                    self.check_budget(&[model], &messages)?;
+                    let data = anthropic_interface::payload(
+                        model,
+                        &messages,
+                        self.tokens,
+                        self.temperature,
+                        true,
+                    );
+                    anthropic_interface::send_chat_stream(
+                        self.transport.as_ref(),
+                        self.get_anthropic_api_key()?,
+                        &data,
+                        &mut on_text,
+                    )
+                }
+                _ => self.send_chat_to(model, &messages).inspect(|headers_json| {
+                    on_text(headers_json.1.choices[0].message.content.as_str())
+                }),
+            };
+            result.map_err(|err| -> Box<dyn Error> {
+                if streamed {
+                    // Part of the reply is out.  Do not try again
+                    Box::new(ApiError::new(
+                        ApiErrorType::FailedRequest(format!("{err}")),
+                        HashMap::new(),
+                    ))
+                } else {
+                    err
+                }
+            })
+        })?;
+        Ok(self.process_chat_response(prompt, model.as_str(), headers_json))
+    }
+
+    /// Record a chat response in the context and account for its cost
+    fn process_chat_response(
+        &mut self,
+        prompt: &str,
+        model: &str,
+        headers_json: (HashMap<String, String>, ChatRequestInfo),
+    ) -> ApiResult<String> {
         let mut headers_ret = Self::usage_headers(headers_json.1.usage.clone());
-        let cost: f64 = Self::cost(headers_json.1.usage, model);
-        self.context.cost += cost;
+        // A cached response was paid for when it was cached
+        let cost: f64 = if headers_json.0.get(CACHE_HEADER).map(|s| s.as_str()) == Some("hit") {
+            0.0
+        } else {
+            Self::cost(headers_json.1.usage.clone(), model)
+        };
+        self.record_charge(model, self.charge_mode, cost, Some(&headers_json.1.usage));
         headers_ret.insert("Cost".to_string(), format!("{cost}"));
+        headers_ret.insert(fallback::MODEL_HEADER.to_string(), model.to_string());
         headers_ret.extend(headers_json.0);
 
         let content = headers_json.1.choices[0].message.content.clone();
-        self.context.push(prompt.to_string());
-        self.context.push(content.clone());
+        self.context
+            .push_turn(prompt.to_string(), content.clone(), cost);
+
+        ApiResult::new(content, headers_ret)
+    }
 
-        Ok(ApiResult::new(content, headers_ret))
+    /// Refuse to send `messages` to each of `models` if together
+    /// they could cost more than the budget allows
+    pub(crate) fn check_budget(
+        &self,
+        models: &[&str],
+        messages: &[Message],
+    ) -> Result<(), Box<dyn Error>> {
+        if self.budget.is_unlimited() {
+            return Ok(());
+        }
+        let mut estimate = 0.0;
+        for model in models {
+            estimate += self.estimate(model, messages)?;
+        }
+        self.budget
+            .check(estimate, self.context.cost, &self.ledger.spending()?)
+    }
+
+    /// What sending `messages` to `model` could cost, with a reply of
+    /// `tokens`.  A model with no known price cannot be kept to a
+    /// budget, so that is an error
+    fn estimate(&self, model: &str, messages: &[Message]) -> Result<f64, Box<dyn Error>> {
+        let prompt_tokens: u32 = messages
+            .iter()
+            .map(|m| budget::estimate_tokens(m.content.as_str()))
+            .sum();
+        let usage = Usage {
+            prompt_tokens,
+            completion_tokens: self.tokens,
+            total_tokens: prompt_tokens + self.tokens,
+        };
+        Self::price(&usage, model).ok_or_else(|| -> Box<dyn Error> {
+            Box::new(ApiError::new(
+                ApiErrorType::Error(format!(
+                    "The price of {model} is not known, so it cannot be kept to the budget"
+                )),
+                HashMap::new(),
+            ))
+        })
+    }
+
+    /// Record that `model` cost `cost` in `mode`, if it cost
+    /// anything, in the ledger
+    pub(crate) fn record_charge(&self, model: &str, mode: &str, cost: f64, usage: Option<&Usage>) {
+        if cost > 0.0 {
+            let mut charge = Charge::new(model, mode, cost, self.session.as_deref());
+            if let Some(usage) = usage {
+                charge = charge.with_usage(usage);
+            }
+            if let Err(err) = self.ledger.record(&charge) {
+                log::warn!("{err}: Cannot record the charge");
+            }
+        }
+    }
+
+    /// Every charged request
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Spending in the current day and month
+    pub fn spending(&self) -> Result<Spending, Box<dyn Error>> {
+        self.ledger.spending()
     }
 
     /// Send the data to the OpenAI server and return the response with headers.  This maintains no state
     pub fn send_chat(
+        transport: &dyn Transport,
         api_key: &str,
         data: &serde_json::Value,
     ) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
@@ -495,7 +922,9 @@ impl ApiInterface {
         // into ``ChatRequestInfo` endpoint
         let uri = format!("{}/chat/completions", API_URL);
         // eprintln!("Sending {uri}");
-        let (headers, response_string) = Self::send_curl(api_key, data, uri.as_str())?;
+        let headers = [("Authorization".to_string(), format!("Bearer {api_key}"))];
+        let (headers, response_string) =
+            Self::post_json(transport, &headers, data, uri.as_str(), None)?;
         // eprintln!("Got back {uri}: {}", response_string.as_str());
         let json: ChatRequestInfo = serde_json::from_str(response_string.as_str())?;
         // eprintln!("Response from: {uri} {json:?}");
@@ -520,60 +949,43 @@ impl ApiInterface {
         prompt: &str,
         model: &str,
     ) -> Result<ApiResult<String>, Box<dyn Error>> {
-        let uri: String = format!("{}/completions", API_URL);
+        if Provider::from_model(model) == Provider::Ollama {
+            let (mut headers, response_text) =
+                self.ollama
+                    .generate(model, prompt, self.temperature, self.tokens)?;
+            headers.insert("Cost".to_string(), "0".to_string());
+            return Ok(ApiResult::new(response_text, headers));
+        }
+        let uri: String = self.model_uri(model, "completions")?;
 
         let payload = CompletionRequestInfo::new(prompt, model, self.temperature, self.tokens);
 
-        let response = self
-            .client
-            .post(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&payload)
-            .send()?;
-
-        let mut headers = Self::header_map_to_hash_map(response.headers());
-        let response_text: String = if response.status() != StatusCode::OK {
-            // There was some sort of failure.  Probably a network
-            // failure
-            format!(
-                "Failed: Status: {}.\nResponse.path({})",
-                response
-                    .status()
-                    .canonical_reason()
-                    .unwrap_or("Unknown Reason"),
-                response.url().path(),
-            )
-        } else {
-            // Got a good response from the LLM
-            let response_debug = format!("{:?}", &response);
-            let json: CompletionRequestInfo = match response.json() {
-                Ok(json) => json,
-                Err(err) => {
-                    panic!("Failed to get json.  {err}\n{response_debug}")
-                }
-            };
+        let response = self.send(HttpRequest::post_json(
+            uri.as_str(),
+            serde_json::to_value(&payload)?,
+        ))?;
 
-            // The data about the query
-            // let choice_count = json.choices.len();
-            let finish_reason = json.choices[0].finish_reason.as_str();
-            if finish_reason != "stop" {
-                headers.insert("finsh reason".to_string(), finish_reason.to_string());
-            }
-
-            if json.choices[0].text.is_empty() {
-                panic!("Empty json.choices[0].  {:?}", &json);
-            } else {
-                json.choices[0].text.clone()
+        let mut headers = response.headers.clone();
+        let json: CompletionRequestInfo = Self::response_json(&response)?;
+        let choice = match json.choices.first() {
+            Some(choice) => choice,
+            None => {
+                return Err(Box::new(ApiError::new(
+                    ApiErrorType::BadJson("No choices in the completion".to_string()),
+                    headers,
+                )))
             }
         };
-        Ok(ApiResult::new(response_text, headers))
+        if choice.finish_reason != "stop" {
+            headers.insert("finsh reason".to_string(), choice.finish_reason.clone());
+        }
+        Ok(ApiResult::new(choice.text.clone(), headers))
     }
 
     /// Handle image mode prompts
     pub fn image(&mut self, prompt: &str) -> Result<ApiResult<String>, Box<dyn Error>> {
         // Endpoint
-        let uri: String = format!("{}/images/generations", API_URL);
+        let uri: String = self.model_uri(IMAGE_MODEL, "images/generations")?;
 
         // Payload
         let data = json!({
@@ -581,15 +993,8 @@ impl ApiInterface {
                   "size": "1024x1024",
         });
 
-        // Set up network comms
-        let res = Client::new()
-            .post(uri)
-            .header("Authorization", format!("Bearer {}", self.api_key).as_str())
-            .header("Content-Type", "application/json")
-            .json(&data);
-
         // Send network request
-        let response = match res.send() {
+        let response = match self.send(HttpRequest::post_json(uri.as_str(), data)) {
             Ok(r) => r,
             Err(err) => {
                 return Ok(ApiResult::new(
@@ -599,33 +1004,8 @@ impl ApiInterface {
             }
         };
 
-        // Prepare diagnostic data
-        let headers = Self::header_map_to_hash_map(&response.headers().clone());
-        if !response.status().is_success() {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            return Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
-                headers,
-            )));
-            //return Ok(ApiResult::new("Request failed".to_string(), headers));
-        }
-
-        // Have a normal result.  Process it
-        let json: ImageRequestInfo = match response.json() {
-            Ok(json) => json,
-            Err(err) => {
-                return Err(Box::new(ApiError::new(
-                    ApiErrorType::BadJson(format!("{err}")),
-                    headers,
-                )))
-            }
-        };
-
-        // Success.
-        Ok(ApiResult::new(json.data[0].url.clone(), headers))
+        let json: ImageRequestInfo = Self::response_json(&response)?;
+        Self::image_url(json, response.headers)
     }
 
     // Editing an image.  The mask defines the region to edit
@@ -639,7 +1019,7 @@ impl ApiInterface {
         mask: &Path,
     ) -> Result<ApiResult<String>, Box<dyn Error>> {
         // Endpoint
-        let uri = format!("{}/images/edits", API_URL);
+        let uri = self.model_uri(IMAGE_MODEL, "images/edits")?;
 
         // Some timeing.  TODO: Why here, in this function, and not everywhere?
         let start = Instant::now();
@@ -649,111 +1029,139 @@ impl ApiInterface {
         // second case the image refered to in the url is downloaded and
         // put into `self.image`
 
-        // let mask_path = mask_file.path().to_owned();
-
         // Prepare the payload to send to OpenAI
-        let form = multipart::Form::new();
-        let form = match form.file("image", image) {
-            Ok(f) => match f.file("mask", mask) {
-                Ok(s) => s
-                    .text("prompt", prompt.to_string())
-                    .text("size", "1024x1024"),
-                Err(err) => {
-                    return Err(Box::new(ApiError::new(
-                        ApiErrorType::Error(format!("{err}")),
-                        HashMap::new(),
-                    )))
-                }
+        let parts = vec![
+            FormPart::File {
+                name: "image".to_string(),
+                path: image.to_path_buf(),
             },
-            // Err(err) => return Err(Box::new(ApiErrorType::Error("Err path: {err}".to_string()))),
-            Err(err) => {
-                return Err(Box::new(ApiError::new(
-                    ApiErrorType::Error(format!("{err}")),
-                    HashMap::new(),
-                )))
-            }
-        };
-
-        // Set up network comms
-        let req_build: RequestBuilder = Client::new()
-            .post(uri.as_str())
-            .timeout(std::time::Duration::from_secs(1200))
-            .header("Authorization", format!("Bearer {}", self.api_key).as_str())
-            .multipart(form);
+            FormPart::File {
+                name: "mask".to_string(),
+                path: mask.to_path_buf(),
+            },
+            FormPart::Text {
+                name: "prompt".to_string(),
+                value: prompt.to_string(),
+            },
+            FormPart::Text {
+                name: "size".to_string(),
+                value: "1024x1024".to_string(),
+            },
+        ];
 
         // Send request
-        let response = match req_build.send() {
-            Ok(r) => r,
-            Err(err) => {
-                println!("Failed url: {uri} Err: {err}");
-                return Err(Box::new(err));
-            }
-        };
+        let response = self.send(HttpRequest::post_multipart(uri.as_str(), parts))?;
+        log::debug!("Image edit from {uri} in {:?}", start.elapsed());
+        let json: ImageRequestInfo = Self::response_json(&response)?;
+        Self::image_url(json, response.headers)
+    }
 
-        let headers = Self::header_map_to_hash_map(&response.headers().clone());
-        println!("Sent message: {:?}", start.elapsed());
-        if !response.status().is_success() {
-            let reason = response
-                .status()
-                .canonical_reason()
-                .unwrap_or("Unknown Reason");
-            return Err(Box::new(ApiError::new(
-                ApiErrorType::Status(response.status(), reason.to_string()),
+    /// The URL of the first image in `json`
+    fn image_url(
+        json: ImageRequestInfo,
+        headers: HashMap<String, String>,
+    ) -> Result<ApiResult<String>, Box<dyn Error>> {
+        match json.data.first() {
+            Some(image) => Ok(ApiResult::new(image.url.clone(), headers)),
+            None => Err(Box::new(ApiError::new(
+                ApiErrorType::BadJson("No image in the reply".to_string()),
                 headers,
-            )));
+            ))),
         }
-        let response_dbg = format!("{:?}", response);
-        // let response_text = response.text()?;
-        // Ok(response_text)
-        let json: ImageRequestInfo = match response.json() {
-            Ok(json) => json,
-            Err(err) => {
-                eprintln!("Failed to get json. {err} Response: {response_dbg}");
-                return Err(Box::new(err));
-            }
-        };
-
-        Ok(ApiResult::new(json.data[0].url.clone(), headers))
     }
 
     /// Handle the response if the user queries what models there are
     /// ("! mm" prompt in cli).  
     pub fn model_list(&self) -> Result<ApiResult<String>, Box<dyn Error>> {
-        let uri: String = format!("{}/models", API_URL);
-        let mut response = self
-            .client
-            .get(uri.as_str())
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()?;
-        if !response.status().is_success() {
-            // If it were not a success the previous cal will have failed
-            // This will not happen
-            panic!("Failed call to get model list. {:?}", response);
-        }
-        let mut s = String::new();
-        _ = response.read_to_string(&mut s)?;
-        let st = s.as_str();
-        let model_info: ModelInfo = serde_json::from_str(st)?;
+        let uri: String = self.uri("models");
+        let response = self.send(HttpRequest::get(uri.as_str()))?;
+        let model_info: ModelInfo = Self::response_json(&response)?;
         let body: String = format!("{model_info}");
-        let headers = Self::header_map_to_hash_map(response.headers());
+        let headers = response.headers;
         Ok(ApiResult { headers, body })
     }
 
-    /// Convert the usege into a price.
-    fn cost(usage: Usage, model: &str) -> f64 {
+    /// The ids of the models OpenAI offers for `api_key`.  This
+    /// maintains no state
+    pub fn model_ids(
+        transport: &dyn Transport,
+        api_key: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let uri = format!("{API_URL}/models");
+        let request = HttpRequest::get(uri.as_str())
+            .header("Authorization", format!("Bearer {api_key}").as_str());
+        let response = transport.send(&request, None)?;
+        if !response.status.is_success() {
+            return Err(Box::new(ApiError::new(
+                ApiErrorType::Status(response.status, response.reason()),
+                response.headers,
+            )));
+        }
+        let json: serde_json::Value = response.json()?;
+        Ok(json["data"]
+            .as_array()
+            .map(|data| {
+                data.iter()
+                    .filter_map(|m| m["id"].as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Ask the providers what models they offer and merge that into
+    /// `registry`.  With Azure the models are those with deployments.
+    /// Without a key for Anthropic its models are unavailable.
+    /// Providers that cannot be reached are logged and left alone
+    pub fn update_registry(&self, registry: &mut ModelRegistry) {
+        if let Some(azure) = self.azure.as_ref() {
+            let listed: Vec<String> = azure.deployments.keys().cloned().collect();
+            registry.merge_listed(Provider::OpenAi, &listed);
+        } else if !self.api_key.is_empty() {
+            match Self::model_ids(self.transport.as_ref(), self.api_key.as_str()) {
+                Ok(listed) => registry.merge_listed(Provider::OpenAi, &listed),
+                Err(err) => log::warn!("Cannot list OpenAI models: {err}"),
+            }
+        }
+        if self.anthropic_api_key.is_none() {
+            registry.merge_listed(Provider::Anthropic, &[]);
+        }
+        match self.ollama.models() {
+            Ok(listed) => registry.merge_listed(Provider::Ollama, &listed),
+            Err(err) => log::warn!("Cannot list Ollama models: {err}"),
+        }
+    }
+
+    /// Convert the usege into a price.  Models that are listed, but
+    /// whose price is not known, are charged nothing, with a warning
+    pub fn cost(usage: Usage, model: &str) -> f64 {
+        Self::price(&usage, model).unwrap_or_else(|| {
+            log::warn!("The price of {model} is not known.  Charged nothing");
+            0.0
+        })
+    }
+
+    /// The price of `usage`, in cents, if the price of `model` is known
+    pub fn price(usage: &Usage, model: &str) -> Option<f64> {
         // GPT-4is more expensive
+        match Provider::from_model(model) {
+            Provider::Anthropic => return Some(anthropic_interface::cost(usage, model)),
+            // Local models are free
+            Provider::Ollama => return Some(0.0),
+            Provider::OpenAi => (),
+        }
         if model.starts_with("gpt-4") {
-            usage.completion_tokens as f64 / 1000.0 * 12.0
-                + usage.prompt_tokens as f64 / 1000.0 * 0.06
+            Some(
+                usage.completion_tokens as f64 / 1000.0 * 12.0
+                    + usage.prompt_tokens as f64 / 1000.0 * 0.06,
+            )
         } else if model.starts_with("gpt-3") {
-            usage.total_tokens as f64 / 1000.0 * 0.2
+            Some(usage.total_tokens as f64 / 1000.0 * 0.2)
         } else {
-            panic!("{model}");
+            None
         }
     }
 
-    fn usage_headers(usage: Usage) -> HashMap<String, String> {
+    pub(crate) fn usage_headers(usage: Usage) -> HashMap<String, String> {
         let prompt_tokens = usage.prompt_tokens.to_string();
         let completion_tokens = usage.completion_tokens.to_string();
         let total_tokens = usage.total_tokens.to_string();
@@ -764,88 +1172,371 @@ impl ApiInterface {
         result
     }
 
-    /// Used to adapt headers reported from Reqwest
-    fn header_map_to_hash_map(header_map: &HeaderMap) -> HashMap<String, String> {
-        let mut hash_map = HashMap::new();
-        for (header_name, header_value) in header_map.iter() {
-            if let (Ok(name), Ok(value)) = (
-                header_name.to_string().as_str().trim().parse::<String>(),
-                header_value.to_str().map(str::to_owned),
-            ) {
-                hash_map.insert(name, value);
-            }
-        }
-        hash_map
-    }
-
     /// Clear the context used to maintain chat history
     pub fn clear_context(&mut self) {
         self.context.clear();
     }
 
-    /// Send a request, the body of which is coded in `data`, to `uri`.
-    /// Return the Json data as a String
-    fn send_curl(
-        api_key: &str,
+    /// POST `data` to `uri` with the extra `headers` (name, value).
+    /// If `on_chunk` is supplied it is passed the body as it arrives,
+    /// for streaming responses.  Return the headers and the whole
+    /// body.  A failed status is an `ApiErrorType::Status` error, with
+    /// the body as part of the reason
+    #[allow(clippy::type_complexity)]
+    /// The body of `response` as a `T`.  A failed status, or a body
+    /// that is not a `T`, is an `ApiError`
+    fn response_json<T: DeserializeOwned>(response: &HttpResponse) -> Result<T, Box<dyn Error>> {
+        if !response.status.is_success() {
+            return Err(Box::new(ApiError::new(
+                ApiErrorType::Status(response.status, response.reason()),
+                response.headers.clone(),
+            )));
+        }
+        response.json().map_err(|err| -> Box<dyn Error> {
+            Box::new(ApiError::new(
+                ApiErrorType::BadJson(format!("{err}: {}", response.text())),
+                response.headers.clone(),
+            ))
+        })
+    }
+
+    pub(crate) fn post_json(
+        transport: &dyn Transport,
+        headers: &[(String, String)],
         data: &serde_json::Value,
         uri: &str,
+        on_chunk: Option<ChunkHandler>,
     ) -> Result<(HashMap<String, String>, String), Box<dyn Error>> {
-        let body = format!("{data}");
+        let mut request = HttpRequest::post_json(uri, data.clone());
+        request.headers.extend_from_slice(headers);
+        let response = transport.send(&request, on_chunk)?;
+        if !response.status.is_success() {
+            let reason = format!(
+                "{}: {}",
+                response.reason(),
+                String::from_utf8_lossy(&response.body).trim()
+            );
+            return Err(Box::new(ApiError::new(
+                ApiErrorType::Status(response.status, reason),
+                response.headers,
+            )));
+        }
+        Ok((response.headers, String::from_utf8(response.body)?))
+    }
+}
 
-        let mut body = body.as_bytes();
-        let mut curl_easy = Easy::new();
-        curl_easy.url(uri)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::Period;
+    use crate::model_mode::ModelMode;
+    use crate::transport::Body;
+    use crate::transport::ChunkHandler;
+    use reqwest::StatusCode;
+    use std::sync::Mutex;
+
+    /// Records the requests and replies to every chat with "Hello".
+    /// Chats with the `busy` model are rate limited
+    #[derive(Debug, Default)]
+    struct FakeTransport {
+        requests: Mutex<Vec<HttpRequest>>,
+        busy: Option<String>,
+    }
 
-        // Prepare the headers
-        let mut list = List::new();
-        list.append(format!("Authorization: Bearer {}", api_key).as_str())?;
-        list.append("Content-Type: application/json")?;
-        curl_easy.http_headers(list)?;
+    impl Transport for FakeTransport {
+        fn send(
+            &self,
+            request: &HttpRequest,
+            _on_chunk: Option<ChunkHandler>,
+        ) -> Result<HttpResponse, Box<dyn Error>> {
+            self.requests.lock().unwrap().push(request.clone());
+            if let (Some(busy), Body::Json(data)) = (self.busy.as_ref(), &request.body) {
+                if data["model"] == busy.as_str() {
+                    return Ok(HttpResponse {
+                        status: StatusCode::TOO_MANY_REQUESTS,
+                        headers: HashMap::new(),
+                        body: br#"{"error": {"message": "Rate limit reached"}}"#.to_vec(),
+                    });
+                }
+            }
+            let reply = ChatRequestInfo::new(
+                "id".to_string(),
+                "gpt-4".to_string(),
+                Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                    total_tokens: 15,
+                },
+                "Hello".to_string(),
+                "stop".to_string(),
+            );
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HashMap::new(),
+                body: serde_json::to_vec(&reply)?,
+            })
+        }
+    }
 
-        // I am unsure why I have to do this magick incantation
-        curl_easy.post_field_size(body.len() as u64)?;
+    #[test]
+    fn chat_uses_injected_transport() {
+        let transport = Arc::new(FakeTransport::default());
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let result = api_interface.chat("Hi", "gpt-4").unwrap();
+        assert_eq!(result.body, "Hello");
+        assert_eq!(api_interface.context.len(), 2);
+        match &transport.requests.lock().unwrap()[0].body {
+            Body::Json(data) => {
+                assert_eq!(data["max_tokens"], 100);
+                assert_eq!(data["temperature"], 0.5);
+            }
+            body => panic!("Not JSON: {body:?}"),
+        }
 
-        // To get the normal output of the server
-        let mut output_buffer = Vec::new();
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].uri, format!("{API_URL}/chat/completions"));
+        assert!(requests[0]
+            .headers
+            .contains(&("Authorization".to_string(), "Bearer key".to_string())));
+    }
 
-        // To get the headers
-        let mut header_buffer = Vec::new();
+    #[test]
+    fn chat_with_unpriced_model() {
+        // A model OpenAI lists, with no price
+        let mut registry = ModelRegistry::builtin();
+        registry.merge_listed(Provider::OpenAi, &["gpt-5".to_string()]);
+        assert_eq!(registry.supports("gpt-5", &ModelMode::Chat), Some(true));
+        let usage = Usage {
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: 15,
+        };
+        assert_eq!(ApiInterface::price(&usage, "gpt-5"), None);
+
+        let transport = Arc::new(FakeTransport::default());
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let result = api_interface.chat("Hi", "gpt-5").unwrap();
+        assert_eq!(result.body, "Hello");
+        assert_eq!(result.headers.get("Cost").map(|c| c.as_str()), Some("0"));
+        assert_eq!(api_interface.context.cost, 0.0);
+
+        // What it costs is not known, so it cannot be kept to a budget
+        api_interface.budget.set(Period::Request, Some(1.0));
+        let err = api_interface.chat("Hi", "gpt-5").unwrap_err();
+        assert!(err.to_string().contains("price of gpt-5 is not known"));
+        assert_eq!(transport.requests.lock().unwrap().len(), 1);
+    }
 
-        // Time the process.
-        let start = Instant::now();
+    #[test]
+    fn chat_over_budget_is_not_sent() {
+        let transport = Arc::new(FakeTransport::default());
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        // 100 tokens of reply from gpt-4 could cost 1.2 cents
+        api_interface.budget.set(Period::Request, Some(2.0));
+        api_interface.chat("Hi", "gpt-4").unwrap();
+        api_interface.chat("Hi", "gpt-4").unwrap();
+        let today = api_interface
+            .spending()
+            .unwrap()
+            .today(chrono::Local::now());
+        assert_eq!(today, api_interface.context.cost);
+
+        api_interface.budget.set(Period::Day, Some(today + 1.0));
+        let err = api_interface.chat("Hi", "gpt-4").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>().map(|e| &e.error_type),
+            Some(ApiErrorType::BudgetExceeded {
+                period: Period::Day,
+                ..
+            })
+        ));
+        assert_eq!(api_interface.context.len(), 4);
+        assert_eq!(transport.requests.lock().unwrap().len(), 2);
+
+        // Local models are free
+        api_interface.budget.set(Period::Day, Some(0.0));
+        assert!(api_interface
+            .check_budget(&["ollama/llama3"], &api_interface.chat_messages("Hi"))
+            .is_ok());
+    }
 
-        {
-            // Start a block so `transfer` is destroyed and releases the
-            // borrow it has on `header_buffer` and `output_buffer`
-            let mut transfer = curl_easy.transfer();
-            transfer.header_function(|data| {
-                header_buffer.push(String::from_utf8(data.to_vec()).unwrap());
-                true
-            })?;
-            transfer.read_function(|buf| Ok(body.read(buf).unwrap_or(0)))?;
-            transfer.write_function(|data| {
-                output_buffer.extend_from_slice(data);
-                Ok(data.len())
-            })?;
-            transfer.perform()?;
-        }
+    #[test]
+    fn azure_chat_uses_deployment_and_api_key() {
+        let transport = Arc::new(FakeTransport::default());
+        let mut api_interface = ApiInterface::builder(String::new(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        api_interface.set_azure(AzureConfig {
+            endpoint: "https://example.openai.azure.com".to_string(),
+            api_version: "2024-02-01".to_string(),
+            api_key: "azure-key".to_string(),
+            deployments: HashMap::from([("gpt-4".to_string(), "my-gpt-4".to_string())]),
+        });
+        api_interface.chat("Hi", "gpt-4").unwrap();
+
+        let requests = transport.requests.lock().unwrap();
+        assert!(requests[0]
+            .uri
+            .starts_with("https://example.openai.azure.com/openai/deployments/my-gpt-4/"));
+        assert!(requests[0]
+            .headers
+            .contains(&("api-key".to_string(), "azure-key".to_string())));
+    }
 
-        // Made the call, got the output,  Close the timer
-        let _duration = start.elapsed();
+    #[test]
+    fn chat_falls_back_when_rate_limited() {
+        let transport = Arc::new(FakeTransport {
+            busy: Some("gpt-4".to_string()),
+            ..Default::default()
+        });
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        assert!(api_interface.chat("Hi", "gpt-4").is_err());
+        assert_eq!(api_interface.context.len(), 0);
+
+        api_interface.fallback = vec!["gpt-3.5-turbo".to_string()];
+        let result = api_interface.chat("Hi", "gpt-4").unwrap();
+        assert_eq!(result.body, "Hello");
+        assert_eq!(result.headers[fallback::MODEL_HEADER], "gpt-3.5-turbo");
+        assert_eq!(
+            result.headers["Cost"],
+            ApiInterface::cost(
+                Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                    total_tokens: 15,
+                },
+                "gpt-3.5-turbo"
+            )
+            .to_string()
+        );
+        assert_eq!(transport.requests.lock().unwrap().len(), 3);
+    }
 
-        let result = String::from_utf8(output_buffer)?; // Process the output
+    #[test]
+    fn compare_leaves_context_until_adopted() {
+        let transport = Arc::new(FakeTransport {
+            busy: Some("gpt-4".to_string()),
+            ..Default::default()
+        });
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let models = ["gpt-4".to_string(), "gpt-3.5-turbo".to_string()];
+        let comparisons = api_interface.compare("Hi", &models).unwrap();
+        assert_eq!(comparisons.len(), 2);
+        assert_eq!(comparisons[0].model, "gpt-4");
+        assert!(comparisons[0].reply.is_err());
+        assert_eq!(comparisons[1].reply.as_deref(), Ok("Hello"));
+        assert_eq!(api_interface.context.len(), 0);
+        assert!(api_interface.context.cost > 0.0);
+        let charges = api_interface.ledger().charges().unwrap();
+        assert_eq!(charges.len(), 1);
+        assert_eq!(charges[0].mode, "compare");
+
+        assert!(!api_interface.adopt("Hi", &comparisons[0]));
+        assert!(api_interface.adopt("Hi", &comparisons[1]));
+        assert_eq!(api_interface.context.prompt_response, ["Hi", "Hello"]);
+    }
 
-        let headers_hm: HashMap<String, String> = header_buffer
-            .into_iter()
-            .filter_map(|item| {
-                let mut parts = item.splitn(2, ':');
-                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                    Some((key.to_string(), value.trim().to_string()))
-                } else {
-                    None
-                }
+    #[test]
+    fn compare_checks_the_budget_for_all_models() {
+        let transport = Arc::new(FakeTransport::default());
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        let messages = api_interface.chat_messages("Hi");
+        let one = api_interface.estimate("gpt-4", &messages).unwrap();
+
+        // Either would fit, but not both
+        api_interface.budget.set(Period::Day, Some(one * 1.5));
+        let models = ["gpt-4".to_string(), "gpt-4".to_string()];
+        let err = api_interface.compare("Hi", &models).unwrap_err();
+        assert!(err.to_string().contains("budget"), "{err}");
+        assert!(transport.requests.lock().unwrap().is_empty());
+
+        api_interface.budget.set(Period::Day, Some(one * 2.5));
+        assert_eq!(api_interface.compare("Hi", &models).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn bad_transport_configuration_is_an_error() {
+        let builder = ApiInterface::builder(String::new(), 0, 0.0)
+            .root_certificate(PathBuf::from("/no/such/ca.pem"));
+        assert!(builder.build_transport().is_err());
+        assert!(builder.build().is_err());
+    }
+
+    /// Answers every request with `status` and `body`
+    #[derive(Debug)]
+    struct FixedReply(StatusCode, &'static str);
+
+    impl Transport for FixedReply {
+        fn send(
+            &self,
+            _request: &HttpRequest,
+            _on_chunk: Option<ChunkHandler>,
+        ) -> Result<HttpResponse, Box<dyn Error>> {
+            Ok(HttpResponse {
+                status: self.0,
+                headers: HashMap::new(),
+                body: self.1.as_bytes().to_vec(),
             })
-            .collect();
-        Ok((headers_hm, result))
+        }
+    }
+
+    #[test]
+    fn bad_replies_are_errors() {
+        let error_type = |status: StatusCode, body: &'static str| -> String {
+            let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+                .transport(Arc::new(FixedReply(status, body)))
+                .build()
+                .unwrap();
+            let completion = api_interface.completion("Hi", "davinci-002").unwrap_err();
+            let models = api_interface.model_list().unwrap_err();
+            let error_type = |err: Box<dyn Error>| match err.downcast_ref::<ApiError>() {
+                Some(ApiError {
+                    error_type: ApiErrorType::Status(..),
+                    ..
+                }) => "status",
+                Some(ApiError {
+                    error_type: ApiErrorType::BadJson(_),
+                    ..
+                }) => "json",
+                _ => "other",
+            };
+            format!("{} {}", error_type(completion), error_type(models))
+        };
+        assert_eq!(
+            error_type(StatusCode::SERVICE_UNAVAILABLE, "Down"),
+            "status status"
+        );
+        assert_eq!(error_type(StatusCode::OK, "Not JSON"), "json json");
+
+        let mut api_interface = ApiInterface::builder("key".to_string(), 100, 0.5)
+            .transport(Arc::new(FixedReply(
+                StatusCode::OK,
+                r#"{"object": "text_completion", "choices": [], "model": "davinci-002",
+                    "usage": {"prompt_tokens": 1, "completion_tokens": 0, "total_tokens": 1}}"#,
+            )))
+            .build()
+            .unwrap();
+        let err = api_interface.completion("Hi", "davinci-002").unwrap_err();
+        assert!(err.to_string().contains("No choices"));
     }
 }
@@ -0,0 +1,423 @@
+//! Apply unified diffs, as models write them, to files.
+//!
+//! Models get the line numbers and counts in hunk headers wrong, so
+//! they are not trusted.  A hunk is applied where its context and
+//! removed lines are found, nearest to where the header says, and
+//! matching trailing white space loosely if need be.  A patch is
+//! applied to all its files or to none.
+//!
+//! The paths come from a model, so they must be relative and stay
+//! inside the directory the patch is applied to
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+fn patch_error(message: String) -> Box<dyn Error> {
+    Box::new(ApiError::new(ApiErrorType::Error(message), HashMap::new()))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Debug, Clone)]
+struct Hunk {
+    /// From the header.  1 based, 0 if it is not known
+    old_start: usize,
+    lines: Vec<Line>,
+}
+
+impl Hunk {
+    /// The lines it replaces
+    fn before(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                Line::Context(s) | Line::Remove(s) => Some(s.as_str()),
+                Line::Add(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines it replaces them with
+    fn after(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                Line::Context(s) | Line::Add(s) => Some(s.as_str()),
+                Line::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The changes to one file
+#[derive(Debug, Clone)]
+pub struct FilePatch {
+    /// `None` for a new file
+    pub old_path: Option<PathBuf>,
+
+    /// `None` for a deleted file
+    pub new_path: Option<PathBuf>,
+
+    hunks: Vec<Hunk>,
+
+    /// "\ No newline at end of file" after the last new line
+    no_newline: bool,
+}
+
+impl FilePatch {
+    pub fn added(&self) -> usize {
+        self.hunks
+            .iter()
+            .flat_map(|h| h.lines.iter())
+            .filter(|l| matches!(l, Line::Add(_)))
+            .count()
+    }
+
+    pub fn removed(&self) -> usize {
+        self.hunks
+            .iter()
+            .flat_map(|h| h.lines.iter())
+            .filter(|l| matches!(l, Line::Remove(_)))
+            .count()
+    }
+
+    /// The new contents of the file, `None` if it is deleted.  A
+    /// file to be deleted must exist and have the lines it removes
+    fn patched(&self, dir: &Path) -> Result<Option<String>, Box<dyn Error>> {
+        let path = match (self.new_path.as_ref(), self.old_path.as_ref()) {
+            (Some(path), _) | (None, Some(path)) => path,
+            (None, None) => return Err(patch_error("No file to patch".to_string())),
+        };
+        let original = match self.old_path.as_ref() {
+            Some(old_path) => fs::read_to_string(dir.join(old_path))
+                .map_err(|err| patch_error(format!("{err}: Cannot read {}", old_path.display())))?,
+            None if dir.join(path).exists() => {
+                return Err(patch_error(format!(
+                    "{} is a new file, but it exists",
+                    path.display()
+                )));
+            }
+            None => String::new(),
+        };
+        let mut lines: Vec<String> = original.lines().map(|l| l.to_string()).collect();
+
+        // Where the last hunk ended, and how far hunks have moved the
+        // lines after them
+        let mut from = 0;
+        let mut offset: isize = 0;
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            let old = hunk.before();
+            let expected = if hunk.old_start == 0 {
+                from
+            } else {
+                ((hunk.old_start as isize - 1 + offset).max(0) as usize).max(from)
+            };
+            let at = find(&lines, &old, from, expected).ok_or_else(|| {
+                patch_error(format!(
+                    "Change {} does not apply to {}",
+                    i + 1,
+                    path.display()
+                ))
+            })?;
+            let new: Vec<String> = hunk.after().iter().map(|l| l.to_string()).collect();
+            offset += new.len() as isize - old.len() as isize;
+            from = at + new.len();
+            lines.splice(at..at + old.len(), new);
+        }
+        if self.new_path.is_none() {
+            return Ok(None);
+        }
+        let mut result = lines.join("\n");
+        if !self.no_newline && !lines.is_empty() {
+            result.push('\n');
+        }
+        Ok(Some(result))
+    }
+}
+
+impl fmt::Display for FilePatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = format!("+{} -{}", self.added(), self.removed());
+        match (self.old_path.as_ref(), self.new_path.as_ref()) {
+            (None, Some(new)) => write!(f, "{}: new file {change}", new.display()),
+            (Some(old), None) => write!(f, "{}: deleted", old.display()),
+            (Some(old), Some(new)) if old != new => {
+                write!(
+                    f,
+                    "{}: renamed from {} {change}",
+                    new.display(),
+                    old.display()
+                )
+            }
+            (_, Some(new)) => write!(f, "{}: {change}", new.display()),
+            (None, None) => write!(f, "No file"),
+        }
+    }
+}
+
+/// Where `old` is in `lines`, at or after `from`, nearest to
+/// `expected`.  Exactly if it can be, otherwise ignoring white space
+/// at the ends of lines
+fn find(lines: &[String], old: &[&str], from: usize, expected: usize) -> Option<usize> {
+    if lines.len() < old.len() || from > lines.len() - old.len() {
+        return None;
+    }
+    let last = lines.len() - old.len();
+    let expected = expected.min(last);
+    let mut candidates: Vec<usize> = (from..=last).collect();
+    candidates.sort_by_key(|at| at.abs_diff(expected));
+    let matches = |at: usize, loose: bool| {
+        old.iter().enumerate().all(|(i, line)| {
+            if loose {
+                lines[at + i].trim_end() == line.trim_end()
+            } else {
+                lines[at + i] == *line
+            }
+        })
+    };
+    candidates
+        .iter()
+        .find(|at| matches(**at, false))
+        .or_else(|| candidates.iter().find(|at| matches(**at, true)))
+        .copied()
+}
+
+/// The path in a "---" or "+++" line.  `None` for "/dev/null"
+fn header_path(header: &str) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    // A time stamp may follow a tab
+    let path = header[4..].split('\t').next().unwrap_or("").trim();
+    if path == "/dev/null" {
+        return Ok(None);
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    let path = PathBuf::from(path);
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(patch_error(format!(
+            "Will not patch {}: Paths must be relative and inside the directory",
+            path.display()
+        )));
+    }
+    Ok(Some(path))
+}
+
+/// The old start line from "@@ -12,7 +12,8 @@"
+fn hunk_start(header: &str) -> usize {
+    header
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix('-'))
+        .and_then(|range| range.split(',').next())
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(0)
+}
+
+/// A unified diff of one or more files
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub files: Vec<FilePatch>,
+}
+
+impl Patch {
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let lines: Vec<&str> = text.lines().collect();
+        let mut files: Vec<FilePatch> = vec![];
+        let mut i = 0;
+        while i < lines.len() {
+            let line = lines[i];
+            let next = lines.get(i + 1).copied().unwrap_or("");
+            if line.starts_with("--- ") && next.starts_with("+++ ") {
+                let old_path = header_path(line)?;
+                let new_path = header_path(next)?;
+                if old_path.is_none() && new_path.is_none() {
+                    return Err(patch_error("A change from nothing to nothing".to_string()));
+                }
+                files.push(FilePatch {
+                    old_path,
+                    new_path,
+                    hunks: vec![],
+                    no_newline: false,
+                });
+                i += 2;
+                continue;
+            }
+            match (files.last_mut(), line.chars().next()) {
+                (Some(file), _) if line.starts_with("@@") => file.hunks.push(Hunk {
+                    old_start: hunk_start(line),
+                    lines: vec![],
+                }),
+                (Some(file), first) => {
+                    if let Some(hunk) = file.hunks.last_mut() {
+                        match first {
+                            Some(' ') => hunk.lines.push(Line::Context(line[1..].to_string())),
+                            Some('-') => hunk.lines.push(Line::Remove(line[1..].to_string())),
+                            Some('+') => hunk.lines.push(Line::Add(line[1..].to_string())),
+                            Some('\\') => {
+                                file.no_newline =
+                                    !matches!(hunk.lines.last(), Some(Line::Remove(_)))
+                            }
+                            // Models drop the space from empty context lines
+                            None => hunk.lines.push(Line::Context(String::new())),
+                            // "diff --git", "index" and the like
+                            Some(_) => (),
+                        }
+                    }
+                }
+                (None, _) => (),
+            }
+            i += 1;
+        }
+        for file in files.iter_mut() {
+            for hunk in file.hunks.iter_mut() {
+                // Blank lines after a hunk are not part of it
+                while hunk.lines.last() == Some(&Line::Context(String::new())) {
+                    hunk.lines.pop();
+                }
+            }
+            if file.hunks.is_empty() && file.new_path.is_some() && file.old_path.is_some() {
+                return Err(patch_error(format!("No changes to {file}")));
+            }
+        }
+        if files.is_empty() {
+            return Err(patch_error("Not a unified diff".to_string()));
+        }
+        Ok(Self { files })
+    }
+
+    /// Apply the patch to the files in `dir`.  If any file cannot be
+    /// patched none are changed
+    pub fn apply(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut changes: Vec<(&FilePatch, Option<String>)> = vec![];
+        for file in self.files.iter() {
+            changes.push((file, file.patched(dir)?));
+        }
+        for (file, contents) in changes {
+            match (contents, file.new_path.as_ref()) {
+                (Some(contents), Some(new_path)) => {
+                    let path = dir.join(new_path);
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(path, contents)?;
+                    if let Some(old_path) = file.old_path.as_ref().filter(|p| *p != new_path) {
+                        fs::remove_file(dir.join(old_path))?;
+                    }
+                }
+                _ => {
+                    if let Some(old_path) = file.old_path.as_ref() {
+                        fs::remove_file(dir.join(old_path))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Patch {
+    /// A line for each file
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let files: Vec<String> = self.files.iter().map(|file| file.to_string()).collect();
+        write!(f, "{}", files.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_what_models_write() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(
+            dir.path().join("src/main.rs"),
+            "// A header\n\nfn main() {\n    println!(\"hello\");\n}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("old.txt"), "gone\n").unwrap();
+
+        // The line numbers and counts are wrong, and the empty
+        // context line has lost its space
+        let diff = "diff --git a/src/main.rs b/src/main.rs\n\
+                    --- a/src/main.rs\n\
+                    +++ b/src/main.rs\n\
+                    @@ -1,3 +1,3 @@\n\
+                    \n\
+                    \x20fn main() {\n\
+                    -    println!(\"hello\");\n\
+                    +    println!(\"goodbye\");\n\
+                    \x20}\n\
+                    --- /dev/null\n\
+                    +++ b/src/lib.rs\n\
+                    @@ -0,0 +1 @@\n\
+                    +pub mod x;\n\
+                    --- a/old.txt\n\
+                    +++ /dev/null\n\
+                    @@ -1 +0,0 @@\n\
+                    -gone\n";
+        let patch = Patch::parse(diff).unwrap();
+        assert_eq!(
+            patch.to_string(),
+            "src/main.rs: +1 -1\nsrc/lib.rs: new file +1 -0\nold.txt: deleted"
+        );
+        patch.apply(dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("src/main.rs")).unwrap(),
+            "// A header\n\nfn main() {\n    println!(\"goodbye\");\n}\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("src/lib.rs")).unwrap(),
+            "pub mod x;\n"
+        );
+        assert!(!dir.path().join("old.txt").exists());
+
+        // It no longer applies, and nothing is changed
+        let err = Patch::parse(diff).unwrap().apply(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("does not apply"));
+
+        // Paths stay inside the directory
+        assert!(Patch::parse("--- a/../x\n+++ b/../x\n@@ -1 +1 @@\n-a\n+b\n").is_err());
+        assert!(Patch::parse("--- /etc/passwd\n+++ /etc/passwd\n@@\n-a\n+b\n").is_err());
+    }
+
+    #[test]
+    fn bad_deletion_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "kept\n").unwrap();
+        let modify = "--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-one\n+two\n";
+
+        // The file to delete is missing
+        let diff = format!("{modify}--- a/missing.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-gone\n");
+        let err = Patch::parse(&diff).unwrap().apply(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("Cannot read missing.txt"), "{err}");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "one\n"
+        );
+
+        // The file to delete does not have the lines removed
+        let diff = format!("{modify}--- a/b.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-gone\n");
+        let err = Patch::parse(&diff).unwrap().apply(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("does not apply to b.txt"), "{err}");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "one\n"
+        );
+        assert!(dir.path().join("b.txt").exists());
+    }
+}
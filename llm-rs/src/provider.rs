@@ -0,0 +1,36 @@
+//! The services that host the models.  Which one to use is decided
+//! by the name of the model
+use crate::ollama_interface::OLLAMA_PREFIX;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    OpenAi,
+    Anthropic,
+    Ollama,
+}
+
+impl Provider {
+    /// Anthropic model names all start "claude-".  Local models are
+    /// named "ollama/<name>".  Everything else goes to OpenAI
+    pub fn from_model(model: &str) -> Provider {
+        if model.starts_with("claude-") {
+            Provider::Anthropic
+        } else if model.starts_with(OLLAMA_PREFIX) {
+            Provider::Ollama
+        } else {
+            Provider::OpenAi
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            Provider::OpenAi => "openai",
+            Provider::Anthropic => "anthropic",
+            Provider::Ollama => "ollama",
+        };
+        write!(f, "{str}")
+    }
+}
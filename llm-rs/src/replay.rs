@@ -0,0 +1,249 @@
+//! Record responses to a file, and replay them in place of the
+//! network.  A run against a recording is reproducible: the same
+//! requests get the same responses, without cost.
+//!
+//! The recording is a JSON array of `Interaction`s.  A request is
+//! matched by its method, URI and body, not its headers, so the keys
+//! used to record it are not needed to replay it (and are not
+//! stored).  If the same request was recorded more than once the
+//! responses are replayed in order, and the last one repeats
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use crate::cache::canonical;
+use crate::transport::Body;
+use crate::transport::ChunkHandler;
+use crate::transport::FormPart;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::Method;
+use crate::transport::Transport;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A request and the response to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    /// Identifies the request.  See `request_key`
+    pub key: String,
+
+    /// For people reading the recording
+    pub method: String,
+    pub uri: String,
+
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+fn method_name(method: &Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Delete => "DELETE",
+    }
+}
+
+/// What identifies `request`: a hash of its method, URI and body
+pub fn request_key(request: &HttpRequest) -> String {
+    let body = match &request.body {
+        Body::Empty => String::new(),
+        Body::Json(data) => canonical(data).to_string(),
+        Body::Multipart(parts) => parts
+            .iter()
+            .map(|part| match part {
+                FormPart::Text { name, value } => format!("{name}={value}"),
+                FormPart::File { name, path } => format!("{name}=@{}", path.display()),
+            })
+            .collect::<Vec<String>>()
+            .join("&"),
+    };
+    let mut hasher = Sha256::new();
+    for part in [
+        method_name(&request.method),
+        request.uri.as_str(),
+        body.as_str(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0_u8]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Sends requests with another `Transport` and records them in a
+/// file.  The file is rewritten after each request
+#[derive(Debug)]
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    path: PathBuf,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl RecordingTransport {
+    /// Record to `path`.  Anything already there is replaced
+    pub fn new(inner: Arc<dyn Transport>, path: &Path) -> Result<Self, Box<dyn Error>> {
+        fs::write(path, "[]")?;
+        Ok(Self {
+            inner,
+            path: path.to_path_buf(),
+            interactions: Mutex::new(vec![]),
+        })
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let response = self.inner.send(request, on_chunk)?;
+        let interaction = Interaction {
+            key: request_key(request),
+            method: method_name(&request.method).to_string(),
+            uri: request.uri.clone(),
+            status: response.status.as_u16(),
+            headers: response.headers.clone(),
+            body: String::from_utf8_lossy(&response.body).to_string(),
+        };
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.push(interaction);
+        fs::write(
+            self.path.as_path(),
+            serde_json::to_string_pretty(&*interactions)?,
+        )?;
+        Ok(response)
+    }
+}
+
+/// Answers requests from a recording.  Nothing goes to the network.
+/// A request that was not recorded is an error
+#[derive(Debug)]
+pub struct ReplayTransport {
+    interactions: Mutex<HashMap<String, VecDeque<Interaction>>>,
+}
+
+impl ReplayTransport {
+    pub fn new(interactions: Vec<Interaction>) -> Self {
+        let mut by_key: HashMap<String, VecDeque<Interaction>> = HashMap::new();
+        for interaction in interactions.into_iter() {
+            by_key
+                .entry(interaction.key.clone())
+                .or_default()
+                .push_back(interaction);
+        }
+        Self {
+            interactions: Mutex::new(by_key),
+        }
+    }
+
+    /// Replay the recording at `path`
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            ApiError::new(
+                ApiErrorType::Error(format!("{err}: Cannot read {}", path.display())),
+                HashMap::new(),
+            )
+        })?;
+        Ok(Self::new(serde_json::from_str(contents.as_str())?))
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let interaction = {
+            let mut interactions = self.interactions.lock().unwrap();
+            let recorded = interactions.get_mut(&request_key(request));
+            match recorded {
+                Some(recorded) if recorded.len() > 1 => recorded.pop_front(),
+                Some(recorded) => recorded.front().cloned(),
+                None => None,
+            }
+        };
+        let interaction = match interaction {
+            Some(interaction) => interaction,
+            None => {
+                return Err(Box::new(ApiError::new(
+                    ApiErrorType::FailedRequest(format!(
+                        "Not in the recording: {} {}",
+                        method_name(&request.method),
+                        request.uri
+                    )),
+                    HashMap::new(),
+                )));
+            }
+        };
+        let body = interaction.body.into_bytes();
+        if let Some(on_chunk) = on_chunk {
+            on_chunk(&body);
+        }
+        Ok(HttpResponse {
+            status: StatusCode::from_u16(interaction.status)?,
+            headers: interaction.headers,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Counts the requests it answers
+    #[derive(Debug, Default)]
+    struct CountingTransport {
+        count: Mutex<usize>,
+    }
+
+    impl Transport for CountingTransport {
+        fn send(
+            &self,
+            _request: &HttpRequest,
+            _on_chunk: Option<ChunkHandler>,
+        ) -> Result<HttpResponse, Box<dyn Error>> {
+            let mut count = self.count.lock().unwrap();
+            *count += 1;
+            Ok(HttpResponse {
+                status: StatusCode::OK,
+                headers: HashMap::new(),
+                body: format!("{}", *count).into_bytes(),
+            })
+        }
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.json");
+        let request = HttpRequest::post_json("https://example.com/v1/chat", json!({"a": 1}));
+        let recorder =
+            RecordingTransport::new(Arc::new(CountingTransport::default()), path.as_path())
+                .unwrap();
+        recorder.send(&request, None).unwrap();
+        recorder.send(&request, None).unwrap();
+
+        let replay = ReplayTransport::from_file(path.as_path()).unwrap();
+        let bodies: Vec<Vec<u8>> = (0..3)
+            .map(|_| replay.send(&request, None).unwrap().body)
+            .collect();
+        assert_eq!(bodies, [b"1", b"2", b"2"]);
+        assert!(replay
+            .send(&HttpRequest::get("https://example.com/v1/models"), None)
+            .is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! Every request is reported as a `RequestEvent`.  Events are logged
+//! through the `log` crate, target "llm_rs::telemetry", as JSON.  They
+//! can also be appended to a file, one JSON object per line.
+//!
+//! `ObservedTransport` wraps another `Transport` and reports what
+//! passes through it
+use crate::json::Usage;
+use crate::openai_interface::ApiInterface;
+use crate::transport::Body;
+use crate::transport::ChunkHandler;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::Transport;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The `log` target events are logged to
+pub const TELEMETRY_TARGET: &str = "llm_rs::telemetry";
+
+/// Headers the providers use for the request id
+const REQUEST_ID_HEADERS: [&str; 3] = ["x-request-id", "request-id", "apim-request-id"];
+
+/// Headers the providers use for remaining requests and tokens
+const REQUESTS_REMAINING_HEADERS: [&str; 2] = [
+    "x-ratelimit-remaining-requests",
+    "anthropic-ratelimit-requests-remaining",
+];
+const TOKENS_REMAINING_HEADERS: [&str; 2] = [
+    "x-ratelimit-remaining-tokens",
+    "anthropic-ratelimit-tokens-remaining",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEvent {
+    /// When the request was sent.  RFC 3339
+    pub timestamp: String,
+
+    /// The URI, without the query
+    pub endpoint: String,
+
+    pub model: Option<String>,
+
+    pub latency_ms: u64,
+
+    /// The HTTP status.  `None` if there was no response
+    pub status: Option<u16>,
+
+    pub request_id: Option<String>,
+
+    pub usage: Option<Usage>,
+
+    /// In cents
+    pub cost: Option<f64>,
+
+    pub requests_remaining: Option<String>,
+
+    pub tokens_remaining: Option<String>,
+
+    /// Why there was no response
+    pub error: Option<String>,
+}
+
+impl RequestEvent {
+    fn new(request: &HttpRequest) -> Self {
+        let model = match &request.body {
+            Body::Json(data) => data["model"].as_str().map(|s| s.to_string()),
+            _ => None,
+        };
+        Self {
+            timestamp: Utc::now().to_rfc3339(),
+            endpoint: request
+                .uri
+                .split('?')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            model,
+            latency_ms: 0,
+            status: None,
+            request_id: None,
+            usage: None,
+            cost: None,
+            requests_remaining: None,
+            tokens_remaining: None,
+            error: None,
+        }
+    }
+
+    /// Fill in what is known from the response
+    fn response(&mut self, response: &HttpResponse) {
+        self.status = Some(response.status.as_u16());
+        self.request_id = find_header(&response.headers, &REQUEST_ID_HEADERS);
+        self.requests_remaining = find_header(&response.headers, &REQUESTS_REMAINING_HEADERS);
+        self.tokens_remaining = find_header(&response.headers, &TOKENS_REMAINING_HEADERS);
+        // Ollama is sent the model's local name, without the prefix,
+        // but local models are free anyway
+        let mut local = false;
+        self.usage = match response.json::<Value>() {
+            Ok(body) => {
+                local = body.get("eval_count").is_some();
+                usage(&body)
+            }
+            Err(_) => stream_usage(response.text().as_str()),
+        };
+        if let (Some(usage), Some(model)) = (self.usage.as_ref(), self.model.as_deref()) {
+            self.cost = if local {
+                Some(0.0)
+            } else {
+                ApiInterface::price(usage, model)
+            };
+        }
+    }
+}
+
+fn find_header(headers: &HashMap<String, String>, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .map(|v| v.to_string())
+}
+
+/// Token usage from a response body.  OpenAI, Anthropic and Ollama
+/// all report it differently.  Streamed responses are not JSON and
+/// have no usage here
+fn usage(body: &Value) -> Option<Usage> {
+    let (prompt_tokens, completion_tokens) = if let Some(usage) = body.get("usage") {
+        if usage.get("input_tokens").is_some() {
+            // Anthropic
+            (&usage["input_tokens"], &usage["output_tokens"])
+        } else {
+            (&usage["prompt_tokens"], &usage["completion_tokens"])
+        }
+    } else if body.get("eval_count").is_some() {
+        // Ollama
+        (&body["prompt_eval_count"], &body["eval_count"])
+    } else {
+        return None;
+    };
+    let prompt_tokens = prompt_tokens.as_u64().unwrap_or(0) as u32;
+    let completion_tokens = completion_tokens.as_u64().unwrap_or(0) as u32;
+    Some(Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    })
+}
+
+/// Token usage from a streamed Anthropic response.  The prompt
+/// tokens are in the "message_start" event and the completion tokens
+/// in "message_delta"
+fn stream_usage(body: &str) -> Option<Usage> {
+    let mut result: Option<Usage> = None;
+    for line in body.lines() {
+        let event = match line.strip_prefix("data:") {
+            Some(event) => event,
+            None => continue,
+        };
+        let event: Value = match serde_json::from_str(event.trim()) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        let usage = result.get_or_insert(Usage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        });
+        match event["type"].as_str() {
+            Some("message_start") => {
+                usage.prompt_tokens = event["message"]["usage"]["input_tokens"]
+                    .as_u64()
+                    .unwrap_or(0) as u32;
+            }
+            Some("message_delta") => {
+                usage.completion_tokens =
+                    event["usage"]["output_tokens"].as_u64().unwrap_or(0) as u32;
+            }
+            _ => (),
+        }
+        usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+    }
+    result
+}
+
+/// Where events go.  Always to the log, and to the file if there is
+/// one
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    sink: Option<Mutex<File>>,
+}
+
+impl Telemetry {
+    /// Also append events to the file at `path`
+    pub fn with_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            sink: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn emit(&self, event: &RequestEvent) {
+        let json = match serde_json::to_string(event) {
+            Ok(json) => json,
+            Err(err) => {
+                log::warn!(target: TELEMETRY_TARGET, "Cannot serialise event: {err}");
+                return;
+            }
+        };
+        log::info!(target: TELEMETRY_TARGET, "{json}");
+        if let Some(sink) = self.sink.as_ref() {
+            let mut file = sink.lock().unwrap();
+            if let Err(err) = writeln!(file, "{json}") {
+                log::warn!(target: TELEMETRY_TARGET, "Cannot write event: {err}");
+            }
+        }
+    }
+}
+
+/// A `Transport` that reports every request sent through it
+#[derive(Debug)]
+pub struct ObservedTransport {
+    inner: Arc<dyn Transport>,
+    telemetry: Telemetry,
+}
+
+impl ObservedTransport {
+    pub fn new(inner: Arc<dyn Transport>, telemetry: Telemetry) -> Self {
+        Self { inner, telemetry }
+    }
+}
+
+impl Transport for ObservedTransport {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        let mut event = RequestEvent::new(request);
+        let start = Instant::now();
+        let result = self.inner.send(request, on_chunk);
+        event.latency_ms = start.elapsed().as_millis() as u64;
+        match result.as_ref() {
+            Ok(response) => event.response(response),
+            Err(err) => event.error = Some(format!("{err}")),
+        }
+        self.telemetry.emit(&event);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn usage_from_each_provider() {
+        let openai = json!({"usage": {"prompt_tokens": 3, "completion_tokens": 4}});
+        let anthropic = json!({"usage": {"input_tokens": 3, "output_tokens": 4}});
+        let ollama = json!({"prompt_eval_count": 3, "eval_count": 4});
+        for body in [openai, anthropic, ollama] {
+            let usage = usage(&body).unwrap();
+            assert_eq!(usage.prompt_tokens, 3);
+            assert_eq!(usage.total_tokens, 7);
+        }
+        assert!(usage(&json!({"data": []})).is_none());
+    }
+
+    #[test]
+    fn usage_from_anthropic_stream() {
+        let body = "event: message_start\n\
+                    data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":3}}}\n\n\
+                    event: message_delta\n\
+                    data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":4}}\n\n";
+        let usage = stream_usage(body).unwrap();
+        assert_eq!(usage.completion_tokens, 4);
+        assert_eq!(usage.total_tokens, 7);
+    }
+}
@@ -0,0 +1,618 @@
+//! Prompt templates.  A template is a file, "<name>.tpl", in a
+//! template directory.  It starts with a header that declares its
+//! parameters, then the text of the prompt:
+//!
+//! ```text
+//! ---
+//! topic: string
+//! words: int = 100
+//! formal: bool = false
+//! ---
+//! Write {{words}} words about {{topic}}.
+//! {% if formal %}Use a formal tone.{% else %}Keep it light.{% endif %}
+//! {% include signature %}
+//! {% file notes/{{topic}}.txt %}
+//! ```
+//!
+//! * Parameters are `string`, `int`, `float` or `bool`.  Those
+//!   without a default must be supplied
+//! * `{{name}}` is replaced by the parameter's value
+//! * `{% if name %}...{% else %}...{% endif %}` depends on whether
+//!   the parameter is true: not false, zero or empty.  `{% if not
+//!   name %}` is the opposite.  They can be nested
+//! * `{% include name %}` is replaced by another template, rendered
+//!   with the same values
+//! * `{% file path %}` is replaced by the contents of the file.
+//!   Relative paths are relative to the template directory
+//!
+//! `{{name}}` can be used in the arguments of `include` and `file`
+use crate::api_error::ApiError;
+use crate::api_error::ApiErrorType;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Template files have this extension
+const TEMPLATE_EXTENSION: &str = "tpl";
+
+/// Marks the beginning and end of the header
+const HEADER_MARKER: &str = "---";
+
+/// Includes deeper than this are assumed to be a loop
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+fn template_error(msg: String) -> Box<dyn Error> {
+    Box::new(ApiError::new(ApiErrorType::Error(msg), HashMap::new()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl FromStr for ParamType {
+    type Err = Box<dyn Error>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(ParamType::String),
+            "int" => Ok(ParamType::Int),
+            "float" => Ok(ParamType::Float),
+            "bool" => Ok(ParamType::Bool),
+            _ => Err(template_error(format!("Unknown parameter type: {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamType::String => write!(f, "string"),
+            ParamType::Int => write!(f, "int"),
+            ParamType::Float => write!(f, "float"),
+            ParamType::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ParamValue {
+    /// Make a value of type `kind` from text
+    pub fn parse(kind: ParamType, s: &str) -> Result<Self, Box<dyn Error>> {
+        let bad = |err: &dyn fmt::Display| template_error(format!("Not a {kind}: {s}: {err}"));
+        Ok(match kind {
+            ParamType::String => ParamValue::String(s.to_string()),
+            ParamType::Int => ParamValue::Int(s.parse().map_err(|e| bad(&e))?),
+            ParamType::Float => ParamValue::Float(s.parse().map_err(|e| bad(&e))?),
+            ParamType::Bool => ParamValue::Bool(s.parse().map_err(|e| bad(&e))?),
+        })
+    }
+
+    /// Used by `{% if %}`
+    pub fn is_true(&self) -> bool {
+        match self {
+            ParamValue::String(s) => !s.is_empty(),
+            ParamValue::Int(i) => *i != 0,
+            ParamValue::Float(f) => *f != 0.0,
+            ParamValue::Bool(b) => *b,
+        }
+    }
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamValue::String(s) => write!(f, "{s}"),
+            ParamValue::Int(i) => write!(f, "{i}"),
+            ParamValue::Float(x) => write!(f, "{x}"),
+            ParamValue::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub kind: ParamType,
+
+    /// If `None` the parameter must be supplied
+    pub default: Option<ParamValue>,
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.kind)?;
+        if let Some(default) = self.default.as_ref() {
+            write!(f, " = {default}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The parsed body of a template
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Var(String),
+    Include(String),
+    File(String),
+    If {
+        name: String,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub params: Vec<Param>,
+    body: Vec<Node>,
+}
+
+impl Template {
+    /// Parse the text of a template.  The header is optional
+    pub fn parse(name: &str, text: &str) -> Result<Self, Box<dyn Error>> {
+        let (params, body) = match Self::split_header(name, text)? {
+            (Some(header), body) => (Self::parse_header(name, header)?, body),
+            (None, body) => (vec![], body),
+        };
+        let mut tokens = Self::tokenise(name, body)?.into_iter();
+        let body = Self::parse_nodes(name, &mut tokens, false)?.0;
+        Ok(Self {
+            name: name.to_string(),
+            params,
+            body,
+        })
+    }
+
+    /// Separate the header, between lines of "---", from the body
+    fn split_header<'a>(
+        name: &str,
+        text: &'a str,
+    ) -> Result<(Option<&'a str>, &'a str), Box<dyn Error>> {
+        let mut offset = 0;
+        let mut header_start: Option<usize> = None;
+        for line in text.split_inclusive('\n') {
+            let end = offset + line.len();
+            if line.trim() == HEADER_MARKER {
+                if let Some(start) = header_start {
+                    return Ok((Some(&text[start..offset]), &text[end..]));
+                }
+                header_start = Some(end);
+            } else if header_start.is_none() {
+                // No header
+                return Ok((None, text));
+            }
+            offset = end;
+        }
+        match header_start {
+            Some(_) => Err(template_error(format!("{name}: Header not closed"))),
+            None => Ok((None, text)),
+        }
+    }
+
+    /// Each line is "name: type" or "name: type = default"
+    fn parse_header(name: &str, header: &str) -> Result<Vec<Param>, Box<dyn Error>> {
+        let mut params = vec![];
+        for line in header.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let (param, rest) = line
+                .split_once(':')
+                .ok_or_else(|| template_error(format!("{name}: Bad parameter: {line}")))?;
+            let (kind, default) = match rest.split_once('=') {
+                Some((kind, default)) => (kind, Some(default.trim())),
+                None => (rest, None),
+            };
+            let kind = ParamType::from_str(kind.trim())?;
+            params.push(Param {
+                name: param.trim().to_string(),
+                kind,
+                default: match default {
+                    Some(default) => Some(ParamValue::parse(kind, default)?),
+                    None => None,
+                },
+            });
+        }
+        Ok(params)
+    }
+
+    /// Split the body into text, `{{var}}` and `{% tag %}`
+    fn tokenise(name: &str, body: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+        let mut tokens = vec![];
+        let mut rest = body;
+        while !rest.is_empty() {
+            let next = [rest.find("{{"), rest.find("{%")]
+                .into_iter()
+                .flatten()
+                .min();
+            let start = match next {
+                Some(start) => start,
+                None => {
+                    tokens.push(Token::Text(rest.to_string()));
+                    break;
+                }
+            };
+            if start > 0 {
+                tokens.push(Token::Text(rest[..start].to_string()));
+            }
+            let is_var = rest[start..].starts_with("{{");
+            let close = if is_var { "}}" } else { "%}" };
+            let end = rest[start + 2..]
+                .find(close)
+                .ok_or_else(|| template_error(format!("{name}: Unclosed {}", &rest[start..])))?
+                + start
+                + 2;
+            let inner = rest[start + 2..end].trim().to_string();
+            tokens.push(if is_var {
+                Token::Var(inner)
+            } else {
+                Token::Tag(inner)
+            });
+            rest = &rest[end + 2..];
+        }
+        Ok(tokens)
+    }
+
+    /// Build nodes until the end, or until `{% else %}` or `{% endif
+    /// %}` if `in_if`.  Returns the nodes and the tag that stopped it
+    fn parse_nodes(
+        name: &str,
+        tokens: &mut std::vec::IntoIter<Token>,
+        in_if: bool,
+    ) -> Result<(Vec<Node>, Option<String>), Box<dyn Error>> {
+        let mut nodes = vec![];
+        while let Some(token) = tokens.next() {
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text)),
+                Token::Var(var) => nodes.push(Node::Var(var)),
+                Token::Tag(tag) => {
+                    let mut words = tag.splitn(2, char::is_whitespace);
+                    let keyword = words.next().unwrap_or_default();
+                    let arg = words.next().unwrap_or_default().trim().to_string();
+                    match keyword {
+                        "else" | "endif" if in_if => return Ok((nodes, Some(keyword.to_string()))),
+                        "include" => nodes.push(Node::Include(arg)),
+                        "file" => nodes.push(Node::File(arg)),
+                        "if" => {
+                            let (negate, var) = match arg.strip_prefix("not ") {
+                                Some(var) => (true, var.trim().to_string()),
+                                None => (false, arg),
+                            };
+                            let (then, end) = Self::parse_nodes(name, tokens, true)?;
+                            let otherwise = match end.as_deref() {
+                                Some("else") => match Self::parse_nodes(name, tokens, true)? {
+                                    (otherwise, Some(end)) if end == "endif" => otherwise,
+                                    _ => {
+                                        return Err(template_error(format!(
+                                            "{name}: Expected endif after else for {var}"
+                                        )))
+                                    }
+                                },
+                                Some("endif") => vec![],
+                                _ => {
+                                    return Err(template_error(format!(
+                                        "{name}: No endif for {var}"
+                                    )))
+                                }
+                            };
+                            nodes.push(Node::If {
+                                name: var,
+                                negate,
+                                then,
+                                otherwise,
+                            });
+                        }
+                        _ => return Err(template_error(format!("{name}: Unknown tag: {tag}"))),
+                    }
+                }
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    /// The values to render with: `args` checked and converted, and
+    /// defaults for the rest
+    fn values(
+        &self,
+        args: &HashMap<String, String>,
+    ) -> Result<HashMap<String, ParamValue>, Box<dyn Error>> {
+        let mut values = HashMap::new();
+        for param in self.params.iter() {
+            let value = match (args.get(&param.name), param.default.as_ref()) {
+                (Some(arg), _) => ParamValue::parse(param.kind, arg)?,
+                (None, Some(default)) => default.clone(),
+                (None, None) => {
+                    return Err(template_error(format!(
+                        "{}: Missing parameter: {param}",
+                        self.name
+                    )))
+                }
+            };
+            values.insert(param.name.clone(), value);
+        }
+        Ok(values)
+    }
+}
+
+/// Tokens in the body of a template
+#[derive(Debug)]
+enum Token {
+    Text(String),
+    Var(String),
+    Tag(String),
+}
+
+/// A directory of templates
+#[derive(Debug, Clone)]
+pub struct TemplateStore {
+    dir: PathBuf,
+}
+
+impl TemplateStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// The names of the templates in the directory, sorted
+    pub fn list(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut names = vec![];
+        if !self.dir.exists() {
+            return Ok(names);
+        }
+        for entry in fs::read_dir(self.dir.as_path())? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(TEMPLATE_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn load(&self, name: &str) -> Result<Template, Box<dyn Error>> {
+        let path = self.dir.join(name).with_extension(TEMPLATE_EXTENSION);
+        let text = fs::read_to_string(path.as_path())
+            .map_err(|err| template_error(format!("{err}: Cannot read {}", path.display())))?;
+        Template::parse(name, text.as_str())
+    }
+
+    /// Render the template `name`.  `args` are the parameters' values
+    /// as text, converted to the parameters' types.  Arguments the
+    /// template does not declare are an error
+    pub fn render(
+        &self,
+        name: &str,
+        args: &HashMap<String, String>,
+    ) -> Result<String, Box<dyn Error>> {
+        let template = self.load(name)?;
+        if let Some(unknown) = args
+            .keys()
+            .find(|k| !template.params.iter().any(|p| &p.name == *k))
+        {
+            return Err(template_error(format!(
+                "{name}: Unknown parameter: {unknown}"
+            )));
+        }
+        self.render_template(&template, args, 0)
+    }
+
+    fn render_template(
+        &self,
+        template: &Template,
+        args: &HashMap<String, String>,
+        depth: usize,
+    ) -> Result<String, Box<dyn Error>> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(template_error(format!(
+                "{}: Includes nested too deeply",
+                template.name
+            )));
+        }
+        let values = template.values(args)?;
+        let mut result = String::new();
+        self.render_nodes(template, &template.body, &values, args, depth, &mut result)?;
+        Ok(result)
+    }
+
+    fn render_nodes(
+        &self,
+        template: &Template,
+        nodes: &[Node],
+        values: &HashMap<String, ParamValue>,
+        args: &HashMap<String, String>,
+        depth: usize,
+        result: &mut String,
+    ) -> Result<(), Box<dyn Error>> {
+        let value = |var: &str| {
+            values.get(var).ok_or_else(|| {
+                template_error(format!("{}: Undeclared parameter: {var}", template.name))
+            })
+        };
+        // `{{name}}` in the arguments of tags
+        let expand = |arg: &str| -> Result<String, Box<dyn Error>> {
+            let mut expanded = String::new();
+            for token in Template::tokenise(template.name.as_str(), arg)? {
+                match token {
+                    Token::Text(text) => expanded.push_str(text.as_str()),
+                    Token::Var(var) => expanded.push_str(value(var.as_str())?.to_string().as_str()),
+                    Token::Tag(tag) => {
+                        return Err(template_error(format!(
+                            "{}: Tag in tag: {tag}",
+                            template.name
+                        )))
+                    }
+                }
+            }
+            Ok(expanded)
+        };
+        for node in nodes.iter() {
+            match node {
+                Node::Text(text) => result.push_str(text.as_str()),
+                Node::Var(var) => result.push_str(value(var.as_str())?.to_string().as_str()),
+                Node::Include(name) => {
+                    let included = self.load(expand(name.as_str())?.as_str())?;
+                    // The included template gets the values it declares
+                    let mut included_args: HashMap<String, String> = args
+                        .iter()
+                        .filter(|(k, _)| included.params.iter().any(|p| &p.name == *k))
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect();
+                    for param in included.params.iter() {
+                        if let (false, Some(v)) = (
+                            included_args.contains_key(&param.name),
+                            values.get(&param.name),
+                        ) {
+                            included_args.insert(param.name.clone(), v.to_string());
+                        }
+                    }
+                    result.push_str(
+                        self.render_template(&included, &included_args, depth + 1)?
+                            .as_str(),
+                    );
+                }
+                Node::File(path) => {
+                    let path = self.dir.join(expand(path.as_str())?);
+                    let contents = fs::read_to_string(path.as_path()).map_err(|err| {
+                        template_error(format!("{err}: Cannot read {}", path.display()))
+                    })?;
+                    result.push_str(contents.as_str());
+                }
+                Node::If {
+                    name,
+                    negate,
+                    then,
+                    otherwise,
+                } => {
+                    let branch = if value(name.as_str())?.is_true() != *negate {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    self.render_nodes(template, branch, values, args, depth, result)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split "key=value key2="a value"" into a map.  Values with spaces
+/// are quoted
+pub fn parse_args(s: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut args = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(template_error(format!("Expected key=value: {s}")));
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            let mut closed = false;
+            for c in chars.by_ref() {
+                if c == '"' {
+                    closed = true;
+                    break;
+                }
+                value.push(c);
+            }
+            if !closed {
+                return Err(template_error(format!("Unclosed quote: {s}")));
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+        args.insert(key, value);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(files: &[(&str, &str)]) -> (tempfile::TempDir, TemplateStore) {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+        let store = TemplateStore::new(dir.path().to_path_buf());
+        (dir, store)
+    }
+
+    #[test]
+    fn renders_params_conditionals_and_includes() {
+        let (_dir, store) = store(&[
+            (
+                "essay.tpl",
+                "---\ntopic: string\nwords: int = 100\nformal: bool = false\n---\n\
+                 Write {{words}} words about {{topic}}.\
+                 {% if formal %} Formal.{% else %} Light.{% endif %}\
+                 {% if not formal %}{% include sign %}{% endif %}\
+                 {% file {{topic}}.txt %}",
+            ),
+            ("sign", "not a template"),
+            ("sign.tpl", "---\ntopic: string\n---\n Bye {{topic}}."),
+            ("cats.txt", " Notes."),
+        ]);
+        assert_eq!(store.list().unwrap(), vec!["essay", "sign"]);
+        let args = parse_args("topic=cats").unwrap();
+        assert_eq!(
+            store.render("essay", &args).unwrap(),
+            "Write 100 words about cats. Light. Bye cats. Notes."
+        );
+        let args = parse_args("topic=cats words=5 formal=true").unwrap();
+        assert_eq!(
+            store.render("essay", &args).unwrap(),
+            "Write 5 words about cats. Formal. Notes."
+        );
+    }
+
+    #[test]
+    fn reports_bad_arguments_and_templates() {
+        let (_dir, store) = store(&[
+            ("t.tpl", "---\nn: int\n---\n{{n}}"),
+            ("loop.tpl", "{% include loop %}"),
+            ("open.tpl", "{% if x %}"),
+        ]);
+        assert!(store.render("t", &HashMap::new()).is_err());
+        assert!(store.render("t", &parse_args("n=two").unwrap()).is_err());
+        assert!(store.render("t", &parse_args("m=2").unwrap()).is_err());
+        assert!(store.render("loop", &HashMap::new()).is_err());
+        assert!(store.render("open", &HashMap::new()).is_err());
+        assert_eq!(parse_args("a=1 b=\"two words\"").unwrap()["b"], "two words");
+    }
+}
@@ -0,0 +1,87 @@
+//! Fakes for testing code that uses the library.  They are built for
+//! the library's own tests, and for others with the `test-util`
+//! feature
+use crate::transport::ChunkHandler;
+use crate::transport::HttpRequest;
+use crate::transport::HttpResponse;
+use crate::transport::Transport;
+use reqwest::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+/// Records the requests and answers as Ollama would.  Pulls are
+/// reported in chunks that split lines, with the `pull_error` at
+/// the end if there is one
+#[derive(Debug, Default)]
+pub struct FakeOllama {
+    pub requests: Mutex<Vec<HttpRequest>>,
+    pub pull_error: Option<String>,
+
+    /// The last report of a pull has no "\n" after it
+    pub unterminated: bool,
+}
+
+impl Transport for FakeOllama {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        self.requests.lock().unwrap().push(request.clone());
+        let ok = |body: serde_json::Value| HttpResponse {
+            status: StatusCode::OK,
+            headers: HashMap::new(),
+            body: serde_json::to_vec(&body).unwrap(),
+        };
+        let endpoint = request.uri.rsplit('/').next().unwrap_or_default();
+        Ok(match endpoint {
+            "chat" => ok(json!({
+                "model": "llama3",
+                "message": {"role": "assistant", "content": "Hello"},
+                "done": true,
+                "done_reason": "stop",
+                "prompt_eval_count": 10,
+                "eval_count": 5,
+            })),
+            "generate" => ok(json!({
+                "model": "llama3",
+                "response": "Once upon a time",
+                "done": true,
+                "eval_count": 4,
+            })),
+            "tags" => ok(json!({"models": [
+                {"name": "llama3:latest", "modified_at": "2024-05-01T12:00:00Z", "size": 1},
+                {"name": "mistral:7b", "modified_at": "2024-05-02T12:00:00Z", "size": 2},
+            ]})),
+            "pull" => {
+                let mut body = "{\"status\": \"pulling manifest\"}\n\n\
+                                {\"status\": \"downloading\", \"total\": 100, \"completed\": 50}\n"
+                    .to_string();
+                match self.pull_error.as_ref() {
+                    Some(error) => body += json!({ "error": error }).to_string().as_str(),
+                    None => body += "{\"status\": \"success\"}",
+                }
+                if !self.unterminated {
+                    body.push('\n');
+                }
+                if let Some(on_chunk) = on_chunk {
+                    for chunk in body.as_bytes().chunks(7) {
+                        on_chunk(chunk);
+                    }
+                }
+                HttpResponse {
+                    status: StatusCode::OK,
+                    headers: HashMap::new(),
+                    body: body.into_bytes(),
+                }
+            }
+            _ => HttpResponse {
+                status: StatusCode::NOT_FOUND,
+                headers: HashMap::new(),
+                body: br#"{"error": "model 'nothing' not found"}"#.to_vec(),
+            },
+        })
+    }
+}
@@ -0,0 +1,379 @@
+//! All HTTP goes through a `Transport`.  The default, `HttpTransport`,
+//! uses reqwest and is configured by a `TransportConfig`: proxy, extra
+//! root certificates, time outs, and headers sent with every request.
+//!
+//! Tests can supply their own `Transport` that does not touch the
+//! network.
+//!
+//! `HttpTransport` runs requests on a background runtime while the
+//! calling thread waits, so a request with a `CancellationToken` can
+//! be abandoned, closing the connection, when the token is cancelled
+use crate::cancel;
+use crate::cancel::CancellationToken;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::multipart;
+use reqwest::Certificate;
+use reqwest::Client;
+use reqwest::ClientBuilder;
+use reqwest::Proxy;
+use reqwest::RequestBuilder;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// How long to wait for a response if not configured
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1200);
+
+/// How often a waiting request checks its `CancellationToken`
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Delete,
+}
+
+/// A part of a multipart form
+#[derive(Debug, Clone)]
+pub enum FormPart {
+    Text { name: String, value: String },
+    File { name: String, path: PathBuf },
+}
+
+#[derive(Debug, Clone)]
+pub enum Body {
+    Empty,
+    Json(serde_json::Value),
+    Multipart(Vec<FormPart>),
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub uri: String,
+
+    /// (name, value)
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+
+    /// If set, cancelling it abandons the request
+    pub cancel: Option<CancellationToken>,
+}
+
+impl HttpRequest {
+    pub fn new(method: Method, uri: &str, body: Body) -> Self {
+        Self {
+            method,
+            uri: uri.to_string(),
+            headers: vec![],
+            body,
+            cancel: None,
+        }
+    }
+
+    pub fn get(uri: &str) -> Self {
+        Self::new(Method::Get, uri, Body::Empty)
+    }
+
+    pub fn delete(uri: &str) -> Self {
+        Self::new(Method::Delete, uri, Body::Empty)
+    }
+
+    pub fn post_json(uri: &str, data: serde_json::Value) -> Self {
+        Self::new(Method::Post, uri, Body::Json(data))
+    }
+
+    pub fn post_multipart(uri: &str, parts: Vec<FormPart>) -> Self {
+        Self::new(Method::Post, uri, Body::Multipart(parts))
+    }
+
+    /// Add a header
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Abandon the request if `token` is cancelled
+    pub fn cancel_with(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_slice(&self.body)
+    }
+
+    /// The reason for the status, for error messages
+    pub fn reason(&self) -> String {
+        self.status
+            .canonical_reason()
+            .unwrap_or("Unknown Reason")
+            .to_string()
+    }
+}
+
+/// Passed the body of a response as it arrives
+pub type ChunkHandler<'a> = &'a mut dyn FnMut(&[u8]);
+
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Send `request` and wait for the whole response.  If `on_chunk`
+    /// is supplied it is passed the body as it arrives, for streaming
+    /// responses.  A failed status is not an error here
+    fn send(
+        &self,
+        request: &HttpRequest,
+        on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// Send requests through this proxy.  If not set the
+    /// `HTTPS_PROXY` environment variable is used
+    pub proxy: Option<String>,
+
+    /// PEM files with root certificates to trust, as well as the
+    /// system's
+    pub root_certificates: Vec<PathBuf>,
+
+    /// How long to wait for a connection.  `None` waits forever
+    pub connect_timeout: Option<Duration>,
+
+    /// How long to wait for each read of the response.  `None`
+    /// waits forever
+    pub timeout: Option<Duration>,
+
+    /// Sent with every request: (name, value)
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            root_certificates: vec![],
+            connect_timeout: None,
+            timeout: Some(DEFAULT_TIMEOUT),
+            headers: vec![],
+        }
+    }
+}
+
+/// Runs the requests of every `HttpTransport`.  It lasts as long as
+/// the programme, so it is never dropped on an async thread, which
+/// would panic
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("llm-rs-http")
+            .enable_all()
+            .build()
+            .expect("HTTP runtime")
+    })
+}
+
+/// From the task running a request to the thread waiting for it
+enum Event {
+    Head(StatusCode, HashMap<String, String>),
+    Chunk(Vec<u8>),
+    Done,
+    Failed(Box<dyn Error + Send + Sync>),
+}
+
+/// `future`, unless it takes longer than `timeout`
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = reqwest::Result<T>>,
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, future).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(Box::new(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for the response",
+            ))),
+        },
+        None => Ok(future.await?),
+    }
+}
+
+/// The `Transport` used unless another is supplied
+#[derive(Debug)]
+pub struct HttpTransport {
+    client: Client,
+
+    /// How long to wait for each read of a response
+    timeout: Option<Duration>,
+}
+
+impl HttpTransport {
+    pub fn new(config: &TransportConfig) -> Result<Self, Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in config.headers.iter() {
+            headers.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+        }
+        let mut builder = ClientBuilder::new()
+            .pool_idle_timeout(None)
+            .default_headers(headers);
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = config.proxy.as_deref() {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        for path in config.root_certificates.iter() {
+            builder = builder.add_root_certificate(Certificate::from_pem(&fs::read(path)?)?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            timeout: config.timeout,
+        })
+    }
+
+    /// Adapt headers reported from reqwest
+    fn header_map_to_hash_map(header_map: &HeaderMap) -> HashMap<String, String> {
+        header_map
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect()
+    }
+
+    /// Send the request and pass on the response as it arrives
+    async fn fetch(
+        builder: RequestBuilder,
+        timeout: Option<Duration>,
+        sender: &mpsc::Sender<Event>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut response = within(timeout, builder.send()).await?;
+        _ = sender.send(Event::Head(
+            response.status(),
+            Self::header_map_to_hash_map(response.headers()),
+        ));
+        while let Some(chunk) = within(timeout, response.chunk()).await? {
+            if sender.send(Event::Chunk(chunk.to_vec())).is_err() {
+                // Nobody is waiting for it
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(
+        &self,
+        request: &HttpRequest,
+        mut on_chunk: Option<ChunkHandler>,
+    ) -> Result<HttpResponse, Box<dyn Error>> {
+        if request.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return Err(cancel::cancelled());
+        }
+        let mut builder = match request.method {
+            Method::Get => self.client.get(request.uri.as_str()),
+            Method::Post => self.client.post(request.uri.as_str()),
+            Method::Delete => self.client.delete(request.uri.as_str()),
+        };
+        for (name, value) in request.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        builder = match &request.body {
+            Body::Empty => builder,
+            Body::Json(data) => builder
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(data)?),
+            Body::Multipart(parts) => {
+                let mut form = multipart::Form::new();
+                for part in parts.iter() {
+                    form = match part {
+                        FormPart::Text { name, value } => form.text(name.clone(), value.clone()),
+                        FormPart::File { name, path } => {
+                            let mut file = multipart::Part::bytes(fs::read(path)?).mime_str(
+                                mime_guess::from_path(path).first_or_octet_stream().as_ref(),
+                            )?;
+                            if let Some(file_name) = path.file_name() {
+                                file = file.file_name(file_name.to_string_lossy().to_string());
+                            }
+                            form.part(name.clone(), file)
+                        }
+                    };
+                }
+                builder.multipart(form)
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let timeout = self.timeout;
+        let task = runtime().spawn(async move {
+            let event = match Self::fetch(builder, timeout, &sender).await {
+                Ok(()) => Event::Done,
+                Err(err) => Event::Failed(err),
+            };
+            _ = sender.send(event);
+        });
+
+        let mut head: Option<(StatusCode, HashMap<String, String>)> = None;
+        let mut body = vec![];
+        loop {
+            if request.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+                // Dropping the request closes the connection
+                task.abort();
+                return Err(cancel::cancelled());
+            }
+            match receiver.recv_timeout(CANCEL_POLL) {
+                Ok(Event::Head(status, headers)) => head = Some((status, headers)),
+                Ok(Event::Chunk(chunk)) => {
+                    if let Some(on_chunk) = on_chunk.as_mut() {
+                        on_chunk(&chunk);
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                Ok(Event::Done) => break,
+                Ok(Event::Failed(err)) => return Err(err),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        match head {
+            Some((status, headers)) => Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            }),
+            None => Err(Box::new(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "The request stopped without a response",
+            ))),
+        }
+    }
+}
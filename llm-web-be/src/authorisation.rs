@@ -38,6 +38,7 @@ pub async fn users() -> io::Result<Vec<AuthorisationRecord>> {
 /// Returned to caller on successful login
 #[derive(Debug)]
 pub struct LoginResult {
+    #[allow(dead_code)]
     pub rights: UserRights,
     pub uuid: Uuid,
     pub token: String, // Send this back to user.  It must be sent with every request
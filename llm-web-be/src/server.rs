@@ -12,17 +12,37 @@ use hyper::body;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
 use hyper::{Body, Request, Response, StatusCode};
+use llm_rs::anthropic_interface;
+use llm_rs::azure;
+use llm_rs::azure::AzureConfig;
+use llm_rs::cancel::CancellableTransport;
+use llm_rs::cancel::CancellationToken;
+use llm_rs::chatgpt_import;
+use llm_rs::fallback;
 use llm_rs::json::ChatRequestInfo;
-use llm_rs::json::Usage;
+use llm_rs::model_mode::ModelMode;
+use llm_rs::model_registry::ModelRegistry;
+use llm_rs::ollama_interface::OllamaInterface;
+use llm_rs::ollama_interface::DEFAULT_OLLAMA_URL;
 use llm_rs::openai_interface;
+use llm_rs::openai_interface::ApiInterfaceBuilder;
+use llm_rs::provider::Provider;
+use llm_rs::transport::Transport;
 use llm_web_common::communication::ChatPrompt;
 use llm_web_common::communication::ChatResponse;
+use llm_web_common::communication::ImportRequest;
+use llm_web_common::communication::ImportResponse;
+use llm_web_common::communication::ImportedConversation;
 use llm_web_common::communication::InvalidRequest;
 use llm_web_common::communication::LLMMessage;
+use llm_web_common::communication::LLMMessageType;
 use llm_web_common::communication::LoginResponse;
 use llm_web_common::communication::LogoutRequest;
 use llm_web_common::communication::LogoutResponse;
 use llm_web_common::communication::Message;
+use llm_web_common::communication::ModelDescription;
+use llm_web_common::communication::ModelsRequest;
+use llm_web_common::communication::ModelsResponse;
 use llm_web_common::communication::{CommType, LoginRequest};
 use serde_json::json;
 use std::collections::HashMap;
@@ -30,13 +50,19 @@ use std::convert::Infallible;
 use std::error::Error;
 use std::fmt;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::vec::Vec;
 use std::{env, fs, io};
 use uuid::Uuid;
 
+/// Anthropic requires a limit on the length of replies.  The front end
+/// does not set one.  Ollama uses it too
+const REPLY_MAX_TOKENS: u32 = 4096;
+
 fn _error(err: String) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
+    io::Error::other(err)
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +79,29 @@ impl AppBackend {
         Self { sessions, testing }
     }
 
+    /// All requests to the LLMs go through this.  It is made on first
+    /// use, from a blocking thread.  A proxy is set
+    /// in `HTTPS_PROXY`.  `LLM_WEB_CA_CERT` names a PEM file of extra
+    /// root certificates.  A record of each request is appended to
+    /// the file named in `LLM_WEB_TELEMETRY`.  If they cannot be used
+    /// it is an error, and making it is tried again next time
+    fn transport() -> Result<Arc<dyn Transport>, Box<dyn Error>> {
+        static TRANSPORT: OnceLock<Arc<dyn Transport>> = OnceLock::new();
+        if let Some(transport) = TRANSPORT.get() {
+            return Ok(transport.clone());
+        }
+        // Only the transport is built, so there is no key
+        let mut builder = ApiInterfaceBuilder::new(String::new(), 0, 0.0);
+        if let Ok(path) = env::var("LLM_WEB_CA_CERT") {
+            builder = builder.root_certificate(PathBuf::from(path));
+        }
+        if let Ok(path) = env::var("LLM_WEB_TELEMETRY") {
+            builder = builder.telemetry_file(PathBuf::from(path));
+        }
+        let transport = builder.build_transport()?;
+        Ok(TRANSPORT.get_or_init(|| transport).clone())
+    }
+
     /// Main loop
     pub async fn run_server(testing: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         // First parameter is port number (optional, defaults to 1337)
@@ -233,47 +282,86 @@ impl AppBackend {
             }
 
             // Now processing a chat_request for a validated session
-            // Need an API key for OpenAI
-            let api_key = env::var("OPENAI_API_KEY").expect("No API Key found");
-
             // Put the conversation so far in here
             let messages: Vec<LLMMessage> = prompt.messages;
+            let model = prompt.model.clone();
 
-            // The JSON payload
-            let data = json!({
-            "messages": messages,
-            "model": prompt.model.as_str(),
-            "temperature": prompt.temperature,
-                });
+            // The models to try, in order.  The prompt's fallback
+            // models, or the default in `LLM_WEB_FALLBACK`
+            let fallback: Vec<String> = if prompt.fallback.is_empty() {
+                env::var("LLM_WEB_FALLBACK")
+                    .map(|models| {
+                        models
+                            .split(',')
+                            .map(|m| m.trim().to_string())
+                            .filter(|m| !m.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            } else {
+                prompt.fallback.clone()
+            };
+            let models = fallback::chain(model.as_str(), &fallback);
 
-            // Send the request to the LLM
-            let response_result: Result<(HashMap<String, String>, ChatRequestInfo), Message> =
-                tokio::task::spawn_blocking(
-                    move || match openai_interface::ApiInterface::send_chat(api_key.as_str(), &data)
-                    {
-                        Ok(r) => Ok(r),
-                        Err(err) => {
-                            let chat_response = InvalidRequest {
-                                reason: format!("OpenAI Chat Error: {err}"),
-                            };
-                            Err(Message {
-                                comm_type: CommType::InvalidRequest,
-                                object: serde_json::to_string(&chat_response).unwrap(),
-                            })
-                        }
-                    },
-                )
-                .await
-                .unwrap();
+            // If the client disconnects hyper drops this future, and
+            // the guard cancels the request so the provider stops
+            // generating a reply nobody will read
+            let cancel = CancellationToken::new();
+            let _cancel_on_drop = cancel.drop_guard();
 
-            let chat_response: (HashMap<String, String>, ChatRequestInfo) = if self.testing {
-                (HashMap::new(), ChatRequestInfo::test_instance())
-            } else {
-                match response_result {
-                    Ok(response) => response,
-                    Err(err) => return err,
+            // Send the request to the LLM.  Get back the model that
+            // answered as well as the answer
+            #[allow(clippy::type_complexity)]
+            let response_result: Result<
+                (String, (HashMap<String, String>, ChatRequestInfo)),
+                Message,
+            > = tokio::task::spawn_blocking(move || {
+                let transport: Arc<dyn Transport> = match Self::transport() {
+                    Ok(transport) => Arc::new(CancellableTransport::new(transport, Some(cancel))),
+                    Err(err) => {
+                        return Err(Message::from(InvalidRequest {
+                            reason: format!("{err}: Cannot send requests"),
+                        }))
+                    }
+                };
+                let send =
+                    |model: &str| Self::send_chat(&transport, model, &messages, prompt.temperature);
+                match fallback::with_fallback(&models, send) {
+                    Ok(r) => Ok(r),
+                    Err(err) => {
+                        let chat_response = InvalidRequest {
+                            reason: format!(
+                                "{} Chat Error: {err}",
+                                Provider::from_model(model.as_str())
+                            ),
+                        };
+                        Err(Message {
+                            comm_type: CommType::InvalidRequest,
+                            object: serde_json::to_string(&chat_response).unwrap(),
+                        })
+                    }
                 }
-            };
+            })
+            .await
+            .unwrap_or_else(|err| {
+                Err(Message::from(InvalidRequest {
+                    reason: format!("{err}: Chat failed"),
+                }))
+            });
+
+            let (model, chat_response): (String, (HashMap<String, String>, ChatRequestInfo)) =
+                if self.testing {
+                    let chat_request_info = ChatRequestInfo::test_instance();
+                    (
+                        chat_request_info.model.clone(),
+                        (HashMap::new(), chat_request_info),
+                    )
+                } else {
+                    match response_result {
+                        Ok(response) => response,
+                        Err(err) => return err,
+                    }
+                };
 
             let mut result = "".to_string();
             result = format!("{result}Headers\n");
@@ -281,9 +369,10 @@ impl AppBackend {
                 result = format!("{result}{k} => {v}\n");
             }
 
-            let cost = Self::cost(chat_response.1.usage, chat_response.1.model.as_str());
+            // Charge for the model that answered, which may be a
+            // fallback model
+            let cost = openai_interface::ApiInterface::cost(chat_response.1.usage, model.as_str());
 
-            let model = chat_response.1.model.clone();
             let response = chat_response.1.choices[0].message.content.clone();
             let credit: f64;
             let uuid: Uuid;
@@ -338,6 +427,155 @@ impl AppBackend {
         }
     }
 
+    /// The models that can be used for chat.  The built in
+    /// capabilities, and those in the file named in `LLM_WEB_MODELS`,
+    /// merged with what the configured providers offer.  Blocks on
+    /// the network
+    fn chat_models() -> Result<Vec<ModelDescription>, Box<dyn Error>> {
+        let mut registry = ModelRegistry::builtin();
+        if let Ok(path) = env::var("LLM_WEB_MODELS") {
+            if let Err(err) = registry.load_file(Path::new(path.as_str())) {
+                eprintln!("{err}: Cannot load model capabilities");
+            }
+        }
+        let transport = Self::transport()?;
+        match env::var("AZURE_OPENAI_CONFIG") {
+            // Only models with deployments
+            Ok(path) => match AzureConfig::from_file(Path::new(path.as_str())) {
+                Ok(config) => {
+                    let listed: Vec<String> = config.deployments.keys().cloned().collect();
+                    registry.merge_listed(Provider::OpenAi, &listed);
+                }
+                Err(err) => {
+                    eprintln!("{err}: Cannot load Azure configuration");
+                    registry.merge_listed(Provider::OpenAi, &[]);
+                }
+            },
+            Err(_) => match env::var("OPENAI_API_KEY") {
+                Ok(api_key) => match openai_interface::ApiInterface::model_ids(
+                    transport.as_ref(),
+                    api_key.as_str(),
+                ) {
+                    Ok(listed) => registry.merge_listed(Provider::OpenAi, &listed),
+                    Err(err) => eprintln!("{err}: Cannot list OpenAI models"),
+                },
+                Err(_) => registry.merge_listed(Provider::OpenAi, &[]),
+            },
+        }
+        if env::var("ANTHROPIC_API_KEY").is_err() {
+            registry.merge_listed(Provider::Anthropic, &[]);
+        }
+        let url = env::var("OLLAMA_HOST").unwrap_or(DEFAULT_OLLAMA_URL.to_string());
+        let local = OllamaInterface::new(url.as_str(), transport)
+            .models()
+            .unwrap_or_default();
+        registry.merge_listed(Provider::Ollama, &local);
+        Ok(Self::model_descriptions(&registry))
+    }
+
+    /// What the front end needs to know about the chat models
+    fn model_descriptions(registry: &ModelRegistry) -> Vec<ModelDescription> {
+        registry
+            .models_for(&ModelMode::Chat)
+            .iter()
+            .map(|m| ModelDescription {
+                id: m.id.clone(),
+                name: m.display_name().to_string(),
+                context_window: m.context_window,
+                pricing_tier: m.pricing_tier.map(|p| p.to_string()),
+            })
+            .collect()
+    }
+
+    /// Tell the front end what models it can offer
+    async fn process_models_request(&self, message: &Message) -> Message {
+        if message.comm_type != CommType::ModelsRequest {
+            return Message::from(InvalidRequest {
+                reason: format!(
+                    "Invalid message type sent to `models`: {}",
+                    message.comm_type
+                ),
+            });
+        }
+        let request: ModelsRequest = match serde_json::from_str(message.object.as_str()) {
+            Ok(request) => request,
+            Err(err) => {
+                return Message::from(InvalidRequest {
+                    reason: format!("{err}: Bad ModelsRequest"),
+                })
+            }
+        };
+        if !self.valid_session(request.token.as_str()) {
+            return Message::from(InvalidRequest {
+                reason: "Invalid session".to_string(),
+            });
+        }
+        let models = if self.testing {
+            // No network
+            Self::model_descriptions(&ModelRegistry::builtin())
+        } else {
+            // An error making the transport, or a panic
+            let models =
+                tokio::task::spawn_blocking(|| Self::chat_models().map_err(|e| e.to_string()))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|models| models);
+            match models {
+                Ok(models) => models,
+                Err(err) => {
+                    return Message::from(InvalidRequest {
+                        reason: format!("{err}: Cannot list models"),
+                    })
+                }
+            }
+        };
+        Message::from(ModelsResponse { models })
+    }
+
+    /// Convert a ChatGPT data export into conversations the front
+    /// end can load
+    async fn process_import_request(&self, message: &Message) -> Message {
+        if message.comm_type != CommType::ImportRequest {
+            return Message::from(InvalidRequest {
+                reason: format!(
+                    "Invalid message type sent to `import`: {}",
+                    message.comm_type
+                ),
+            });
+        }
+        let request: ImportRequest = match serde_json::from_str(message.object.as_str()) {
+            Ok(request) => request,
+            Err(err) => {
+                return Message::from(InvalidRequest {
+                    reason: format!("{err}: Bad ImportRequest"),
+                })
+            }
+        };
+        if !self.valid_session(request.token.as_str()) {
+            return Message::from(InvalidRequest {
+                reason: "Invalid session".to_string(),
+            });
+        }
+        let conversations = match chatgpt_import::parse_export(request.export.as_str()) {
+            Ok(conversations) => conversations,
+            Err(err) => {
+                return Message::from(InvalidRequest {
+                    reason: format!("{err}"),
+                })
+            }
+        };
+        Message::from(ImportResponse {
+            conversations: conversations
+                .iter()
+                .map(|c| ImportedConversation {
+                    title: c.title.clone().unwrap_or_default(),
+                    exchanges: c.exchanges(),
+                })
+                .filter(|c| !c.exchanges.is_empty())
+                .collect(),
+        })
+    }
+
     /// Dispatch the request to subroutines
     async fn process_request(&self, req: Request<Body>) -> Result<Response<Body>, ServerError> {
         let mut response = Response::new(Body::empty());
@@ -373,6 +611,26 @@ impl AppBackend {
 
                 *response.body_mut() = Body::from(s);
             }
+            (_, "/api/models") => {
+                let str = Self::body_to_string(req.into_body()).await.unwrap();
+                let message: Message = match serde_json::from_str(&str) {
+                    Ok(s) => s,
+                    Err(err) => return Err(ServerError::from(err)),
+                };
+                let return_message = self.process_models_request(&message).await;
+                let s = serde_json::to_string(&return_message).unwrap();
+                *response.body_mut() = Body::from(s);
+            }
+            (_, "/api/import") => {
+                let str = Self::body_to_string(req.into_body()).await.unwrap();
+                let message: Message = match serde_json::from_str(&str) {
+                    Ok(s) => s,
+                    Err(err) => return Err(ServerError::from(err)),
+                };
+                let return_message = self.process_import_request(&message).await;
+                let s = serde_json::to_string(&return_message).unwrap();
+                *response.body_mut() = Body::from(s);
+            }
             (_, "/api/logout") => {
                 let str = Self::body_to_string(req.into_body()).await.unwrap();
                 let message: Message = match serde_json::from_str(&str) {
@@ -423,17 +681,85 @@ impl AppBackend {
         Ok(rustls::PrivateKey(keys[0].clone()))
     }
 
-    // Calculate the cost of a OpenAI chat
-    /// Convert the usege into a price.
-    fn cost(usage: Usage, model: &str) -> f64 {
-        // GPT-4is more expensive
-        if model.starts_with("gpt-4") {
-            usage.completion_tokens as f64 / 1000.0 * 12.0
-                + usage.prompt_tokens as f64 / 1000.0 * 0.06
-        } else if model.starts_with("gpt-3") {
-            usage.total_tokens as f64 / 1000.0 * 0.2
-        } else {
-            panic!("{}", model);
+    /// Send `messages` to `model`, through the provider that has it.
+    /// Keys, and where the providers are, come from the environment
+    fn send_chat(
+        transport: &Arc<dyn Transport>,
+        model: &str,
+        messages: &[LLMMessage],
+        temperature: f64,
+    ) -> Result<(HashMap<String, String>, ChatRequestInfo), Box<dyn Error>> {
+        match Provider::from_model(model) {
+            Provider::OpenAi => {
+                // The JSON payload
+                let data = json!({
+                    "messages": messages,
+                    "model": model,
+                    "temperature": temperature,
+                });
+                match env::var("AZURE_OPENAI_CONFIG") {
+                    // OpenAI models are used through Azure
+                    Ok(path) => {
+                        AzureConfig::from_file(Path::new(path.as_str())).and_then(|config| {
+                            azure::send_chat(transport.as_ref(), &config, model, &data)
+                        })
+                    }
+                    Err(_) => {
+                        // Need an API key for OpenAI
+                        let api_key = Self::api_key("OPENAI_API_KEY")?;
+                        openai_interface::ApiInterface::send_chat(
+                            transport.as_ref(),
+                            api_key.as_str(),
+                            &data,
+                        )
+                    }
+                }
+            }
+            Provider::Anthropic => {
+                let api_key = Self::api_key("ANTHROPIC_API_KEY")?;
+                let messages: Vec<llm_rs::json::Message> =
+                    messages.iter().map(Self::llm_message).collect();
+                let data = anthropic_interface::payload(
+                    model,
+                    &messages,
+                    REPLY_MAX_TOKENS,
+                    temperature as f32,
+                    false,
+                );
+                anthropic_interface::send_chat(transport.as_ref(), api_key.as_str(), &data)
+            }
+            Provider::Ollama => {
+                let url = env::var("OLLAMA_HOST").unwrap_or(DEFAULT_OLLAMA_URL.to_string());
+                let messages: Vec<llm_rs::json::Message> =
+                    messages.iter().map(Self::llm_message).collect();
+                OllamaInterface::new(url.as_str(), transport.clone()).chat(
+                    model,
+                    &messages,
+                    temperature as f32,
+                    REPLY_MAX_TOKENS,
+                )
+            }
+        }
+    }
+
+    /// The API key in the environment variable `variable`.  A chat
+    /// that needs a key that is not set is an invalid request, not a
+    /// reason to stop the server
+    fn api_key(variable: &str) -> Result<String, Box<dyn Error>> {
+        env::var(variable)
+            .map_err(|err| -> Box<dyn Error> { format!("{err}: No API key in {variable}").into() })
+    }
+
+    /// Convert a message from the front end into the form `llm-rs` uses
+    fn llm_message(message: &LLMMessage) -> llm_rs::json::Message {
+        let role = match message.role {
+            LLMMessageType::System => "system",
+            LLMMessageType::User => "user",
+            LLMMessageType::Assistant => "assistant",
+        };
+        llm_rs::json::Message {
+            role: role.to_string(),
+            content: message.content.clone(),
         }
     }
 }
@@ -490,6 +816,7 @@ mod tests {
     use crate::data_store::delete_user;
     use crate::data_store::tests::get_unique_user;
     use data_store::add_user;
+    use llm_rs::test_util::FakeOllama;
     use llm_web_common::communication::LoginRequest;
     use llm_web_common::communication::Message;
 
@@ -549,6 +876,113 @@ mod tests {
         assert!(result.comm_type == CommType::InvalidRequest);
     }
 
+    #[test]
+    fn missing_api_key() {
+        let err = AppBackend::api_key("LLM_WEB_NO_SUCH_API_KEY").unwrap_err();
+        assert!(err.to_string().contains("LLM_WEB_NO_SUCH_API_KEY"));
+    }
+
+    #[test]
+    fn chat_with_ollama() {
+        let fake = Arc::new(FakeOllama::default());
+        let transport: Arc<dyn Transport> = fake.clone();
+        let messages = [LLMMessage {
+            role: LLMMessageType::User,
+            content: "Hi".to_string(),
+        }];
+        let (_, reply) =
+            AppBackend::send_chat(&transport, "ollama/llama3", &messages, 0.5).unwrap();
+        assert_eq!(reply.model, "ollama/llama3");
+        assert_eq!(reply.choices[0].message.content, "Hello");
+        assert_eq!(reply.usage.total_tokens, 15);
+
+        // No key is needed
+        let requests = fake.requests.lock().unwrap();
+        assert!(requests[0].uri.ends_with("/api/chat"));
+        assert!(requests[0].headers.is_empty());
+        match &requests[0].body {
+            llm_rs::transport::Body::Json(data) => {
+                assert_eq!(data["model"], "llama3");
+                assert_eq!(data["messages"], json!([{"role": "user", "content": "Hi"}]));
+            }
+            body => panic!("Not JSON: {body:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn models_request() {
+        let server = AppBackend::new(true);
+        let request = Message::from(ModelsRequest {
+            token: "no-such-session".to_string(),
+        });
+        let result = server.process_models_request(&request).await;
+        assert_eq!(result.comm_type, CommType::InvalidRequest);
+
+        let token = "models_request-token".to_string();
+        server.sessions.lock().unwrap().insert(
+            token.clone(),
+            Session::new(
+                Uuid::new_v4(),
+                Utc::now() + chrono::Duration::minutes(5),
+                token.clone(),
+                0.0,
+                UserRights::Chat,
+            ),
+        );
+        let result = server
+            .process_models_request(&Message::from(ModelsRequest { token }))
+            .await;
+        assert_eq!(result.comm_type, CommType::ModelsResponse);
+        let response: ModelsResponse = serde_json::from_str(result.object.as_str()).unwrap();
+        assert!(response.models.iter().any(|m| m.id == "gpt-4"));
+        assert!(!response.models.iter().any(|m| m.id == "whisper-1"));
+    }
+
+    #[tokio::test]
+    async fn import_request() {
+        let server = AppBackend::new(true);
+        let token = "import_request-token".to_string();
+        server.sessions.lock().unwrap().insert(
+            token.clone(),
+            Session::new(
+                Uuid::new_v4(),
+                Utc::now() + chrono::Duration::minutes(5),
+                token.clone(),
+                0.0,
+                UserRights::Chat,
+            ),
+        );
+        let export = r#"[{"title": "Greeting", "current_node": "b", "mapping": {
+            "a": {"message": {"author": {"role": "user"}, "content": {"content_type": "text", "parts": ["Hi"]}},
+                  "parent": null, "children": ["b"]},
+            "b": {"message": {"author": {"role": "assistant"}, "content": {"content_type": "text", "parts": ["Hello"]}},
+                  "parent": "a", "children": []}}},
+            {"title": "Empty", "mapping": {}}]"#;
+        let result = server
+            .process_import_request(&Message::from(ImportRequest {
+                token: token.clone(),
+                export: export.to_string(),
+            }))
+            .await;
+        assert_eq!(result.comm_type, CommType::ImportResponse);
+        let response: ImportResponse = serde_json::from_str(result.object.as_str()).unwrap();
+        assert_eq!(
+            response.conversations,
+            [ImportedConversation {
+                title: "Greeting".to_string(),
+                exchanges: vec![("Hi".to_string(), "Hello".to_string(), "chatgpt".to_string())],
+            }]
+        );
+
+        let result = server
+            .process_import_request(&Message::from(ImportRequest {
+                token,
+                export: "Not JSON".to_string(),
+            }))
+            .await;
+        assert_eq!(result.comm_type, CommType::InvalidRequest);
+    }
+
     #[tokio::test]
     async fn server_test() {
         // Server to test
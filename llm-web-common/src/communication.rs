@@ -16,6 +16,10 @@ pub enum CommType {
     LogoutResponse,
     ChatPrompt,
     ChatResponse,
+    ModelsRequest,
+    ModelsResponse,
+    ImportRequest,
+    ImportResponse,
     InvalidRequest,
 }
 
@@ -93,6 +97,12 @@ pub struct ChatPrompt {
 
     // The user's authenticating data
     pub token: String,
+
+    /// Models to try, in order, if `model` is rate limited,
+    /// overloaded or times out.  If empty the back end's default is
+    /// used
+    #[serde(default)]
+    pub fallback: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -118,6 +128,63 @@ pub struct ChatResponse {
     pub model: String,
 }
 
+/// From llm-web-fe -> llm-web-be.  Ask what models can be used for
+/// chat
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModelsRequest {
+    // The user's authenticating data
+    pub token: String,
+}
+
+/// A model the front end can offer
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ModelDescription {
+    /// Sent in `ChatPrompt.model`
+    pub id: String,
+
+    /// For people
+    pub name: String,
+
+    /// In tokens, if known
+    pub context_window: Option<u32>,
+
+    /// "free", "low", "medium" or "high", if known
+    pub pricing_tier: Option<String>,
+}
+
+/// From llm-web-be -> llm-web-fe.  The models that can be used for
+/// chat
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ModelsResponse {
+    pub models: Vec<ModelDescription>,
+}
+
+/// From llm-web-fe -> llm-web-be.  Convert a ChatGPT data export
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportRequest {
+    // The user's authenticating data
+    pub token: String,
+
+    /// The contents of "conversations.json" from the export
+    pub export: String,
+}
+
+/// A conversation from a ChatGPT export
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ImportedConversation {
+    pub title: String,
+
+    /// (prompt, response, model), in order
+    pub exchanges: Vec<(String, String, String)>,
+}
+
+/// From llm-web-be -> llm-web-fe.  The conversations in the export
+/// that have replies
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportResponse {
+    pub conversations: Vec<ImportedConversation>,
+}
+
 // Display for CommType
 impl fmt::Display for CommType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -128,6 +195,10 @@ impl fmt::Display for CommType {
             CommType::LogoutResponse => write!(f, "Logout Response"),
             CommType::ChatPrompt => write!(f, "Chat Prompt"),
             CommType::ChatResponse => write!(f, "Chat Response"),
+            CommType::ModelsRequest => write!(f, "Models Request"),
+            CommType::ModelsResponse => write!(f, "Models Response"),
+            CommType::ImportRequest => write!(f, "Import Request"),
+            CommType::ImportResponse => write!(f, "Import Response"),
             CommType::InvalidRequest => write!(f, "Invalid Request"),
         }
     }
@@ -220,3 +291,39 @@ impl From<LogoutResponse> for Message {
         }
     }
 }
+
+impl From<ModelsRequest> for Message {
+    fn from(request: ModelsRequest) -> Self {
+        Message {
+            comm_type: CommType::ModelsRequest,
+            object: serde_json::to_string(&request).unwrap(),
+        }
+    }
+}
+
+impl From<ModelsResponse> for Message {
+    fn from(response: ModelsResponse) -> Self {
+        Message {
+            comm_type: CommType::ModelsResponse,
+            object: serde_json::to_string(&response).unwrap(),
+        }
+    }
+}
+
+impl From<ImportRequest> for Message {
+    fn from(request: ImportRequest) -> Self {
+        Message {
+            comm_type: CommType::ImportRequest,
+            object: serde_json::to_string(&request).unwrap(),
+        }
+    }
+}
+
+impl From<ImportResponse> for Message {
+    fn from(response: ImportResponse) -> Self {
+        Message {
+            comm_type: CommType::ImportResponse,
+            object: serde_json::to_string(&response).unwrap(),
+        }
+    }
+}
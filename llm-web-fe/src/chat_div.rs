@@ -20,14 +20,20 @@ use gloo_events::EventListener;
 use llm_web_common::communication::ChatPrompt;
 use llm_web_common::communication::ChatResponse;
 use llm_web_common::communication::CommType;
+use llm_web_common::communication::ImportRequest;
+use llm_web_common::communication::ImportResponse;
 use llm_web_common::communication::InvalidRequest;
 use llm_web_common::communication::LLMMessage;
 use llm_web_common::communication::LLMMessageType;
 use llm_web_common::communication::Message;
+use llm_web_common::communication::ModelDescription;
+use llm_web_common::communication::ModelsRequest;
+use llm_web_common::communication::ModelsResponse;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
+use web_sys::FileReader;
 use web_sys::KeyboardEvent;
 use web_sys::{Event, XmlHttpRequest};
 
@@ -37,6 +43,10 @@ use web_sys::{
     HtmlOptionElement, HtmlSelectElement, HtmlSpanElement, HtmlTextAreaElement,
 };
 
+/// The model offered until the back end says what models there are:
+/// (name, id)
+const DEFAULT_MODEL: (&str, &str) = ("GPT-3.5", "gpt-3.5-turbo");
+
 /// Hold the code for creating and manipulating the chat_div
 #[derive(Debug, Deserialize)]
 pub struct ChatDiv;
@@ -120,6 +130,10 @@ impl LlmWebPage for ChatDiv {
 
         let side_panel_div = make_side_panel(document, chats.clone())?;
 
+        // Fill the model selection when the back end says what
+        // models there are
+        request_models(chats.clone())?;
+
         // Put the page together
         chat_div.append_child(&conversation_div)?;
         chat_div.append_child(&prompt_div)?;
@@ -347,6 +361,10 @@ struct Conversation {
     responses: Vec<(String, ChatResponse)>,
     #[serde(skip_serializing, skip_deserializing)]
     request: Option<XmlHttpRequest>,
+
+    /// Conversations imported from ChatGPT have a title
+    #[serde(default)]
+    title: Option<String>,
 }
 
 impl Conversation {
@@ -359,6 +377,7 @@ impl Conversation {
             prompt: None,
             responses: Vec::new(),
             request: None,
+            title: None,
         }
     }
 
@@ -456,7 +475,10 @@ impl Conversation {
     /// It is hard to know what to do here.  Perhaps a method for the
     /// user to name conversations?
     fn get_label(&self) -> String {
-        if self.responses.is_empty() {
+        if let Some(title) = self.title.as_ref() {
+            let title: String = title.chars().take(17).collect();
+            format!("{}: {title}", self.key)
+        } else if self.responses.is_empty() {
             format!("{}: Empty conversation", self.key)
         } else {
             // Forced unwrap OK because guraded responses.is_empty()
@@ -483,6 +505,10 @@ pub struct Chats {
     // conversation.
     current_conversation: Option<usize>,
     credit: f64,
+
+    /// The models the back end offers for chat
+    #[serde(default)]
+    models: Vec<ModelDescription>,
 }
 
 impl Chats {
@@ -503,6 +529,7 @@ impl Chats {
             conversations: HashMap::new(),
             current_conversation: None,
             credit: 0.0, // TODO:  Fix this!
+            models: vec![],
         })
     }
 
@@ -516,7 +543,7 @@ impl Chats {
 
     /// Triggered by the radio buttons.  Chage the current conversation
     fn set_current_conversation(&mut self, cc: usize) -> Result<(), JsValue> {
-        if self.conversations.get(&cc).is_some() {
+        if self.conversations.contains_key(&cc) {
             self.current_conversation = Some(cc);
             Ok(())
         } else {
@@ -592,7 +619,7 @@ impl Chats {
 
     /// Check that a conversation exists
     fn conversation_exists(&self, key: usize) -> bool {
-        self.conversations.get(&key).is_some()
+        self.conversations.contains_key(&key)
     }
 
     /// Return 1 + max(keys)
@@ -1172,6 +1199,7 @@ fn send_prompt(prompt: String, chats: Rc<RefCell<Chats>>) -> Result<(), JsValue>
         messages,
         temperature: 1.0, // Todo: Get this from user interface
         token,
+        fallback: vec![], // The back end's default
     };
 
     let message: Message = Message::from(chat_prompt);
@@ -1314,6 +1342,174 @@ fn remake_side_panel(chats: Rc<RefCell<Chats>>) -> Result<(), JsValue> {
     Ok(())
 }
 
+/// Ask the back end what models can be used for chat
+fn request_models(chats: Rc<RefCell<Chats>>) -> Result<(), JsValue> {
+    let token = match get_doc()
+        .body()
+        .ok_or("request_models: Cannot get <body>")?
+        .get_attribute("data.token")
+    {
+        Some(token) => token,
+        // Not logged in
+        None => return Ok(()),
+    };
+    let message = Message::from(ModelsRequest { token });
+    make_request(
+        message,
+        move |message: Message| models_response_cb(message, chats.clone()),
+        || (),
+    )?;
+    Ok(())
+}
+
+/// The back end has said what models there are
+fn models_response_cb(message: Message, chats: Rc<RefCell<Chats>>) {
+    match message.comm_type {
+        CommType::ModelsResponse => {
+            let models_response: ModelsResponse =
+                match serde_json::from_str(message.object.as_str()) {
+                    Ok(r) => r,
+                    Err(err) => {
+                        print_to_console(format!("models_response_cb: {err}"));
+                        return;
+                    }
+                };
+            match chats.try_borrow_mut() {
+                Ok(mut chats) => chats.models = models_response.models,
+                Err(err) => {
+                    print_to_console(format!("Failed to borrow chats `models_response_cb`: {err:?}"));
+                    return;
+                }
+            };
+            if let Err(err) = remake_side_panel(chats) {
+                print_to_console(format!("models_response_cb: {err:?}"));
+            }
+        }
+        CommType::InvalidRequest => {
+            let inr: InvalidRequest =
+                serde_json::from_str(message.object.as_str()).expect("Not an InvalidRequest");
+            set_status(&inr.reason);
+        }
+        _ => print_to_console(format!("models_response_cb: Unexpected {}", message.comm_type)),
+    }
+}
+
+/// The user has chosen a ChatGPT export.  Read it and send it to
+/// the back end to convert
+fn import_file_cb(event: &Event, chats: Rc<RefCell<Chats>>) {
+    let file = match event
+        .target()
+        .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+        .and_then(|input| input.files())
+        .and_then(|files| files.get(0))
+    {
+        Some(file) => file,
+        None => return,
+    };
+    let reader = match FileReader::new() {
+        Ok(reader) => reader,
+        Err(err) => {
+            print_to_console(format!("import_file_cb: {err:?}"));
+            return;
+        }
+    };
+    let reader_clone = reader.clone();
+    let onload = Closure::wrap(Box::new(move || {
+        let export = match reader_clone.result().ok().and_then(|r| r.as_string()) {
+            Some(export) => export,
+            None => {
+                set_status("Cannot read the file");
+                return;
+            }
+        };
+        if let Err(err) = send_import(export, chats.clone()) {
+            print_to_console(format!("import_file_cb: {err:?}"));
+        }
+    }) as Box<dyn Fn()>);
+    reader.set_onload(Some(onload.as_ref().unchecked_ref()));
+    onload.forget();
+    match reader.read_as_text(&file) {
+        Ok(()) => set_status(format!("Importing {}", file.name()).as_str()),
+        Err(err) => print_to_console(format!("import_file_cb: {err:?}")),
+    }
+}
+
+/// Send the contents of a ChatGPT "conversations.json" to the back
+/// end
+fn send_import(export: String, chats: Rc<RefCell<Chats>>) -> Result<(), JsValue> {
+    let token = get_doc()
+        .body()
+        .ok_or("send_import: Cannot get <body>")?
+        .get_attribute("data.token")
+        .ok_or("send_import: Cannot get token")?;
+    let message = Message::from(ImportRequest { token, export });
+    make_request(
+        message,
+        move |message: Message| import_response_cb(message, chats.clone()),
+        || (),
+    )?;
+    Ok(())
+}
+
+/// The back end has converted the export.  Each conversation becomes
+/// a new conversation here
+fn import_response_cb(message: Message, chats: Rc<RefCell<Chats>>) {
+    match message.comm_type {
+        CommType::ImportResponse => {
+            let import_response: ImportResponse =
+                match serde_json::from_str(message.object.as_str()) {
+                    Ok(r) => r,
+                    Err(err) => {
+                        print_to_console(format!("import_response_cb: {err}"));
+                        return;
+                    }
+                };
+            match chats.try_borrow_mut() {
+                Ok(mut chats) => {
+                    let credit = chats.credit;
+                    for imported in import_response.conversations.into_iter() {
+                        let key = chats.new_conversation_key();
+                        let mut conversation = Conversation::new(key);
+                        if !imported.title.is_empty() {
+                            conversation.title = Some(imported.title);
+                        }
+                        conversation.responses = imported
+                            .exchanges
+                            .into_iter()
+                            .map(|(prompt, response, model)| {
+                                (
+                                    prompt,
+                                    ChatResponse {
+                                        credit,
+                                        cost: 0.0,
+                                        response,
+                                        model,
+                                    },
+                                )
+                            })
+                            .collect();
+                        chats.conversations.insert(key, conversation);
+                    }
+                }
+                Err(err) => {
+                    print_to_console(format!("Failed to borrow chats `import_response_cb`: {err:?}"));
+                    return;
+                }
+            };
+            set_status("Imported conversations");
+            if let Err(err) = remake_side_panel(chats) {
+                print_to_console(format!("import_response_cb: {err:?}"));
+            }
+        }
+        CommType::InvalidRequest => {
+            let inr: InvalidRequest =
+                serde_json::from_str(message.object.as_str()).expect("Not an InvalidRequest");
+            set_status(&inr.reason);
+        }
+        _ => print_to_console(format!("import_response_cb: Unexpected {}", message.comm_type)),
+    }
+}
+
 /// Create the side panel
 fn make_side_panel(document: &Document, chats: Rc<RefCell<Chats>>) -> Result<Element, JsValue> {
     // The side_panel menu
@@ -1332,14 +1528,28 @@ fn make_side_panel(document: &Document, chats: Rc<RefCell<Chats>>) -> Result<Ele
     select_element.set_id("model_chat");
     let options = select_element.options();
 
-    options.add_with_html_option_element(&HtmlOptionElement::new_with_text_and_value(
-        "Gpt-3",
-        "gpt-3.5-turbo",
-    )?)?;
-
-    options.add_with_html_option_element(&HtmlOptionElement::new_with_text_and_value(
-        "Gpt-4", "gpt-4",
-    )?)?;
+    let models: Vec<ModelDescription> = match chats.try_borrow() {
+        Ok(chats) => chats.models.clone(),
+        Err(_err) => return Err(JsValue::from_str("Cannot borrow chats.  make_side_panel")),
+    };
+    if models.is_empty() {
+        // Still waiting for the back end
+        options.add_with_html_option_element(&HtmlOptionElement::new_with_text_and_value(
+            DEFAULT_MODEL.0,
+            DEFAULT_MODEL.1,
+        )?)?;
+    }
+    for model in models.iter() {
+        let text = match model.pricing_tier.as_deref() {
+            Some(tier) => format!("{} ({tier})", model.name),
+            None => model.name.clone(),
+        };
+        let option = HtmlOptionElement::new_with_text_and_value(text.as_str(), model.id.as_str())?;
+        if let Some(context_window) = model.context_window {
+            option.set_title(format!("{context_window} tokens").as_str());
+        }
+        options.add_with_html_option_element(&option)?;
+    }
     side_panel_div.append_child(&select_element)?;
 
     // New conversation button
@@ -1353,6 +1563,31 @@ fn make_side_panel(document: &Document, chats: Rc<RefCell<Chats>>) -> Result<Ele
     new_conversation_closure.forget();
 
     side_panel_div.append_child(&new_conversation)?;
+
+    // Import conversations from a ChatGPT data export.  The label
+    // is the button, the file input is hidden
+    let import_label = document.create_element("label")?;
+    import_label.set_id("import_label");
+    import_label.set_inner_html("Import ChatGPT");
+    import_label.set_attribute(
+        "title",
+        "Load \"conversations.json\" from a ChatGPT data export",
+    )?;
+    let import_input = document
+        .create_element("input")?
+        .dyn_into::<HtmlInputElement>()?;
+    import_input.set_id("import_input");
+    import_input.set_type("file");
+    import_input.set_accept(".json,application/json");
+    let chats_clone = chats.clone();
+    let on_change = EventListener::new(&import_input, "change", move |event| {
+        import_file_cb(event, chats_clone.clone());
+    });
+    on_change.forget();
+    import_label.append_child(&import_input)?;
+    side_panel_div.append_child(&import_label)?;
+    add_css_rule(document, "#import_input", "display", "none")?;
+    add_css_rule(document, "#import_label", "cursor", "pointer")?;
     // Experimental button
     let clear_style = new_button(document, "clear_style", "Style Experiment")?;
     let resp_closure = Closure::wrap(Box::new(|| {
@@ -24,6 +24,8 @@ pub fn make_request(
     let api = match message.comm_type {
         CommType::LoginRequest => "login",
         CommType::ChatPrompt => "chat",
+        CommType::ModelsRequest => "models",
+        CommType::ImportRequest => "import",
         _ => {
             print_to_console(format!("make_request Unimplemented: {message}"));
             let err = format!(
@@ -67,7 +69,7 @@ pub fn make_request(
 
     // Lastly do the actual network operation
     let message_str = serde_json::to_string(&message).unwrap();
-    xhr.send_with_opt_u8_array(Some(message_str.as_str().as_bytes()))
+    xhr.send_with_opt_u8_array(Some(message_str.as_bytes()))
         .unwrap();
 
     Ok(xhr)